@@ -0,0 +1,266 @@
+//! HTTP endpoints for inspecting and managing the sessions.
+//!
+//! `AdminEndpoint` exposes the administrative operations of `StoreBackend`
+//! (see `store::SessionAdmin`) with the following routes, under the path
+//! prefix configured by `AdminEndpoint::prefix` (`/sessions` by default):
+//!
+//! * `GET <prefix>?cursor=<cursor>&count=<count>` - lists the session ids,
+//!   as `{"session_ids": [..], "next_cursor": ..}`. The `count` defaults to 100
+//!   and is capped at 1000; `count=0` is rejected with `400 Bad Request`.
+//! * `GET <prefix>/count` - counts the active sessions, as `{"count": ..}`.
+//! * `GET <prefix>/<session-id>` - retrieves a session,
+//!   as `{"user_id": .., "value": ..}`.
+//! * `DELETE <prefix>/<session-id>` - removes a session, as `{"deleted": ..}`.
+//!   The listeners registered by `StoreBackend::on_event` are notified of
+//!   the `Destroyed` event.
+//!
+//! The requests whose path does not match any of the above routes are not
+//! handled by this endpoint, so that it can be combined with the others.
+//! Every matched request is checked by the authorization callback before accessing
+//! the store, and is rejected with `403 Forbidden` if the callback returns `false`.
+//!
+//! # Example
+//!
+//! ```
+//! extern crate finchers;
+//! extern crate finchers_session;
+//!
+//! use finchers::input::Input;
+//! use finchers_session::admin::AdminEndpoint;
+//! use finchers_session::in_memory::InMemoryBackend;
+//!
+//! # fn main() {
+//! let backend = InMemoryBackend::default();
+//!
+//! let admin = AdminEndpoint::new(backend.clone(), |input: &Input| {
+//!     // ... verify the credentials of the staff ...
+//!     input
+//!         .request()
+//!         .headers()
+//!         .get("x-admin-token")
+//!         .map_or(false, |token| token == "secret")
+//! }).prefix("/admin/sessions");
+//! # drop(move || finchers::server::start(admin).serve("127.0.0.1:4000"));
+//! # }
+//! ```
+
+use finchers::endpoint::{ApplyContext, ApplyError, ApplyResult, Endpoint};
+use finchers::error::{Error, HttpError};
+use finchers::input::Input;
+use finchers::output::Json;
+
+use std::cmp;
+use std::fmt;
+
+use futures::future;
+use futures::Future;
+use http::{Method, StatusCode};
+use uuid::Uuid;
+
+use store::{SessionAdmin, SessionInfo, SessionPage, StoreBackend};
+
+// The number of session ids in a page if the request does not specify it.
+const DEFAULT_LIST_COUNT: usize = 100;
+
+// The upper bound of the number of session ids in a page.
+const MAX_LIST_COUNT: usize = 1000;
+
+/// The response body returned from `AdminEndpoint`.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum AdminResponse {
+    /// A page of the session ids.
+    Page(SessionPage),
+    /// The content of a session.
+    Session(SessionInfo),
+    /// The number of active sessions.
+    Count {
+        #[allow(missing_docs)]
+        count: usize,
+    },
+    /// The id of the removed session.
+    Deleted {
+        #[allow(missing_docs)]
+        deleted: Uuid,
+    },
+}
+
+#[derive(Debug)]
+struct AdminError {
+    status: StatusCode,
+}
+
+impl fmt::Display for AdminError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.status.canonical_reason().unwrap_or("error"))
+    }
+}
+
+impl HttpError for AdminError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+}
+
+fn admin_error(status: StatusCode) -> Error {
+    AdminError { status }.into()
+}
+
+// The resource identified by the request path.
+#[derive(Debug)]
+enum Target {
+    Sessions,
+    Count,
+    Session(Uuid),
+}
+
+#[derive(Debug)]
+enum Route {
+    List {
+        cursor: Option<String>,
+        count: usize,
+    },
+    Count,
+    Get(Uuid),
+    Delete(Uuid),
+}
+
+/// An endpoint which exposes the administrative operations of `StoreBackend`.
+#[derive(Debug, Clone)]
+pub struct AdminEndpoint<K, F> {
+    backend: StoreBackend<K>,
+    authorize: F,
+    prefix: String,
+}
+
+impl<K, F> AdminEndpoint<K, F>
+where
+    F: Fn(&Input) -> bool,
+{
+    /// Create a new `AdminEndpoint` from the backend and the authorization callback.
+    pub fn new(backend: StoreBackend<K>, authorize: F) -> AdminEndpoint<K, F> {
+        AdminEndpoint {
+            backend,
+            authorize,
+            prefix: "/sessions".into(),
+        }
+    }
+
+    /// Set the path prefix of the routes.
+    ///
+    /// The default value is `/sessions`.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> AdminEndpoint<K, F> {
+        let mut prefix = prefix.into();
+        while prefix.ends_with('/') {
+            prefix.pop();
+        }
+        self.prefix = prefix;
+        self
+    }
+
+    // Returns the resource identified by the request path, or `None` if the
+    // path is not handled by this endpoint.
+    fn target(&self, input: &Input) -> Option<Target> {
+        let mut path = input.request().uri().path();
+        while path.len() > 1 && path.ends_with('/') {
+            path = &path[..path.len() - 1];
+        }
+        if !path.starts_with(&self.prefix[..]) {
+            return None;
+        }
+        match &path[self.prefix.len()..] {
+            "" => Some(Target::Sessions),
+            "/count" => Some(Target::Count),
+            rest if rest.starts_with('/') => rest[1..].parse().ok().map(Target::Session),
+            _ => None,
+        }
+    }
+
+    fn route(&self, input: &Input, target: Target) -> Result<Route, Error> {
+        if !(self.authorize)(input) {
+            return Err(admin_error(StatusCode::FORBIDDEN));
+        }
+
+        let method = input.request().method();
+        match (method, target) {
+            (&Method::GET, Target::Sessions) => {
+                let mut cursor = None;
+                let mut count = DEFAULT_LIST_COUNT;
+                let query = input.request().uri().query().unwrap_or("");
+                for pair in query.split('&') {
+                    let mut parts = pair.splitn(2, '=');
+                    match (parts.next(), parts.next()) {
+                        (Some("cursor"), Some(value)) => cursor = Some(value.to_owned()),
+                        (Some("count"), Some(value)) => {
+                            count = value
+                                .parse()
+                                .map_err(|_| admin_error(StatusCode::BAD_REQUEST))?
+                        }
+                        _ => {}
+                    }
+                }
+                if count == 0 {
+                    return Err(admin_error(StatusCode::BAD_REQUEST));
+                }
+                let count = cmp::min(count, MAX_LIST_COUNT);
+                Ok(Route::List { cursor, count })
+            }
+            (&Method::GET, Target::Count) => Ok(Route::Count),
+            (&Method::GET, Target::Session(session_id)) => Ok(Route::Get(session_id)),
+            (&Method::DELETE, Target::Session(session_id)) => Ok(Route::Delete(session_id)),
+            _ => Err(admin_error(StatusCode::METHOD_NOT_ALLOWED)),
+        }
+    }
+}
+
+impl<'a, K, F> Endpoint<'a> for AdminEndpoint<K, F>
+where
+    K: SessionAdmin + 'a,
+    K::LoadFuture: 'a,
+    K::DeleteFuture: 'a,
+    K::ListFuture: 'a,
+    K::CountFuture: 'a,
+    F: Fn(&Input) -> bool + 'a,
+{
+    type Output = (Json<AdminResponse>,);
+    type Future = Box<dyn Future<Item = Self::Output, Error = Error> + 'a>;
+
+    fn apply(&'a self, cx: &mut ApplyContext<'_>) -> ApplyResult<Self::Future> {
+        let target = match self.target(cx.input()) {
+            Some(target) => target,
+            None => return Err(ApplyError::not_matched()),
+        };
+        // The authorization is checked only for the requests matched to the routes.
+        let route = match self.route(cx.input(), target) {
+            Ok(route) => route,
+            Err(err) => return Ok(Box::new(future::err(err))),
+        };
+
+        let future: Self::Future = match route {
+            Route::List { cursor, count } => Box::new(
+                self.backend
+                    .list_sessions(cursor.as_ref().map(|s| s.as_str()), count)
+                    .map(|page| (Json(AdminResponse::Page(page)),)),
+            ),
+            Route::Count => Box::new(
+                self.backend
+                    .count_sessions()
+                    .map(|count| (Json(AdminResponse::Count { count }),)),
+            ),
+            Route::Get(session_id) => Box::new(
+                self.backend
+                    .get_session(&session_id)
+                    .and_then(|info| match info {
+                        Some(info) => Ok((Json(AdminResponse::Session(info)),)),
+                        None => Err(admin_error(StatusCode::NOT_FOUND)),
+                    }),
+            ),
+            Route::Delete(session_id) => Box::new(
+                self.backend
+                    .destroy_session(session_id, cx.input())
+                    .map(move |()| (Json(AdminResponse::Deleted { deleted: session_id }),)),
+            ),
+        };
+        Ok(future)
+    }
+}
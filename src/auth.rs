@@ -0,0 +1,222 @@
+//! Authentication helpers built on top of `Session`.
+//!
+//! The id of the authenticated user is stored in the session by `Session::login`
+//! and discarded by `Session::logout`. The endpoint `Authenticated` extracts
+//! the current user by using a loader callback, and rejects the request with
+//! `401 Unauthorized` if the session has no authenticated user.
+//!
+//! The user id is stored together with the session value, so it is only as
+//! trustworthy as the backend. Only the server-side backends (`StoreBackend`)
+//! and the client-side ones which sign or encrypt the values (the signed,
+//! private and PASETO Cookie backends, and `JwtBackend`) are safe. With
+//! `cookie::plain()` the client could forge the user, so `Session::login`
+//! panics and no user is authenticated (see `RawSession::is_trusted`).
+//!
+//! # Example
+//!
+//! ```
+//! #[macro_use]
+//! extern crate finchers;
+//! extern crate finchers_session;
+//!
+//! use finchers::prelude::*;
+//! use finchers_session::Session;
+//! use finchers_session::auth::{Authenticated, AuthenticatedUser};
+//! use finchers_session::in_memory::{InMemoryBackend, InMemorySession};
+//!
+//! #[derive(Debug)]
+//! struct User {
+//!     name: String,
+//! }
+//!
+//! # fn main() {
+//! let backend = InMemoryBackend::default();
+//!
+//! let login = path!(@post / "login")
+//!     .and(backend.clone())
+//!     .and_then(|session: Session<InMemorySession>| {
+//!         session.with(|session| {
+//!             // ... verify the credentials ...
+//!             session.login("alice");
+//!             Ok("logged in")
+//!         })
+//!     });
+//!
+//! let logout = path!(@post / "logout")
+//!     .and(backend.clone())
+//!     .and_then(|session: Session<InMemorySession>| {
+//!         session.with(|session| {
+//!             session.logout();
+//!             Ok("logged out")
+//!         })
+//!     });
+//!
+//! let current_user = Authenticated::new(backend, |id: &str| -> Result<_, finchers::error::Error> {
+//!     // ... load the user from the database ...
+//!     Ok(Some(User { name: id.to_owned() }))
+//! });
+//!
+//! let profile = path!(@get / "profile")
+//!     .and(current_user)
+//!     .map(|user: AuthenticatedUser<User>| format!("Hello, {}", user.name));
+//!
+//! let endpoint = login.or(logout).or(profile);
+//! # drop(move || finchers::server::start(endpoint).serve("127.0.0.1:4000"));
+//! # }
+//! ```
+
+use finchers::endpoint::{ApplyContext, ApplyResult, Endpoint};
+use finchers::error::{Error, HttpError};
+
+use std::fmt;
+use std::mem;
+use std::ops::Deref;
+
+use futures::{Async, Future, IntoFuture, Poll};
+use http::StatusCode;
+
+use session::{RawSession, Session};
+
+/// The error type which represents that the request is not authenticated.
+#[derive(Debug)]
+pub struct Unauthorized {
+    _priv: (),
+}
+
+impl fmt::Display for Unauthorized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("authentication required")
+    }
+}
+
+impl HttpError for Unauthorized {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::UNAUTHORIZED
+    }
+}
+
+/// The user authenticated in the current session.
+#[derive(Debug)]
+pub struct AuthenticatedUser<U> {
+    id: String,
+    user: U,
+}
+
+impl<U> AuthenticatedUser<U> {
+    /// Returns the user id stored in the session.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns a reference to the user loaded by the loader callback.
+    pub fn user(&self) -> &U {
+        &self.user
+    }
+
+    /// Consumes itself and returns the user loaded by the loader callback.
+    pub fn into_user(self) -> U {
+        self.user
+    }
+}
+
+impl<U> Deref for AuthenticatedUser<U> {
+    type Target = U;
+
+    fn deref(&self) -> &Self::Target {
+        &self.user
+    }
+}
+
+/// An endpoint which extracts the user authenticated in the current session.
+///
+/// The loader callback receives the user id stored in the session and returns
+/// a future which resolves to the user, or `None` if the user no longer exists.
+#[derive(Debug, Clone)]
+pub struct Authenticated<E, F> {
+    session: E,
+    loader: F,
+}
+
+impl<E, F> Authenticated<E, F> {
+    /// Create a new `Authenticated` from the session endpoint and the loader callback.
+    pub fn new(session: E, loader: F) -> Authenticated<E, F> {
+        Authenticated { session, loader }
+    }
+}
+
+impl<'a, E, S, F, R, U> Endpoint<'a> for Authenticated<E, F>
+where
+    E: Endpoint<'a, Output = (Session<S>,)>,
+    S: RawSession + 'a,
+    F: Fn(&str) -> R + 'a,
+    R: IntoFuture<Item = Option<U>, Error = Error> + 'a,
+    U: 'a,
+{
+    type Output = (AuthenticatedUser<U>,);
+    type Future = AuthenticatedFuture<'a, E::Future, F, R>;
+
+    fn apply(&'a self, cx: &mut ApplyContext<'_>) -> ApplyResult<Self::Future> {
+        let future = self.session.apply(cx)?;
+        Ok(AuthenticatedFuture {
+            state: AuthenticatedFutureState::Reading(future),
+            loader: &self.loader,
+        })
+    }
+}
+
+#[doc(hidden)]
+#[allow(missing_debug_implementations)]
+pub struct AuthenticatedFuture<'a, T, F: 'a, R: IntoFuture> {
+    state: AuthenticatedFutureState<T, R::Future>,
+    loader: &'a F,
+}
+
+#[allow(missing_debug_implementations)]
+enum AuthenticatedFutureState<T, L> {
+    Reading(T),
+    Loading { future: L, user_id: String },
+}
+
+impl<'a, T, F, R, S, U> Future for AuthenticatedFuture<'a, T, F, R>
+where
+    T: Future<Item = (Session<S>,), Error = Error>,
+    S: RawSession,
+    F: Fn(&str) -> R + 'a,
+    R: IntoFuture<Item = Option<U>, Error = Error>,
+{
+    type Item = (AuthenticatedUser<U>,);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        use self::AuthenticatedFutureState::*;
+        loop {
+            let user_id = match self.state {
+                Reading(ref mut future) => {
+                    let (session,) = try_ready!(future.poll());
+                    // The session is not written since it is not modified.
+                    match session.user_id() {
+                        Some(user_id) => user_id,
+                        None => return Err(Unauthorized { _priv: () }.into()),
+                    }
+                }
+                Loading {
+                    ref mut future,
+                    ref mut user_id,
+                } => {
+                    return match try_ready!(future.poll()) {
+                        Some(user) => Ok(Async::Ready((AuthenticatedUser {
+                            id: mem::replace(user_id, String::new()),
+                            user,
+                        },))),
+                        None => Err(Unauthorized { _priv: () }.into()),
+                    }
+                }
+            };
+
+            self.state = Loading {
+                future: (self.loader)(&user_id).into_future(),
+                user_id,
+            };
+        }
+    }
+}
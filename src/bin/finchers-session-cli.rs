@@ -0,0 +1,296 @@
+//! A command line tool for inspecting the sessions managed by `finchers-session`
+//! (requires the feature flag `feature = "cli"`).
+//!
+//! ```text
+//! finchers-session-cli cookie decode [OPTIONS] <COOKIE-VALUE>
+//! finchers-session-cli cookie mint [OPTIONS] <SESSION-VALUE>
+//!     --security <plain|signed|private|paseto>  (default: signed)
+//!     --key <MASTER-KEY>                 (or the environment variable FINCHERS_SESSION_KEY)
+//!     --key-id <KEY-ID>                  (only for paseto)
+//!     --name <COOKIE-NAME>               (default: finchers-session)
+//!
+//! finchers-session-cli redis list [OPTIONS] [--cursor <CURSOR>] [--count <COUNT>]
+//! finchers-session-cli redis get [OPTIONS] <SESSION-ID>
+//! finchers-session-cli redis delete [OPTIONS] <SESSION-ID>
+//!     --url <REDIS-URL>                  (default: redis://127.0.0.1/)
+//!     --key-prefix <PREFIX>              (default: the one of RedisBackend)
+//! ```
+//!
+//! The options are interpreted by the same builders as the application
+//! (`CookieBackend` and `RedisBackend`), so the values are decoded and stored
+//! exactly as the application does.
+//!
+//! The master key of `signed` and `private` is a string of at least 32 bytes,
+//! and the key of `paseto` is 32 bytes encoded in 64 hexadecimal digits.
+
+#![warn(rust_2018_idioms, unused)]
+
+#[macro_use]
+extern crate failure;
+extern crate finchers_session;
+extern crate futures;
+extern crate tokio;
+extern crate uuid;
+
+use std::collections::HashMap;
+use std::env;
+use std::process;
+
+use failure::Error;
+use futures::Future;
+use tokio::runtime::current_thread::Runtime;
+use uuid::Uuid;
+
+use finchers_session::cookie::CookieBackend;
+use finchers_session::paseto::PasetoKey;
+use finchers_session::redis::{Client, RedisBackend};
+
+const USAGE: &str = "\
+usage:
+    finchers-session-cli cookie decode [--security <plain|signed|private|paseto>] [--key <KEY>] [--key-id <KEY-ID>] [--name <NAME>] <COOKIE-VALUE>
+    finchers-session-cli cookie mint [--security <plain|signed|private|paseto>] [--key <KEY>] [--key-id <KEY-ID>] [--name <NAME>] <SESSION-VALUE>
+    finchers-session-cli redis list [--url <URL>] [--key-prefix <PREFIX>] [--cursor <CURSOR>] [--count <COUNT>]
+    finchers-session-cli redis get [--url <URL>] [--key-prefix <PREFIX>] <SESSION-ID>
+    finchers-session-cli redis delete [--url <URL>] [--key-prefix <PREFIX>] <SESSION-ID>";
+
+/// The parsed command line arguments.
+#[derive(Debug)]
+struct Args {
+    command: Vec<String>,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Args, Error> {
+        let mut command = vec![];
+        let mut options = HashMap::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg.starts_with("--") {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format_err!("missing the value of {}", arg))?;
+                options.insert(arg[2..].to_owned(), value);
+            } else {
+                command.push(arg);
+            }
+        }
+        Ok(Args { command, options })
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(|s| s.as_str())
+    }
+
+    fn argument(&self, pos: usize, name: &str) -> Result<&str, Error> {
+        self.command
+            .get(pos)
+            .map(|s| s.as_str())
+            .ok_or_else(|| format_err!("missing the argument <{}>", name))
+    }
+}
+
+fn cookie_backend(args: &Args) -> Result<CookieBackend, Error> {
+    let key = match args.option("key") {
+        Some(key) => Some(key.to_owned()),
+        None => env::var("FINCHERS_SESSION_KEY").ok(),
+    };
+    let backend = match args.option("security").unwrap_or("signed") {
+        "plain" => CookieBackend::plain(),
+        "paseto" => {
+            let key = key.ok_or_else(|| format_err!("the key is required"))?;
+            let key = PasetoKey::new(parse_hex_key(&key)?);
+            CookieBackend::paseto(match args.option("key-id") {
+                Some(key_id) => key.with_id(key_id),
+                None => key,
+            })
+        }
+        security => {
+            let key = key.ok_or_else(|| format_err!("the master key is required"))?;
+            // `Key::from_master` panics if the key is too short.
+            if key.len() < 32 {
+                bail!("the master key must be at least 32 bytes");
+            }
+            match security {
+                "signed" => finchers_session::cookie::signed(key),
+                "private" => finchers_session::cookie::private(key),
+                security => bail!("unknown security: {}", security),
+            }
+        }
+    };
+    Ok(match args.option("name") {
+        Some(name) => backend.name(name),
+        None => backend,
+    })
+}
+
+fn parse_hex_key(key: &str) -> Result<[u8; 32], Error> {
+    if key.len() != 64 || !key.is_ascii() {
+        bail!("the key must be 64 hexadecimal digits");
+    }
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&key[2 * i..2 * i + 2], 16)
+            .map_err(|_| format_err!("the key must be 64 hexadecimal digits"))?;
+    }
+    Ok(bytes)
+}
+
+fn redis_backend(args: &Args) -> Result<RedisBackend, Error> {
+    let client = Client::open(args.option("url").unwrap_or("redis://127.0.0.1/"))?;
+    let backend = RedisBackend::new(client);
+    Ok(match args.option("key-prefix") {
+        Some(prefix) => backend.key_prefix(prefix),
+        None => backend,
+    })
+}
+
+fn run(args: &Args) -> Result<(), Error> {
+    let command: Vec<&str> = args.command.iter().map(|s| s.as_str()).take(2).collect();
+    match &command[..] {
+        ["cookie", "decode"] => {
+            let backend = cookie_backend(args)?;
+            let value = args.argument(2, "COOKIE-VALUE")?;
+            match backend.decode_value(value) {
+                Some(value) => println!("{}", value),
+                None => bail!("the Cookie value is invalid or has been tampered"),
+            }
+        }
+        ["cookie", "mint"] => {
+            let backend = cookie_backend(args)?;
+            let value = args.argument(2, "SESSION-VALUE")?;
+            println!("{}", backend.encode_value(value));
+        }
+        ["redis", "list"] => {
+            let backend = redis_backend(args)?;
+            let count = match args.option("count") {
+                Some(count) => count.parse()?,
+                None => 100,
+            };
+            let mut runtime = Runtime::new()?;
+            let page = runtime.block_on(
+                backend
+                    .list_sessions(args.option("cursor"), count)
+                    .map_err(|err| format_err!("{}", err)),
+            )?;
+            for session_id in page.session_ids {
+                println!("{}", session_id);
+            }
+            if let Some(cursor) = page.next_cursor {
+                eprintln!("next cursor: {}", cursor);
+            }
+        }
+        ["redis", "get"] => {
+            let backend = redis_backend(args)?;
+            let session_id: Uuid = args.argument(2, "SESSION-ID")?.parse()?;
+            let mut runtime = Runtime::new()?;
+            let info = runtime
+                .block_on(
+                    backend
+                        .get_session(&session_id)
+                        .map_err(|err| format_err!("{}", err)),
+                )?.ok_or_else(|| format_err!("the session is not found"))?;
+            if let Some(user_id) = info.user_id {
+                eprintln!("user: {}", user_id);
+            }
+            println!("{}", info.value.unwrap_or_default());
+        }
+        ["redis", "delete"] => {
+            let backend = redis_backend(args)?;
+            let session_id: Uuid = args.argument(2, "SESSION-ID")?.parse()?;
+            let mut runtime = Runtime::new()?;
+            runtime.block_on(
+                backend
+                    .delete_session(&session_id)
+                    .map_err(|err| format_err!("{}", err)),
+            )?;
+        }
+        _ => bail!("{}", USAGE),
+    }
+    Ok(())
+}
+
+fn main() {
+    let result = Args::parse(env::args().skip(1)).and_then(|args| run(&args));
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Args {
+        Args::parse(args.iter().map(|&arg| arg.to_owned())).unwrap()
+    }
+
+    const KEY: &str = "0123456789abcdef0123456789abcdef";
+    const HEX_KEY: &str = "707172737475767778797a7b7c7d7e7f808182838485868788898a8b8c8d8e8f";
+
+    #[test]
+    fn test_parse_args() {
+        let parsed = args(&["cookie", "--name", "sid", "decode", "value", "--key", KEY]);
+        assert_eq!(parsed.command, vec!["cookie", "decode", "value"]);
+        assert_eq!(parsed.option("name"), Some("sid"));
+        assert_eq!(parsed.option("key"), Some(KEY));
+        assert_eq!(parsed.option("security"), None);
+        assert_eq!(parsed.argument(2, "COOKIE-VALUE").unwrap(), "value");
+        assert!(parsed.argument(3, "EXTRA").is_err());
+
+        assert!(Args::parse(vec!["cookie".to_owned(), "--key".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_parse_hex_key() {
+        let key = parse_hex_key(HEX_KEY).unwrap();
+        assert_eq!(key[0], 0x70);
+        assert_eq!(key[31], 0x8f);
+
+        assert!(parse_hex_key(&HEX_KEY[2..]).is_err());
+        assert!(parse_hex_key(&HEX_KEY.replace("7", "g")).is_err());
+    }
+
+    #[test]
+    fn test_cookie_backend_options() {
+        assert!(cookie_backend(&args(&["--security", "signed"])).is_err());
+        assert!(cookie_backend(&args(&["--security", "signed", "--key", "short"])).is_err());
+        assert!(cookie_backend(&args(&["--security", "unknown", "--key", KEY])).is_err());
+        assert!(cookie_backend(&args(&["--security", "paseto", "--key", KEY])).is_err());
+    }
+
+    #[test]
+    fn test_cookie_mint_and_decode() {
+        let backends = vec![
+            (
+                args(&["--security", "plain"]),
+                finchers_session::cookie::plain(),
+            ),
+            (args(&["--key", KEY]), finchers_session::cookie::signed(KEY)),
+            (
+                args(&["--security", "private", "--key", KEY, "--name", "sid"]),
+                finchers_session::cookie::private(KEY).name("sid"),
+            ),
+            (
+                args(&["--security", "paseto", "--key", HEX_KEY, "--key-id", "k1"]),
+                CookieBackend::paseto(
+                    PasetoKey::new(parse_hex_key(HEX_KEY).unwrap()).with_id("k1"),
+                ),
+            ),
+        ];
+        for (args, app) in backends {
+            let cli = cookie_backend(&args).unwrap();
+
+            // The values minted by the CLI are accepted by the application, and vice versa.
+            let minted = cli.encode_value("foo");
+            assert_eq!(app.decode_value(minted.value()), Some("foo".into()));
+            let sent = app.encode_value("bar");
+            assert_eq!(cli.decode_value(sent.value()), Some("bar".into()));
+        }
+
+        let cli = cookie_backend(&args(&["--key", KEY])).unwrap();
+        let other = finchers_session::cookie::signed(HEX_KEY);
+        assert_eq!(cli.decode_value(other.encode_value("foo").value()), None);
+    }
+}
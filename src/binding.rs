@@ -0,0 +1,320 @@
+//! Binding sessions to the attributes of the client.
+//!
+//! When a `SessionBinding` is set to `StoreBackend`, the selected attributes
+//! of the request (the hash of User-Agent, the prefix of client IP address,
+//! or the hash of custom headers) are recorded together with the session value
+//! when the session is created. The subsequent requests whose attributes do not
+//! match the recorded ones are handled as if the session does not exist,
+//! so that a stolen session id cannot be used from another client easily.
+//! This behavior can be customized by `SessionBinding::on_mismatch`.
+//!
+//! Note that changing the set of attributes invalidates the existing bindings.
+//!
+//! # Client IP address
+//!
+//! By default, the client IP address is the peer address recorded in the request
+//! extensions (as `std::net::SocketAddr`), and the forwarding headers are ignored
+//! since the client can send arbitrary values in them. Note that the server of
+//! Finchers 0.13 does not record the peer address, so the application has to insert
+//! it into the extensions by itself (e.g. in a custom service wrapping the endpoint).
+//! If the address is absent, the binding to the IP address has no effect and
+//! a warning is logged once.
+//!
+//! If the application is behind reverse proxies, `SessionBinding::trusted_proxies`
+//! sets the number of proxies, and the address appended by the outermost trusted
+//! proxy is taken from `X-Forwarded-For` (counted from the right-most entry).
+//!
+//! # Example
+//!
+//! ```
+//! #[macro_use]
+//! extern crate finchers;
+//! extern crate finchers_session;
+//!
+//! use finchers::prelude::*;
+//! use finchers_session::Session;
+//! use finchers_session::binding::SessionBinding;
+//! use finchers_session::in_memory::{InMemoryBackend, InMemorySession};
+//!
+//! # fn main() {
+//! let backend = InMemoryBackend::default().binding(
+//!     SessionBinding::new()
+//!         .user_agent()
+//!         .ip_prefix(24, 64)
+//!         .trusted_proxies(1),
+//! );
+//!
+//! let endpoint = path!(@get /)
+//!     .and(backend)
+//!     .and_then(|session: Session<InMemorySession>| {
+//!         session.with(|_session| {
+//!             // ...
+//! #           Ok("done")
+//!         })
+//!     });
+//! # drop(move || finchers::server::start(endpoint).serve("127.0.0.1:4000"));
+//! # }
+//! ```
+
+use finchers::input::Input;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use http::header::{self, HeaderName};
+use uuid::Uuid;
+
+/// An attribute of the request which the session is bound to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindingAttribute {
+    /// The value of `User-Agent` header.
+    UserAgent,
+    /// The network prefix of client IP address.
+    IpPrefix,
+    /// The value of a custom request header.
+    Header(HeaderName),
+}
+
+impl BindingAttribute {
+    fn tag(&self) -> String {
+        match *self {
+            BindingAttribute::UserAgent => "ua".to_owned(),
+            BindingAttribute::IpPrefix => "ip".to_owned(),
+            BindingAttribute::Header(ref name) => format!("h:{}", name.as_str()),
+        }
+    }
+}
+
+/// The action taken when the attributes of the request do not match
+/// the ones recorded in the session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MismatchAction {
+    /// Handles the request as if the session does not exist.
+    Invalidate,
+    /// Uses the session as usual.
+    Accept,
+}
+
+type MismatchPolicy = dyn Fn(&Uuid, &[BindingAttribute]) -> MismatchAction + Send + Sync;
+
+/// The configuration of the attributes which the sessions are bound to.
+#[derive(Clone)]
+pub struct SessionBinding {
+    attributes: Vec<BindingAttribute>,
+    ip_header: HeaderName,
+    trusted_proxies: usize,
+    ipv4_prefix_len: u8,
+    ipv6_prefix_len: u8,
+    on_mismatch: Option<Arc<MismatchPolicy>>,
+}
+
+impl fmt::Debug for SessionBinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionBinding")
+            .field("attributes", &self.attributes)
+            .field("ip_header", &self.ip_header)
+            .field("trusted_proxies", &self.trusted_proxies)
+            .field("ipv4_prefix_len", &self.ipv4_prefix_len)
+            .field("ipv6_prefix_len", &self.ipv6_prefix_len)
+            .finish()
+    }
+}
+
+impl Default for SessionBinding {
+    fn default() -> SessionBinding {
+        SessionBinding::new()
+    }
+}
+
+impl SessionBinding {
+    /// Create a new `SessionBinding` without any attributes.
+    pub fn new() -> SessionBinding {
+        SessionBinding {
+            attributes: vec![],
+            ip_header: HeaderName::from_static("x-forwarded-for"),
+            trusted_proxies: 0,
+            ipv4_prefix_len: 32,
+            ipv6_prefix_len: 128,
+            on_mismatch: None,
+        }
+    }
+
+    fn attribute(mut self, attribute: BindingAttribute) -> SessionBinding {
+        if !self.attributes.contains(&attribute) {
+            self.attributes.push(attribute);
+        }
+        self
+    }
+
+    /// Binds the sessions to the hash of `User-Agent` header.
+    pub fn user_agent(self) -> SessionBinding {
+        self.attribute(BindingAttribute::UserAgent)
+    }
+
+    /// Binds the sessions to the network prefix of client IP address,
+    /// with the specified prefix lengths for IPv4 and IPv6.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the prefix lengths exceed 32 or 128, respectively.
+    pub fn ip_prefix(mut self, ipv4_prefix_len: u8, ipv6_prefix_len: u8) -> SessionBinding {
+        assert!(ipv4_prefix_len <= 32, "invalid IPv4 prefix length");
+        assert!(ipv6_prefix_len <= 128, "invalid IPv6 prefix length");
+        self.ipv4_prefix_len = ipv4_prefix_len;
+        self.ipv6_prefix_len = ipv6_prefix_len;
+        self.attribute(BindingAttribute::IpPrefix)
+    }
+
+    /// Sets the name of request header which carries the client IP address.
+    ///
+    /// The default value is `X-Forwarded-For`. The header is used only if
+    /// `trusted_proxies` is set.
+    pub fn ip_header(mut self, name: HeaderName) -> SessionBinding {
+        self.ip_header = name;
+        self
+    }
+
+    /// Sets the number of trusted reverse proxies in front of the application.
+    ///
+    /// The client IP address is the `n`-th entry from the right of the header
+    /// set by `ip_header`, where `n` is the number of trusted proxies. The
+    /// entries on the left of it are ignored, since they can be forged by the client.
+    ///
+    /// The default value is `0`, that is, the header is ignored and the peer
+    /// address is used.
+    pub fn trusted_proxies(mut self, n: usize) -> SessionBinding {
+        self.trusted_proxies = n;
+        self
+    }
+
+    /// Binds the sessions to the hash of the specified request header.
+    pub fn header(self, name: HeaderName) -> SessionBinding {
+        self.attribute(BindingAttribute::Header(name))
+    }
+
+    /// Sets the callback which decides the action when the attributes
+    /// of the request do not match.
+    ///
+    /// The callback receives the session id and the mismatched attributes.
+    /// By default, the session is invalidated.
+    pub fn on_mismatch<F>(mut self, f: F) -> SessionBinding
+    where
+        F: Fn(&Uuid, &[BindingAttribute]) -> MismatchAction + Send + Sync + 'static,
+    {
+        self.on_mismatch = Some(Arc::new(f));
+        self
+    }
+
+    /// Computes the fingerprint of the request, which is stored together
+    /// with the session value.
+    pub(crate) fn fingerprint(&self, input: &Input) -> String {
+        let headers = input.request().headers();
+        let header_hash = |name: &HeaderName| match headers.get(name) {
+            Some(value) => format!("{:016x}", fnv1a(value.as_bytes())),
+            None => "-".to_owned(),
+        };
+
+        let entries: Vec<String> = self
+            .attributes
+            .iter()
+            .map(|attribute| {
+                let value = match *attribute {
+                    BindingAttribute::UserAgent => header_hash(&header::USER_AGENT),
+                    BindingAttribute::Header(ref name) => header_hash(name),
+                    BindingAttribute::IpPrefix => self
+                        .client_addr(input)
+                        .map(|addr| self.ip_prefix_of(addr))
+                        .unwrap_or_else(|| "-".to_owned()),
+                };
+                format!("{}={}", attribute.tag(), value)
+            }).collect();
+        entries.join(";")
+    }
+
+    fn client_addr(&self, input: &Input) -> Option<IpAddr> {
+        let request = input.request();
+        if self.trusted_proxies == 0 {
+            let addr = request.extensions().get::<SocketAddr>().map(|addr| addr.ip());
+            if addr.is_none() && !MISSING_PEER_ADDR_WARNED.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "The peer address is not found in the request extensions, so the sessions \
+                     are not bound to the client IP address. Insert `SocketAddr` into the \
+                     extensions, or set `SessionBinding::trusted_proxies`."
+                );
+            }
+            return addr;
+        }
+        // Each proxy appends the address of its peer, so that the entries
+        // appended by the trusted proxies are the right-most ones.
+        let entries: Vec<&str> = request
+            .headers()
+            .get_all(&self.ip_header)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        let index = entries.len().checked_sub(self.trusted_proxies)?;
+        entries[index].trim().parse().ok()
+    }
+
+    fn ip_prefix_of(&self, addr: IpAddr) -> String {
+        match addr {
+            IpAddr::V4(addr) => {
+                let len = self.ipv4_prefix_len;
+                let mask = if len == 0 { 0 } else { !0u32 << (32 - len) };
+                format!("{}/{}", Ipv4Addr::from(u32::from(addr) & mask), len)
+            }
+            IpAddr::V6(addr) => {
+                let len = self.ipv6_prefix_len;
+                let mask = if len == 0 { 0 } else { !0u128 << (128 - len) };
+                format!("{}/{}", Ipv6Addr::from(u128::from(addr) & mask), len)
+            }
+        }
+    }
+
+    /// Returns `true` if the session with the stored fingerprint can be used
+    /// by the request with the current fingerprint.
+    pub(crate) fn accepts(&self, session_id: &Uuid, stored: &str, current: &str) -> bool {
+        let parse = |fingerprint: &str| -> HashMap<String, String> {
+            fingerprint
+                .split(';')
+                .filter_map(|entry| {
+                    let mut parts = entry.splitn(2, '=');
+                    Some((parts.next()?.to_owned(), parts.next()?.to_owned()))
+                }).collect()
+        };
+        let stored = parse(stored);
+        let current = parse(current);
+
+        let mismatched: Vec<BindingAttribute> = self
+            .attributes
+            .iter()
+            .filter(|attribute| {
+                let tag = attribute.tag();
+                stored.get(&tag) != current.get(&tag)
+            }).cloned()
+            .collect();
+        if mismatched.is_empty() {
+            return true;
+        }
+
+        let action = match self.on_mismatch {
+            Some(ref on_mismatch) => on_mismatch(session_id, &mismatched),
+            None => MismatchAction::Invalidate,
+        };
+        action == MismatchAction::Accept
+    }
+}
+
+// Set after the missing peer address is reported, in order to log it only once.
+static MISSING_PEER_ADDR_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// The 64-bit FNV-1a hash, which is stable across the versions of Rust.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
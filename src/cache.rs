@@ -0,0 +1,297 @@
+//! The two-tier session store which caches the session values in memory.
+//!
+//! `CachedStore` keeps a short-lived copy of the session values in the process
+//! in front of an arbitrary `SessionStore` (typically a remote one such as Redis),
+//! so that the subsequent requests with the same session can skip the round trip
+//! to the remote store. The writes are always forwarded to the remote store.
+//! The local copy is evicted before the remote write starts, and is updated only
+//! after the remote write has succeeded, so a failed write never leaves a stale
+//! value in the cache.
+//!
+//! When multiple instances of the application share a remote store, the local
+//! copies have to be invalidated when another instance modifies the session value.
+//! The handle for this purpose can be obtained by `CachedStore::invalidator`.
+//! For Redis, see also `redis::listen_invalidation`.
+//!
+//! # Example
+//!
+//! ```
+//! #[macro_use]
+//! extern crate finchers;
+//! extern crate finchers_session;
+//!
+//! use finchers::prelude::*;
+//! use finchers_session::Session;
+//! use finchers_session::cache::CachedStore;
+//! use finchers_session::in_memory::InMemoryStore;
+//! use finchers_session::store::{StoreBackend, StoreSession};
+//! use std::time::Duration;
+//!
+//! # fn main() {
+//! let store = CachedStore::new(InMemoryStore::default())
+//!     .local_ttl(Duration::from_secs(10));
+//! let backend = StoreBackend::from_store(store);
+//!
+//! let endpoint = path!(@get /)
+//!     .and(backend)
+//!     .and_then(|session: Session<StoreSession<CachedStore<InMemoryStore>>>| {
+//!         session.with(|_session| {
+//!             // ...
+//! #           Ok("done")
+//!         })
+//!     });
+//! # drop(move || finchers::server::start(endpoint).serve("127.0.0.1:4000"));
+//! # }
+//! ```
+
+use finchers::error::Error;
+
+use std::cmp;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Async, Future, Poll};
+use uuid::Uuid;
+
+use in_memory::Storage;
+use store::{SessionStore, UserSessionIndex};
+
+/// The implementor of `SessionStore` which caches the values of another store
+/// in memory.
+#[derive(Debug)]
+pub struct CachedStore<S> {
+    remote: S,
+    local: Arc<Storage>,
+    local_ttl: Duration,
+}
+
+impl<S> CachedStore<S>
+where
+    S: SessionStore,
+{
+    /// Create a new `CachedStore` in front of the specified store.
+    pub fn new(remote: S) -> CachedStore<S> {
+        CachedStore {
+            remote,
+            local: Arc::new(Storage::default()),
+            local_ttl: Duration::from_secs(5),
+        }
+    }
+
+    /// Set the duration while the cached values are kept in memory.
+    ///
+    /// The default value is 5 seconds.
+    pub fn local_ttl(mut self, ttl: Duration) -> CachedStore<S> {
+        self.local_ttl = ttl;
+        self
+    }
+
+    /// Returns a reference to the underlying remote store.
+    pub fn remote(&self) -> &S {
+        &self.remote
+    }
+
+    /// Returns a handle to invalidate the cached values.
+    pub fn invalidator(&self) -> CacheInvalidator {
+        CacheInvalidator {
+            local: self.local.clone(),
+        }
+    }
+}
+
+impl<S> SessionStore for CachedStore<S>
+where
+    S: SessionStore,
+{
+    type LoadFuture = LoadFuture<S::LoadFuture>;
+    type SaveFuture = WriteThroughFuture<S::SaveFuture>;
+    type DeleteFuture = WriteThroughFuture<S::DeleteFuture>;
+
+    fn load(&self, session_id: &Uuid) -> Self::LoadFuture {
+        let state = match self.local.get(session_id) {
+            Ok(Some(value)) => LoadFutureState::Cached(Some(value)),
+            Ok(None) => LoadFutureState::Remote {
+                future: self.remote.load(session_id),
+                local: self.local.clone(),
+                local_ttl: self.local_ttl,
+                session_id: *session_id,
+            },
+            Err(err) => LoadFutureState::Failed(Some(err)),
+        };
+        LoadFuture { state }
+    }
+
+    fn save(&self, session_id: &Uuid, value: String, ttl: Option<Duration>) -> Self::SaveFuture {
+        let local_ttl = match ttl {
+            Some(ttl) => cmp::min(ttl, self.local_ttl),
+            None => self.local_ttl,
+        };
+        WriteThroughFuture {
+            future: self.remote.save(session_id, value.clone(), ttl),
+            local: self.local.clone(),
+            session_id: *session_id,
+            value: Some((value, local_ttl)),
+            evicted: false,
+        }
+    }
+
+    fn delete(&self, session_id: &Uuid) -> Self::DeleteFuture {
+        WriteThroughFuture {
+            future: self.remote.delete(session_id),
+            local: self.local.clone(),
+            session_id: *session_id,
+            value: None,
+            evicted: false,
+        }
+    }
+}
+
+impl<S> UserSessionIndex for CachedStore<S>
+where
+    S: UserSessionIndex,
+{
+    type ListFuture = S::ListFuture;
+    type RevokeFuture = RevokeFuture<S::RevokeFuture>;
+
+    fn list_user_sessions(&self, user_id: &str) -> Self::ListFuture {
+        self.remote.list_user_sessions(user_id)
+    }
+
+    fn revoke_user_sessions(&self, user_id: &str) -> Self::RevokeFuture {
+        RevokeFuture {
+            future: self.remote.revoke_user_sessions(user_id),
+            local: self.local.clone(),
+        }
+    }
+}
+
+/// A handle to invalidate the values cached by `CachedStore`.
+#[derive(Debug, Clone)]
+pub struct CacheInvalidator {
+    local: Arc<Storage>,
+}
+
+impl CacheInvalidator {
+    /// Removes the cached value associated with the specified session id.
+    ///
+    /// The next access to the session will fetch the value from the remote store.
+    pub fn invalidate(&self, session_id: &Uuid) -> Result<(), Error> {
+        self.local.remove(session_id)
+    }
+
+    /// Removes all cached values.
+    pub fn invalidate_all(&self) -> Result<(), Error> {
+        self.local.clear()
+    }
+}
+
+#[doc(hidden)]
+#[allow(missing_debug_implementations)]
+pub struct LoadFuture<F> {
+    state: LoadFutureState<F>,
+}
+
+#[allow(missing_debug_implementations)]
+enum LoadFutureState<F> {
+    Failed(Option<Error>),
+    Cached(Option<String>),
+    Remote {
+        future: F,
+        local: Arc<Storage>,
+        local_ttl: Duration,
+        session_id: Uuid,
+    },
+}
+
+impl<F> Future for LoadFuture<F>
+where
+    F: Future<Item = Option<String>, Error = Error>,
+{
+    type Item = Option<String>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        use self::LoadFutureState::*;
+        match self.state {
+            Failed(ref mut err) => Err(err.take().expect("The future has already polled.")),
+            Cached(ref mut value) => Ok(Async::Ready(value.take())),
+            Remote {
+                ref mut future,
+                ref local,
+                local_ttl,
+                session_id,
+            } => {
+                let value = try_ready!(future.poll());
+                if let Some(ref value) = value {
+                    local.set(session_id, value.clone(), Some(local_ttl))?;
+                }
+                Ok(Async::Ready(value))
+            }
+        }
+    }
+}
+
+#[doc(hidden)]
+#[allow(missing_debug_implementations)]
+pub struct WriteThroughFuture<F> {
+    future: F,
+    local: Arc<Storage>,
+    session_id: Uuid,
+    value: Option<(String, Duration)>,
+    evicted: bool,
+}
+
+impl<F> Future for WriteThroughFuture<F>
+where
+    F: Future<Item = (), Error = Error>,
+{
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // The cached value is evicted before the remote write starts, and
+        // again if the write fails, since the remote value is unknown then.
+        if !self.evicted {
+            self.local.remove(&self.session_id)?;
+            self.evicted = true;
+        }
+        match self.future.poll() {
+            Ok(Async::Ready(())) => {}
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(err) => {
+                self.local.remove(&self.session_id)?;
+                return Err(err);
+            }
+        }
+        match self.value.take() {
+            Some((value, local_ttl)) => {
+                self.local.set(self.session_id, value, Some(local_ttl))?;
+            }
+            None => self.local.remove(&self.session_id)?,
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+#[doc(hidden)]
+#[allow(missing_debug_implementations)]
+pub struct RevokeFuture<F> {
+    future: F,
+    local: Arc<Storage>,
+}
+
+impl<F> Future for RevokeFuture<F>
+where
+    F: Future<Item = Vec<Uuid>, Error = Error>,
+{
+    type Item = Vec<Uuid>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let session_ids = try_ready!(self.future.poll());
+        for session_id in &session_ids {
+            self.local.remove(session_id)?;
+        }
+        Ok(Async::Ready(session_ids))
+    }
+}
@@ -1,5 +1,20 @@
 //! The session backend using Cookie as a session storage.
 //!
+//! By default, the signed (or encrypted) Cookie value which cannot be verified
+//! is discarded and the request is handled with an empty session, as if no
+//! Cookie entry is sent. The entry is removed from the client when the session
+//! is written. Use `CookieBackend::invalid_value_policy` to reject such requests
+//! with `SessionError::Tampered` instead (the entry is removed from the client
+//! immediately), and `CookieBackend::on_invalid_value` to observe them.
+//!
+//! The session value whose encoded
+//! Cookie entry exceeds 4096 bytes is rejected with `SessionError::TooLarge`,
+//! since it would be discarded by the browsers.
+//!
+//! The values stored by `plain()` can be read and modified by the client.
+//! Such sessions must not be used for authentication: `Session::login` panics
+//! and `Session::user_id` always returns `None` with them.
+//!
 //! # Example
 //!
 //! ```
@@ -34,19 +49,26 @@ use finchers::input::Input;
 
 #[cfg(feature = "secure")]
 use self::cookie::Key;
-use self::cookie::{Cookie, SameSite};
+use self::cookie::{Cookie, CookieJar, SameSite};
 use futures::future;
 use std::borrow::Cow;
 use std::fmt;
 use std::sync::Arc;
 use time::Duration;
 
+#[cfg(feature = "paseto")]
+use paseto::{PasetoKey, PasetoKeys};
+use error::{InvalidValuePolicy, SessionError};
 use session::{RawSession, Session};
 use util::BuilderExt;
+use wrap::Wrap;
 
 // TODOs:
 // * add support for setting whether to compress data
 
+// The maximum size of the name and value of Cookie entry accepted by the browsers.
+const MAX_COOKIE_SIZE: usize = 4096;
+
 /// Create a `CookieSessionBackend` without signing and encryption.
 ///
 /// This function is equivalent to `CookieSessionBackend::plain()`.
@@ -72,12 +94,23 @@ pub fn private(master: impl AsRef<[u8]>) -> CookieBackend {
     CookieBackend::private(Key::from_master(master.as_ref()))
 }
 
+/// Create a `CookieSessionBackend` with PASETO `v4.local` tokens
+/// (requires `feature = "paseto"`).
+///
+/// This function is equivalent to `CookieSessionBackend::paseto(PasetoKey::new(key))`.
+#[cfg(feature = "paseto")]
+pub fn paseto(key: [u8; 32]) -> CookieBackend {
+    CookieBackend::paseto(PasetoKey::new(key))
+}
+
 enum Security {
     Plain,
     #[cfg(feature = "secure")]
     Signed(Key),
     #[cfg(feature = "secure")]
     Private(Key),
+    #[cfg(feature = "paseto")]
+    Paseto(PasetoKeys),
 }
 
 impl fmt::Debug for Security {
@@ -88,11 +121,14 @@ impl fmt::Debug for Security {
             Security::Signed(..) => f.debug_tuple("Signed").finish(),
             #[cfg(feature = "secure")]
             Security::Private(..) => f.debug_tuple("Private").finish(),
+            #[cfg(feature = "paseto")]
+            Security::Paseto(ref keys) => f.debug_tuple("Paseto").field(keys).finish(),
         }
     }
 }
 
-#[derive(Debug)]
+type InvalidValueHook = dyn Fn(&SessionError) + Send + Sync;
+
 struct CookieConfig {
     security: Security,
     name: String,
@@ -102,26 +138,61 @@ struct CookieConfig {
     domain: Option<Cow<'static, str>>,
     same_site: Option<SameSite>,
     max_age: Option<Duration>,
+    invalid_value_policy: InvalidValuePolicy,
+    on_invalid_value: Option<Arc<InvalidValueHook>>,
+}
+
+impl fmt::Debug for CookieConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CookieConfig")
+            .field("security", &self.security)
+            .field("name", &self.name)
+            .field("path", &self.path)
+            .field("secure", &self.secure)
+            .field("http_only", &self.http_only)
+            .field("domain", &self.domain)
+            .field("same_site", &self.same_site)
+            .field("max_age", &self.max_age)
+            .field("invalid_value_policy", &self.invalid_value_policy)
+            .finish()
+    }
 }
 
 impl CookieConfig {
-    fn read_value(&self, input: &mut Input) -> Result<Option<String>, Error> {
-        let jar = input.cookies()?;
-        let cookie = match self.security {
-            Security::Plain => jar.get(&self.name).cloned(),
+    fn get(&self, jar: &mut CookieJar) -> Result<Option<String>, SessionError> {
+        if jar.get(&self.name).is_none() {
+            return Ok(None);
+        }
+        let value = match self.security {
+            Security::Plain => jar.get(&self.name).map(|cookie| cookie.value().to_string()),
             #[cfg(feature = "secure")]
-            Security::Signed(ref key) => jar.signed(key).get(&self.name),
+            Security::Signed(ref key) => jar
+                .signed(key)
+                .get(&self.name)
+                .map(|cookie| cookie.value().to_string()),
             #[cfg(feature = "secure")]
-            Security::Private(ref key) => jar.private(key).get(&self.name),
+            Security::Private(ref key) => jar
+                .private(key)
+                .get(&self.name)
+                .map(|cookie| cookie.value().to_string()),
+            // The token is bound to the name of Cookie entry.
+            #[cfg(feature = "paseto")]
+            Security::Paseto(ref keys) => jar
+                .get(&self.name)
+                .and_then(|cookie| keys.decrypt(cookie.value(), self.name.as_bytes())),
         };
+        value.map(Some).ok_or(SessionError::Tampered)
+    }
 
-        match cookie {
-            Some(cookie) => Ok(Some(cookie.value().to_string())),
-            None => Ok(None),
-        }
+    fn encode(&self, value: String) -> Cookie<'static> {
+        let mut jar = CookieJar::new();
+        self.add(&mut jar, value);
+        jar.get(&self.name)
+            .cloned()
+            .expect("the Cookie entry should have been added")
     }
 
-    fn write_value(&self, input: &mut Input, value: String) -> Result<(), Error> {
+    fn add(&self, jar: &mut CookieJar, value: String) {
         let cookie = Cookie::build(self.name.clone(), value)
             .path(self.path.clone())
             .secure(self.secure)
@@ -131,15 +202,53 @@ impl CookieConfig {
             .if_some(self.max_age, |cookie, value| cookie.max_age(value))
             .finish();
 
-        let jar = input.cookies()?;
         match self.security {
             Security::Plain => jar.add(cookie),
             #[cfg(feature = "secure")]
             Security::Signed(ref key) => jar.signed(key).add(cookie),
             #[cfg(feature = "secure")]
             Security::Private(ref key) => jar.private(key).add(cookie),
+            #[cfg(feature = "paseto")]
+            Security::Paseto(ref keys) => {
+                let mut cookie = cookie;
+                let token = keys.encrypt(cookie.value(), self.name.as_bytes());
+                cookie.set_value(token);
+                jar.add(cookie)
+            }
+        }
+    }
+
+    fn read_value(&self, input: &mut Input) -> Result<Option<String>, Error> {
+        let jar = input.cookies()?;
+        match self.get(jar) {
+            Ok(value) => Ok(value),
+            Err(err) => {
+                if let Some(ref on_invalid_value) = self.on_invalid_value {
+                    on_invalid_value(&err);
+                }
+                match self.invalid_value_policy {
+                    // The entry is removed when the (empty) session is written.
+                    InvalidValuePolicy::Discard => Ok(None),
+                    InvalidValuePolicy::Reject => {
+                        // The client would send the broken value forever otherwise.
+                        jar.remove(Cookie::named(self.name.clone()));
+                        Err(err.into())
+                    }
+                }
+            }
         }
+    }
 
+    fn write_value(&self, input: &mut Input, value: String) -> Result<(), Error> {
+        let cookie = self.encode(value);
+        let size = cookie.name().len() + cookie.value().len();
+        if size > MAX_COOKIE_SIZE {
+            return Err(SessionError::TooLarge {
+                size,
+                limit: MAX_COOKIE_SIZE,
+            }.into());
+        }
+        input.cookies()?.add(cookie);
         Ok(())
     }
 
@@ -152,6 +261,8 @@ impl CookieConfig {
             Security::Signed(ref key) => jar.signed(key).remove(cookie),
             #[cfg(feature = "secure")]
             Security::Private(ref key) => jar.private(key).remove(cookie),
+            #[cfg(feature = "paseto")]
+            Security::Paseto(..) => jar.remove(cookie),
         }
         Ok(())
     }
@@ -175,6 +286,8 @@ impl CookieBackend {
                 max_age: None,
                 secure: true,
                 http_only: true,
+                invalid_value_policy: InvalidValuePolicy::Discard,
+                on_invalid_value: None,
             }),
         }
     }
@@ -200,10 +313,49 @@ impl CookieBackend {
         CookieBackend::new(Security::Private(key))
     }
 
+    /// Creates a `CookieSessionBackend` which stores the Cookie values as
+    /// PASETO `v4.local` tokens encrypted with the specified key.
+    ///
+    /// The tokens are bound to the name of Cookie entry via the implicit assertion,
+    /// and the key id (if any) is stored in the footer.
+    ///
+    /// This method is only available if the feature flag `paseto` is set.
+    #[cfg(feature = "paseto")]
+    pub fn paseto(key: PasetoKey) -> CookieBackend {
+        CookieBackend::new(Security::Paseto(PasetoKeys::new(key)))
+    }
+
+    /// Adds a previous PASETO key, which is used only for decrypting the tokens.
+    ///
+    /// This method is useful for rotating the keys without invalidating the
+    /// existing sessions.
+    ///
+    /// This method is only available if the feature flag `paseto` is set.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the backend is not created by `CookieBackend::paseto`.
+    #[cfg(feature = "paseto")]
+    pub fn paseto_previous_key(mut self, key: PasetoKey) -> CookieBackend {
+        match self.config_mut().security {
+            Security::Paseto(ref mut keys) => keys.previous.push(key),
+            _ => panic!("The backend does not use PASETO tokens."),
+        }
+        self
+    }
+
     fn config_mut(&mut self) -> &mut CookieConfig {
         Arc::get_mut(&mut self.config).expect("The instance has already shared.")
     }
 
+    /// Sets the name of Cookie entry.
+    ///
+    /// The default value is `"finchers-session"`.
+    pub fn name(mut self, value: impl Into<String>) -> CookieBackend {
+        self.config_mut().name = value.into();
+        self
+    }
+
     /// Sets the path of Cookie entry.
     ///
     /// The default value is `"/"`.
@@ -251,6 +403,54 @@ impl CookieBackend {
         self.config_mut().max_age = Some(value);
         self
     }
+
+    /// Sets the policy applied when the Cookie value sent by the client cannot be verified.
+    ///
+    /// The default value is `InvalidValuePolicy::Discard`.
+    pub fn invalid_value_policy(mut self, policy: InvalidValuePolicy) -> CookieBackend {
+        self.config_mut().invalid_value_policy = policy;
+        self
+    }
+
+    /// Registers a callback which is called with `SessionError::Tampered`
+    /// when the Cookie value sent by the client cannot be verified.
+    pub fn on_invalid_value<F>(mut self, f: F) -> CookieBackend
+    where
+        F: Fn(&SessionError) + Send + Sync + 'static,
+    {
+        self.config_mut().on_invalid_value = Some(Arc::new(f));
+        self
+    }
+
+    /// Decodes the value of Cookie entry sent by the client, and returns the
+    /// session value if it is successfully verified (or decrypted).
+    ///
+    /// The argument is the part after `<name>=` in the `Cookie` header.
+    ///
+    /// This method is intended for `finchers-session-cli`, and is not a part of the public API.
+    #[doc(hidden)]
+    pub fn decode_value(&self, value: &str) -> Option<String> {
+        let mut jar = CookieJar::new();
+        jar.add_original(Cookie::new(self.config.name.clone(), value.to_owned()));
+        self.config.get(&mut jar).ok().and_then(|value| value)
+    }
+
+    /// Encodes the session value into the Cookie entry, in the same way as
+    /// the one sent to the client in `Set-Cookie` header.
+    ///
+    /// This method is intended for `finchers-session-cli`, and is not a part of the public API.
+    #[doc(hidden)]
+    pub fn encode_value(&self, value: impl Into<String>) -> Cookie<'static> {
+        self.config.encode(value.into())
+    }
+
+    /// Wraps the specified endpoint so that the session is committed automatically
+    /// after the endpoint.
+    ///
+    /// See the module `wrap` for details.
+    pub fn wrap<E>(self, endpoint: E) -> Wrap<CookieBackend, E> {
+        Wrap::new(self, endpoint)
+    }
 }
 
 impl<'a> Endpoint<'a> for CookieBackend {
@@ -304,4 +504,14 @@ impl RawSession for CookieSession {
     fn write(self, input: &mut Input) -> Self::WriteFuture {
         future::result(self.write_impl(input))
     }
+
+    fn is_trusted(&self) -> bool {
+        match self.config.security {
+            Security::Plain => false,
+            #[cfg(feature = "secure")]
+            Security::Signed(..) | Security::Private(..) => true,
+            #[cfg(feature = "paseto")]
+            Security::Paseto(..) => true,
+        }
+    }
 }
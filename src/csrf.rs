@@ -0,0 +1,520 @@
+//! CSRF protection tied to the session.
+//!
+//! The CSRF token is generated per session and stored together with the session
+//! value of the application, in the same way as the authenticated user and the
+//! flash messages, so it does not interfere with the session value itself.
+//! The token is discarded by `Session::login` and `Session::logout`, and a new
+//! one is generated when it is requested next time. The token can also be
+//! obtained in the handlers via `Session::csrf_token`.
+//!
+//! The token is only as trustworthy as the session backend which stores it.
+//! Use a server-side backend or a signed, private or PASETO Cookie backend;
+//! with `cookie::plain()` the token is readable and writable by the client
+//! (and by anyone who can set Cookie entries for the domain).
+//!
+//! `Csrf` is an endpoint which exposes the token (e.g. for embedding it into
+//! the forms in templates), and `CsrfGuard` is an endpoint filter which rejects
+//! the requests with unsafe methods which do not carry the valid token.
+//! The token is taken from the request header (`X-CSRF-Token` by default), or
+//! from the field of urlencoded form (`csrf-token` by default) if the header is absent.
+//!
+//! `CsrfGuard` consumes the request body in order to read the form field.
+//! `CsrfFormGuard` (created by `Csrf::form_guard`) validates the token in the
+//! same way, and yields the submitted form fields to the handler.
+//!
+//! # Example
+//!
+//! ```
+//! #[macro_use]
+//! extern crate finchers;
+//! extern crate finchers_session;
+//!
+//! use finchers::prelude::*;
+//! use finchers_session::csrf::{Csrf, CsrfForm, CsrfToken};
+//!
+//! # fn main() {
+//! let backend = finchers_session::cookie::private("this-is-a-very-very-very-long-secret-key");
+//! let csrf = Csrf::new(backend);
+//!
+//! let form = path!(@get /).and(csrf.clone()).map(|token: CsrfToken| {
+//!     format!(r#"<input type="hidden" name="csrf-token" value="{}">"#, token)
+//! });
+//!
+//! let submit = path!(@post /)
+//!     .and(csrf.form_guard())
+//!     .map(|form: CsrfForm| format!("submitted: {:?}", form.get("comment")));
+//!
+//! let endpoint = form.or(submit);
+//! # drop(move || finchers::server::start(endpoint).serve("127.0.0.1:4000"));
+//! # }
+//! ```
+
+use finchers::endpoint::{ApplyContext, ApplyResult, Endpoint};
+use finchers::endpoints::body::{self, ReceiveAll};
+use finchers::error::{Error, HttpError};
+use finchers::input::Input;
+
+use std::fmt;
+use std::mem;
+use std::sync::Arc;
+
+use futures::future;
+use futures::{Async, Future, IntoFuture, Poll};
+use http::header::{self, HeaderName};
+use http::{Method, StatusCode};
+
+use session::{RawSession, Session, WriteSessionFuture};
+use util::{constant_time_eq, parse_urlencoded};
+
+/// The error type which represents that the CSRF token is missing or invalid.
+#[derive(Debug)]
+pub struct CsrfError {
+    _priv: (),
+}
+
+impl fmt::Display for CsrfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("missing or invalid CSRF token")
+    }
+}
+
+impl HttpError for CsrfError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::FORBIDDEN
+    }
+}
+
+/// The CSRF token associated with the current session.
+#[derive(Debug, Clone)]
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    pub(crate) fn new(token: String) -> CsrfToken {
+        CsrfToken(token)
+    }
+
+    /// Returns the string representation of the token.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Validates the token submitted by the client (e.g. via a form field).
+    pub fn verify(&self, submitted: &str) -> Result<(), Error> {
+        if constant_time_eq(self.0.as_bytes(), submitted.as_bytes()) {
+            Ok(())
+        } else {
+            Err(CsrfError { _priv: () }.into())
+        }
+    }
+}
+
+impl fmt::Display for CsrfToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The fields of the urlencoded form submitted with the CSRF token.
+#[derive(Debug, Clone, Default)]
+pub struct CsrfForm {
+    fields: Vec<(String, String)>,
+}
+
+impl CsrfForm {
+    /// Returns the value of the first field with the specified name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|&&(ref field, _)| field == name)
+            .map(|&(_, ref value)| value.as_str())
+    }
+
+    /// Returns all fields in the submitted order.
+    pub fn fields(&self) -> &[(String, String)] {
+        &self.fields
+    }
+}
+
+#[derive(Debug)]
+struct CsrfConfig {
+    header_name: HeaderName,
+    field_name: String,
+}
+
+/// An endpoint which retrieves (or generates) the CSRF token of the current session.
+#[derive(Debug, Clone)]
+pub struct Csrf<E> {
+    session: E,
+    config: Arc<CsrfConfig>,
+}
+
+impl<E> Csrf<E> {
+    /// Create a new `Csrf` from the endpoint which yields the session of the
+    /// application, i.e. the same backend as the one used by the handlers.
+    ///
+    /// The endpoints created from this value read (and write, if a token is
+    /// generated) the session independently of the handlers. If the handler
+    /// also modifies the session, take the token via `Session::csrf_token`
+    /// in the handler instead, so that the session is written only once.
+    pub fn new(session: E) -> Csrf<E> {
+        Csrf {
+            session,
+            config: Arc::new(CsrfConfig {
+                header_name: HeaderName::from_static("x-csrf-token"),
+                field_name: "csrf-token".into(),
+            }),
+        }
+    }
+
+    fn config_mut(&mut self) -> &mut CsrfConfig {
+        Arc::get_mut(&mut self.config).expect("The instance has already shared.")
+    }
+
+    /// Sets the name of request header which carries the CSRF token.
+    ///
+    /// The default value is `X-CSRF-Token`.
+    pub fn header_name(mut self, name: HeaderName) -> Csrf<E> {
+        self.config_mut().header_name = name;
+        self
+    }
+
+    /// Sets the name of form field which carries the CSRF token.
+    ///
+    /// The default value is `csrf-token`.
+    pub fn field_name(mut self, name: impl Into<String>) -> Csrf<E> {
+        self.config_mut().field_name = name.into();
+        self
+    }
+
+    /// Creates an endpoint filter which validates the CSRF token in the request
+    /// header or the form field if the request method is not safe.
+    pub fn guard(&self) -> CsrfGuard<E>
+    where
+        E: Clone,
+    {
+        CsrfGuard {
+            csrf: self.clone(),
+            body: body::receive_all(),
+        }
+    }
+
+    /// Creates an endpoint which validates the CSRF token in the same way as
+    /// `guard`, and yields the fields of the submitted urlencoded form.
+    pub fn form_guard(&self) -> CsrfFormGuard<E>
+    where
+        E: Clone,
+    {
+        CsrfFormGuard {
+            csrf: self.clone(),
+            body: body::receive_all(),
+        }
+    }
+
+    // Returns the token in the request header, or `None` if the request method
+    // is safe and the token does not have to be validated.
+    fn submitted_header(&self, input: &Input) -> Option<Option<String>> {
+        let request = input.request();
+        match *request.method() {
+            Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE => None,
+            _ => Some(
+                request
+                    .headers()
+                    .get(&self.config.header_name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(ToOwned::to_owned),
+            ),
+        }
+    }
+
+    fn form_future<'a, S>(
+        &'a self,
+        cx: &mut ApplyContext<'_>,
+        body: &'a ReceiveAll,
+        always_read_body: bool,
+    ) -> ApplyResult<CsrfFormFuture<<ReceiveAll as Endpoint<'a>>::Future, E::Future, S>>
+    where
+        E: Endpoint<'a, Output = (Session<S>,)>,
+        S: RawSession + 'a,
+    {
+        let submitted = self.submitted_header(cx.input());
+        let is_form = cx
+            .input()
+            .request()
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map_or(false, |value| {
+                value.starts_with("application/x-www-form-urlencoded")
+            });
+        let read_body = is_form && (always_read_body || submitted == Some(None));
+        let body = if read_body {
+            Some(body.apply(cx)?)
+        } else {
+            None
+        };
+        let session = self.session.apply(cx)?;
+        Ok(CsrfFormFuture {
+            state: CsrfFormFutureState::Receiving {
+                body,
+                session: Some(session),
+                submitted,
+                field_name: self.config.field_name.clone(),
+            },
+        })
+    }
+}
+
+impl<'a, E, S> Endpoint<'a> for Csrf<E>
+where
+    E: Endpoint<'a, Output = (Session<S>,)>,
+    S: RawSession + 'a,
+{
+    type Output = (CsrfToken,);
+    type Future = CsrfFuture<E::Future, S>;
+
+    fn apply(&'a self, cx: &mut ApplyContext<'_>) -> ApplyResult<Self::Future> {
+        let future = self.session.apply(cx)?;
+        Ok(CsrfFuture::reading(future, None))
+    }
+}
+
+/// An endpoint filter which rejects the unsafe requests without the valid
+/// CSRF token.
+pub struct CsrfGuard<E> {
+    csrf: Csrf<E>,
+    body: ReceiveAll,
+}
+
+impl<E: fmt::Debug> fmt::Debug for CsrfGuard<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CsrfGuard")
+            .field("csrf", &self.csrf)
+            .finish()
+    }
+}
+
+impl<E: Clone> Clone for CsrfGuard<E> {
+    fn clone(&self) -> Self {
+        self.csrf.guard()
+    }
+}
+
+impl<'a, E, S> Endpoint<'a> for CsrfGuard<E>
+where
+    E: Endpoint<'a, Output = (Session<S>,)>,
+    S: RawSession + 'a,
+{
+    type Output = ();
+    type Future = future::Map<
+        CsrfFormFuture<<ReceiveAll as Endpoint<'a>>::Future, E::Future, S>,
+        fn((CsrfForm,)),
+    >;
+
+    fn apply(&'a self, cx: &mut ApplyContext<'_>) -> ApplyResult<Self::Future> {
+        // The body is consumed only if the token is not sent in the header.
+        let future = self.csrf.form_future(cx, &self.body, false)?;
+        Ok(future.map(discard as fn(_)))
+    }
+}
+
+/// An endpoint which validates the CSRF token like `CsrfGuard`, and yields
+/// the fields of the submitted urlencoded form.
+///
+/// The form is empty if the request body is not urlencoded.
+pub struct CsrfFormGuard<E> {
+    csrf: Csrf<E>,
+    body: ReceiveAll,
+}
+
+impl<E: fmt::Debug> fmt::Debug for CsrfFormGuard<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CsrfFormGuard")
+            .field("csrf", &self.csrf)
+            .finish()
+    }
+}
+
+impl<E: Clone> Clone for CsrfFormGuard<E> {
+    fn clone(&self) -> Self {
+        self.csrf.form_guard()
+    }
+}
+
+impl<'a, E, S> Endpoint<'a> for CsrfFormGuard<E>
+where
+    E: Endpoint<'a, Output = (Session<S>,)>,
+    S: RawSession + 'a,
+{
+    type Output = (CsrfForm,);
+    type Future = CsrfFormFuture<<ReceiveAll as Endpoint<'a>>::Future, E::Future, S>;
+
+    fn apply(&'a self, cx: &mut ApplyContext<'_>) -> ApplyResult<Self::Future> {
+        self.csrf.form_future(cx, &self.body, true)
+    }
+}
+
+fn discard(_: (CsrfForm,)) {}
+
+#[doc(hidden)]
+#[allow(missing_debug_implementations)]
+pub struct CsrfFormFuture<B, F, S: RawSession> {
+    state: CsrfFormFutureState<B, F, S>,
+}
+
+#[allow(missing_debug_implementations)]
+enum CsrfFormFutureState<B, F, S: RawSession> {
+    Receiving {
+        // `None` if the body is not read.
+        body: Option<B>,
+        session: Option<F>,
+        // `Some(..)` if the token should be validated.
+        submitted: Option<Option<String>>,
+        field_name: String,
+    },
+    Verifying {
+        future: CsrfFuture<F, S>,
+        form: Option<CsrfForm>,
+    },
+}
+
+impl<B, T, F, S> Future for CsrfFormFuture<B, F, S>
+where
+    B: Future<Item = (T,), Error = Error>,
+    T: AsRef<[u8]>,
+    F: Future<Item = (Session<S>,), Error = Error>,
+    S: RawSession,
+{
+    type Item = (CsrfForm,);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        use self::CsrfFormFutureState::*;
+        loop {
+            let (session, submitted, form) = match self.state {
+                Receiving {
+                    ref mut body,
+                    ref mut session,
+                    ref mut submitted,
+                    ref field_name,
+                } => {
+                    let fields = match *body {
+                        Some(ref mut body) => {
+                            let (data,) = try_ready!(body.poll());
+                            parse_urlencoded(data.as_ref())
+                        }
+                        None => vec![],
+                    };
+                    let form = CsrfForm { fields };
+                    let submitted = submitted.take().map(|header| {
+                        header.or_else(|| form.get(field_name).map(ToOwned::to_owned))
+                    });
+                    let session = session.take().expect("This future has already polled.");
+                    (session, submitted, form)
+                }
+                Verifying {
+                    ref mut future,
+                    ref mut form,
+                } => {
+                    try_ready!(future.poll());
+                    let form = form.take().expect("This future has already polled.");
+                    return Ok(Async::Ready((form,)));
+                }
+            };
+
+            self.state = Verifying {
+                future: CsrfFuture::reading(session, submitted),
+                form: Some(form),
+            };
+        }
+    }
+}
+
+#[doc(hidden)]
+#[allow(missing_debug_implementations)]
+pub struct CsrfFuture<F, S: RawSession> {
+    state: CsrfFutureState<F, S>,
+}
+
+#[allow(missing_debug_implementations)]
+enum CsrfFutureState<F, S: RawSession> {
+    Reading {
+        future: F,
+        // `Some(..)` if the token should be validated.
+        submitted: Option<Option<String>>,
+    },
+    Writing {
+        future: WriteSessionFuture<S::WriteFuture>,
+        token: String,
+    },
+    Done,
+}
+
+impl<F, S> CsrfFuture<F, S>
+where
+    S: RawSession,
+{
+    fn reading(future: F, submitted: Option<Option<String>>) -> CsrfFuture<F, S> {
+        CsrfFuture {
+            state: CsrfFutureState::Reading { future, submitted },
+        }
+    }
+}
+
+impl<F, S> Future for CsrfFuture<F, S>
+where
+    F: Future<Item = (Session<S>,), Error = Error>,
+    S: RawSession,
+{
+    type Item = (CsrfToken,);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        use self::CsrfFutureState::*;
+        loop {
+            let mut session = match self.state {
+                Reading { ref mut future, .. } => {
+                    let (session,) = try_ready!(future.poll());
+                    session
+                }
+                Writing { ref mut future, .. } => {
+                    try_ready!(future.poll());
+                    match mem::replace(&mut self.state, Done) {
+                        Writing { token, .. } => return Ok(Async::Ready((CsrfToken(token),))),
+                        _ => unreachable!("unexpected condition"),
+                    }
+                }
+                Done => panic!("unexpected state"),
+            };
+
+            let submitted = match mem::replace(&mut self.state, Done) {
+                Reading { submitted, .. } => submitted,
+                _ => unreachable!("unexpected condition"),
+            };
+
+            let token = session.stored_csrf_token();
+            if let Some(submitted) = submitted {
+                let valid = match (&token, &submitted) {
+                    (&Some(ref token), &Some(ref submitted)) => {
+                        constant_time_eq(token.as_bytes(), submitted.as_bytes())
+                    }
+                    _ => false,
+                };
+                if !valid {
+                    return Err(CsrfError { _priv: () }.into());
+                }
+            }
+
+            match token {
+                // The session does not have to be written since it is not modified.
+                Some(token) => return Ok(Async::Ready((CsrfToken(token),))),
+                None => {
+                    let token = session.csrf_token().0;
+                    self.state = Writing {
+                        future: session.into_future(),
+                        token,
+                    };
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! The encoding of the metadata (flash messages, authenticated user, CSRF token)
+//! stored together with the session value.
+//!
+//! The raw session value is the session value itself if there is no metadata.
+//! Otherwise, it has the following form:
+//!
+//! ```text
+//! envelope := MARKER ('u' length ':' user_id)? ('c' length ':' csrf_token)?
+//!             ('b' length ':' fingerprint)? (level length ':' message)*
+//!             SEPARATOR ('+' value | '-')
+//! ```
+//!
+//! The fingerprint is handled only by the backend which binds the session
+//! to the client, and is not visible from `Session`.
+//!
+//! The values written by the earlier versions, which stored only the flash
+//! messages with the marker `"\u{1f}flash\u{1f}"`, are decoded as well.
+
+use flash::{Flash, FlashLevel};
+
+const MARKER: &str = "\u{1f}meta\u{1f}";
+// The grammar of the legacy envelope is a subset of the current one.
+const LEGACY_MARKER: &str = "\u{1f}flash\u{1f}";
+const SEPARATOR: char = '\u{1e}';
+
+/// The metadata stored together with the session value.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Envelope {
+    pub(crate) user_id: Option<String>,
+    pub(crate) csrf_token: Option<String>,
+    pub(crate) fingerprint: Option<String>,
+    pub(crate) flashes: Vec<Flash>,
+}
+
+impl Envelope {
+    fn is_empty(&self) -> bool {
+        self.user_id.is_none()
+            && self.csrf_token.is_none()
+            && self.fingerprint.is_none()
+            && self.flashes.is_empty()
+    }
+}
+
+/// Splits the raw session value into the metadata and the session value.
+pub(crate) fn decode(raw: Option<&str>) -> (Envelope, Option<&str>) {
+    match raw {
+        Some(raw) => decode_envelope(raw).unwrap_or_else(|| (Envelope::default(), Some(raw))),
+        None => (Envelope::default(), None),
+    }
+}
+
+fn strip_marker(raw: &str) -> Option<&str> {
+    if raw.starts_with(MARKER) {
+        Some(&raw[MARKER.len()..])
+    } else if raw.starts_with(LEGACY_MARKER) {
+        Some(&raw[LEGACY_MARKER.len()..])
+    } else {
+        None
+    }
+}
+
+fn decode_envelope(raw: &str) -> Option<(Envelope, Option<&str>)> {
+    let mut rest = strip_marker(raw)?;
+    let mut envelope = Envelope::default();
+    loop {
+        let mut chars = rest.chars();
+        let c = chars.next()?;
+        if c == SEPARATOR {
+            rest = chars.as_str();
+            break;
+        }
+        let s = chars.as_str();
+        let colon = s.find(':')?;
+        let len: usize = s[..colon].parse().ok()?;
+        let end = colon + 1 + len;
+        let content = s.get(colon + 1..end)?;
+        match c {
+            'u' if envelope.user_id.is_none()
+                && envelope.csrf_token.is_none()
+                && envelope.fingerprint.is_none()
+                && envelope.flashes.is_empty() =>
+            {
+                envelope.user_id = Some(content.to_owned());
+            }
+            'c' if envelope.csrf_token.is_none()
+                && envelope.fingerprint.is_none()
+                && envelope.flashes.is_empty() =>
+            {
+                envelope.csrf_token = Some(content.to_owned());
+            }
+            'b' if envelope.fingerprint.is_none() && envelope.flashes.is_empty() => {
+                envelope.fingerprint = Some(content.to_owned());
+            }
+            c => {
+                let level = FlashLevel::from_char(c)?;
+                envelope.flashes.push(Flash::new(level, content));
+            }
+        }
+        rest = &s[end..];
+    }
+
+    if rest.starts_with('+') {
+        Some((envelope, Some(&rest[1..])))
+    } else if rest == "-" {
+        Some((envelope, None))
+    } else {
+        None
+    }
+}
+
+/// Builds the raw session value from the metadata and the session value.
+///
+/// The returned value is `None` if there is nothing to store.
+pub(crate) fn encode(envelope: &Envelope, value: Option<&str>) -> Option<String> {
+    match value {
+        None if envelope.is_empty() => return None,
+        Some(value) if envelope.is_empty() && strip_marker(value).is_none() => {
+            return Some(value.to_owned())
+        }
+        _ => {}
+    }
+
+    fn push_entry(raw: &mut String, c: char, content: &str) {
+        raw.push(c);
+        *raw += &content.len().to_string();
+        raw.push(':');
+        *raw += content;
+    }
+
+    let mut raw = String::from(MARKER);
+    if let Some(ref user_id) = envelope.user_id {
+        push_entry(&mut raw, 'u', user_id);
+    }
+    if let Some(ref csrf_token) = envelope.csrf_token {
+        push_entry(&mut raw, 'c', csrf_token);
+    }
+    if let Some(ref fingerprint) = envelope.fingerprint {
+        push_entry(&mut raw, 'b', fingerprint);
+    }
+    for flash in &envelope.flashes {
+        push_entry(&mut raw, flash.level().to_char(), flash.message());
+    }
+    raw.push(SEPARATOR);
+    match value {
+        Some(value) => {
+            raw.push('+');
+            raw += value;
+        }
+        None => raw.push('-'),
+    }
+    Some(raw)
+}
+
+/// Splits the fingerprint from the raw session value.
+pub(crate) fn split_fingerprint(raw: &str) -> (Option<String>, Option<String>) {
+    let (mut envelope, value) = decode(Some(raw));
+    let fingerprint = envelope.fingerprint.take();
+    (fingerprint, encode(&envelope, value))
+}
+
+/// Attaches the fingerprint to the raw session value.
+pub(crate) fn with_fingerprint(raw: &str, fingerprint: &str) -> String {
+    let (mut envelope, value) = decode(Some(raw));
+    envelope.fingerprint = Some(fingerprint.to_owned());
+    encode(&envelope, value).expect("the envelope should not be empty")
+}
@@ -0,0 +1,137 @@
+//! The error type of session failures.
+//!
+//! The failures reported by the backends in this crate are represented as
+//! `SessionError`, which is converted into `finchers::error::Error` with the
+//! corresponding HTTP status code. The original value can be recovered from
+//! the `Error` by downcasting to `SessionError`, in order to distinguish
+//! (for example) an outage of the storage from a tampered session value.
+
+use failure;
+use finchers::error::{Error, HttpError};
+
+use std::fmt;
+
+use http::StatusCode;
+
+/// The error type which represents a failure in the session handling.
+#[derive(Debug)]
+pub enum SessionError {
+    /// The storage of session values cannot be accessed (e.g. the connection
+    /// to Redis is lost, or the lock of in-memory storage is poisoned).
+    ///
+    /// The status code is `503 Service Unavailable`.
+    StorageUnavailable(failure::Error),
+
+    /// The session value cannot be serialized or deserialized.
+    ///
+    /// The status code is `500 Internal Server Error`.
+    Serialization(failure::Error),
+
+    /// The session id sent by the client is malformed.
+    ///
+    /// The status code is `400 Bad Request`.
+    InvalidId(String),
+
+    /// The cursor used for listing the sessions is malformed.
+    ///
+    /// The status code is `400 Bad Request`.
+    InvalidCursor(String),
+
+    /// The session value sent by the client has been tampered, or cannot be
+    /// verified with the current keys.
+    ///
+    /// The status code is `400 Bad Request`.
+    Tampered,
+
+    /// The token sent by the client has been expired.
+    ///
+    /// The status code is `401 Unauthorized`.
+    Expired,
+
+    /// The encoded session value exceeds the maximum size accepted by the backend.
+    ///
+    /// The status code is `413 Payload Too Large`.
+    TooLarge {
+        /// The size of the encoded session value, in bytes.
+        size: usize,
+        /// The maximum size accepted by the backend, in bytes.
+        limit: usize,
+    },
+
+    /// The sessions have been modified by another request concurrently, and
+    /// the operation has been given up after retrying.
+    ///
+    /// The status code is `409 Conflict`.
+    Conflict,
+
+    /// The operation on the session storage has timed out.
+    ///
+    /// The status code is `504 Gateway Timeout`.
+    Timeout,
+}
+
+impl SessionError {
+    pub(crate) fn storage(cause: impl Into<failure::Error>) -> Error {
+        SessionError::StorageUnavailable(cause.into()).into()
+    }
+
+    pub(crate) fn serialization(cause: impl Into<failure::Error>) -> Error {
+        SessionError::Serialization(cause.into()).into()
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            SessionError::StorageUnavailable(ref cause) => {
+                write!(f, "the session storage is unavailable: {}", cause)
+            }
+            SessionError::Serialization(ref cause) => {
+                write!(f, "failed to serialize the session value: {}", cause)
+            }
+            SessionError::InvalidId(ref id) => write!(f, "invalid session id: {:?}", id),
+            SessionError::InvalidCursor(ref cursor) => write!(f, "invalid cursor: {:?}", cursor),
+            SessionError::Tampered => f.write_str("the session value has been tampered"),
+            SessionError::Expired => f.write_str("the session token has been expired"),
+            SessionError::TooLarge { size, limit } => write!(
+                f,
+                "the session value is too large ({} bytes, the limit is {} bytes)",
+                size, limit
+            ),
+            SessionError::Conflict => {
+                f.write_str("the sessions have been modified concurrently")
+            }
+            SessionError::Timeout => {
+                f.write_str("the operation on the session storage has timed out")
+            }
+        }
+    }
+}
+
+/// The policy applied when the session value sent by the client cannot be
+/// verified, e.g. a tampered Cookie entry or an expired token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidValuePolicy {
+    /// Handles the request as if no session value is sent, and removes the
+    /// value from the client when the session is written.
+    Discard,
+    /// Rejects the request with `SessionError::Tampered` (or `SessionError::Expired`),
+    /// and removes the value from the client.
+    Reject,
+}
+
+impl HttpError for SessionError {
+    fn status_code(&self) -> StatusCode {
+        match *self {
+            SessionError::StorageUnavailable(..) => StatusCode::SERVICE_UNAVAILABLE,
+            SessionError::Serialization(..) => StatusCode::INTERNAL_SERVER_ERROR,
+            SessionError::InvalidId(..)
+            | SessionError::InvalidCursor(..)
+            | SessionError::Tampered => StatusCode::BAD_REQUEST,
+            SessionError::Expired => StatusCode::UNAUTHORIZED,
+            SessionError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            SessionError::Conflict => StatusCode::CONFLICT,
+            SessionError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Lifecycle events of the sessions managed by `StoreBackend`.
+//!
+//! The listeners registered by `StoreBackend::on_event` are called with
+//! the following events:
+//!
+//! * `Created` - a new session value has been stored with a new session id.
+//! * `Loaded` - the session value has been loaded from the store.
+//! * `Updated` - the session value has been stored with the existing session id.
+//! * `Regenerated` - the session value has been moved to a new session id
+//!   (e.g. by `Session::login`).
+//! * `Destroyed` - the session value has been removed from the store.
+//! * `Expired` - the session id sent by the client was not found in the store,
+//!   typically because the session value has been expired.
+//!
+//! The futures returned from the listeners are completed before the session
+//! is passed to the application (for `Loaded` and `Expired`) or before the
+//! response is sent (for the others). The request fails if one of them fails.
+//!
+//! # Example
+//!
+//! ```
+//! #[macro_use]
+//! extern crate finchers;
+//! extern crate finchers_session;
+//!
+//! use finchers::prelude::*;
+//! use finchers_session::Session;
+//! use finchers_session::event::{SessionEvent, SessionEventKind};
+//! use finchers_session::in_memory::{InMemoryBackend, InMemorySession};
+//!
+//! # fn main() {
+//! let backend = InMemoryBackend::default().on_event(|event: &SessionEvent| {
+//!     if event.kind() == SessionEventKind::Created {
+//!         println!("created: {} ({})", event.session_id(), event.request().uri());
+//!     }
+//!     Ok::<(), finchers::error::Error>(())
+//! });
+//!
+//! let endpoint = path!(@get /)
+//!     .and(backend)
+//!     .and_then(|session: Session<InMemorySession>| {
+//!         session.with(|_session| {
+//!             // ...
+//! #           Ok("done")
+//!         })
+//!     });
+//! # drop(move || finchers::server::start(endpoint).serve("127.0.0.1:4000"));
+//! # }
+//! ```
+
+use finchers::error::Error;
+use finchers::input::Input;
+
+use std::fmt;
+use std::sync::Arc;
+
+use futures::future;
+use futures::{Future, IntoFuture};
+use http::{HeaderMap, Method, Uri};
+use uuid::Uuid;
+
+/// The kind of session lifecycle events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SessionEventKind {
+    #[allow(missing_docs)]
+    Created,
+    #[allow(missing_docs)]
+    Loaded,
+    #[allow(missing_docs)]
+    Updated,
+    #[allow(missing_docs)]
+    Regenerated,
+    #[allow(missing_docs)]
+    Destroyed,
+    #[allow(missing_docs)]
+    Expired,
+}
+
+/// The metadata of the request which triggered the event.
+#[derive(Debug, Clone)]
+pub struct RequestMetadata {
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+}
+
+impl RequestMetadata {
+    pub(crate) fn from_input(input: &Input) -> RequestMetadata {
+        let request = input.request();
+        RequestMetadata {
+            method: request.method().clone(),
+            uri: request.uri().clone(),
+            headers: request.headers().clone(),
+        }
+    }
+
+    /// Returns the HTTP method of the request.
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    /// Returns the URI of the request.
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    /// Returns the header map of the request.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+}
+
+/// A lifecycle event of a session.
+#[derive(Debug, Clone)]
+pub struct SessionEvent {
+    kind: SessionEventKind,
+    session_id: Uuid,
+    previous_session_id: Option<Uuid>,
+    request: Arc<RequestMetadata>,
+}
+
+impl SessionEvent {
+    pub(crate) fn new(
+        kind: SessionEventKind,
+        session_id: Uuid,
+        previous_session_id: Option<Uuid>,
+        request: Arc<RequestMetadata>,
+    ) -> SessionEvent {
+        SessionEvent {
+            kind,
+            session_id,
+            previous_session_id,
+            request,
+        }
+    }
+
+    /// Returns the kind of this event.
+    pub fn kind(&self) -> SessionEventKind {
+        self.kind
+    }
+
+    /// Returns the session id associated with this event.
+    pub fn session_id(&self) -> &Uuid {
+        &self.session_id
+    }
+
+    /// Returns the discarded session id if the kind of event is `Regenerated`.
+    pub fn previous_session_id(&self) -> Option<&Uuid> {
+        self.previous_session_id.as_ref()
+    }
+
+    /// Returns the metadata of the request which triggered this event.
+    pub fn request(&self) -> &RequestMetadata {
+        &self.request
+    }
+}
+
+type Listener =
+    dyn Fn(&SessionEvent) -> Box<dyn Future<Item = (), Error = Error> + Send> + Send + Sync;
+
+/// A set of event listeners.
+#[derive(Clone, Default)]
+pub(crate) struct Listeners {
+    listeners: Vec<Arc<Listener>>,
+}
+
+impl fmt::Debug for Listeners {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Listeners")
+            .field("len", &self.listeners.len())
+            .finish()
+    }
+}
+
+impl Listeners {
+    pub(crate) fn push<F, R>(&mut self, f: F)
+    where
+        F: Fn(&SessionEvent) -> R + Send + Sync + 'static,
+        R: IntoFuture<Item = (), Error = Error>,
+        R::Future: Send + 'static,
+    {
+        self.listeners.push(Arc::new(move |event: &SessionEvent| {
+            Box::new(f(event).into_future()) as Box<dyn Future<Item = (), Error = Error> + Send>
+        }));
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.listeners.is_empty()
+    }
+
+    /// Calls the listeners with the events, and returns a future which waits
+    /// for all returned futures.
+    ///
+    /// The returned value is `None` if there is nothing to wait.
+    pub(crate) fn notify(
+        &self,
+        events: &[SessionEvent],
+    ) -> Option<Box<dyn Future<Item = (), Error = Error> + Send>> {
+        if self.listeners.is_empty() || events.is_empty() {
+            return None;
+        }
+        let futures: Vec<_> = events
+            .iter()
+            .flat_map(|event| self.listeners.iter().map(move |listener| listener(event)))
+            .collect();
+        Some(Box::new(future::join_all(futures).map(|_| ())))
+    }
+}
@@ -0,0 +1,166 @@
+//! The behavior of `StoreBackend` when the session store is unavailable.
+//!
+//! By default, a failure of loading the session value fails the whole request.
+//! `FallbackPolicy` changes this behavior so that the request is handled with
+//! an empty, read-only session (`FallbackPolicy::Degrade`), or the loading is
+//! retried with exponential backoff until a deadline (`FallbackPolicy::Retry`).
+//!
+//! In addition, a `CircuitBreaker` stops accessing the store for a while after
+//! consecutive failures, so that a down store is not hammered by every request.
+//! While the circuit is open, the requests are handled as if the loading failed
+//! (without retries), and the writing of session values fails immediately.
+//! The failures of both loading and writing are counted by the circuit breaker.
+//!
+//! The read-only sessions are never written back to the store, that is, the
+//! modifications made by the handler are discarded.
+//!
+//! # Example
+//!
+//! ```
+//! #[macro_use]
+//! extern crate finchers;
+//! extern crate finchers_session;
+//!
+//! use finchers::prelude::*;
+//! use finchers_session::Session;
+//! use finchers_session::fallback::{CircuitBreaker, FallbackPolicy};
+//! use finchers_session::redis::{Client, RedisBackend, RedisSession};
+//! use std::time::Duration;
+//!
+//! # fn main() {
+//! # drop(|| {
+//! let client = Client::open("redis://127.0.0.1/").unwrap();
+//! let backend = RedisBackend::new(client)
+//!     .fallback(FallbackPolicy::Degrade)
+//!     .circuit_breaker(CircuitBreaker::new(5, Duration::from_secs(30)));
+//!
+//! let endpoint = path!(@get /)
+//!     .and(backend)
+//!     .and_then(|session: Session<RedisSession>| {
+//!         session.with(|_session| {
+//!             // ...
+//! #           Ok("done")
+//!         })
+//!     });
+//! # finchers::server::start(endpoint).serve("127.0.0.1:4000")
+//! # });
+//! # }
+//! ```
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The behavior when the session value cannot be loaded from the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FallbackPolicy {
+    /// Fails the request with the error returned from the store.
+    FailFast,
+    /// Handles the request with an empty, read-only session.
+    Degrade,
+    /// Retries the loading with exponential backoff, and fails the request
+    /// if it does not succeed until the deadline.
+    Retry {
+        /// The interval before the first retry, which is doubled at each retry.
+        backoff: Duration,
+        /// The maximum duration from the first attempt.
+        deadline: Duration,
+    },
+}
+
+impl Default for FallbackPolicy {
+    fn default() -> FallbackPolicy {
+        FallbackPolicy::FailFast
+    }
+}
+
+impl FallbackPolicy {
+    /// Returns the time of the next retry, or `None` if the policy does not
+    /// retry any more.
+    pub(crate) fn next_retry(&self, attempt: u32, started: Instant) -> Option<Instant> {
+        match *self {
+            FallbackPolicy::Retry { backoff, deadline } => {
+                let at = Instant::now() + backoff * 2u32.pow(attempt.min(16));
+                if at <= started + deadline {
+                    Some(at)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    opened_at: Option<Instant>,
+    // The time when the trial request has started while the circuit is half-open.
+    trial: Option<Instant>,
+}
+
+/// A circuit breaker which stops accessing the store after consecutive failures.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    reset_timeout: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    /// Create a new `CircuitBreaker` which opens the circuit after
+    /// `failure_threshold` consecutive failures.
+    ///
+    /// After `reset_timeout` has elapsed, a request is allowed to access the
+    /// store as a trial, and the circuit is closed if it succeeds. If the trial
+    /// does not finish within `reset_timeout` (e.g. the request is cancelled),
+    /// another request is allowed as a new trial.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `failure_threshold` is zero.
+    pub fn new(failure_threshold: u32, reset_timeout: Duration) -> CircuitBreaker {
+        assert!(failure_threshold > 0, "the failure threshold must be positive");
+        CircuitBreaker {
+            failure_threshold,
+            reset_timeout,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    /// Returns `true` if the circuit is open, that is, the store is not accessed.
+    pub fn is_open(&self) -> bool {
+        let state = self.state.lock().expect("the mutex has been poisoned");
+        state.opened_at.is_some()
+    }
+
+    /// Returns `true` if the store can be accessed by the current request.
+    pub(crate) fn allow(&self) -> bool {
+        let mut state = self.state.lock().expect("the mutex has been poisoned");
+        let since = match (state.opened_at, state.trial) {
+            (None, _) => return true,
+            (Some(_), Some(trial)) => trial,
+            (Some(opened_at), None) => opened_at,
+        };
+        if since.elapsed() >= self.reset_timeout {
+            state.trial = Some(Instant::now());
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn record_success(&self) {
+        let mut state = self.state.lock().expect("the mutex has been poisoned");
+        *state = BreakerState::default();
+    }
+
+    pub(crate) fn record_failure(&self) {
+        let mut state = self.state.lock().expect("the mutex has been poisoned");
+        state.failures = state.failures.saturating_add(1);
+        if state.trial.is_some() || state.failures >= self.failure_threshold {
+            state.opened_at = Some(Instant::now());
+            state.trial = None;
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Flash messages stored in the session.
+//!
+//! The flash messages are stored together with the session value in the
+//! same backend, and are removed once they are taken by `Session::take_flashes`.
+//! The session value is stored as is when there are no pending flash messages,
+//! so using the flash messages does not change the format of existing
+//! session values.
+//!
+//! # Example
+//!
+//! ```
+//! #[macro_use]
+//! extern crate finchers;
+//! extern crate finchers_session;
+//!
+//! use finchers::prelude::*;
+//! use finchers_session::Session;
+//! use finchers_session::flash::FlashLevel;
+//! use finchers_session::in_memory::{InMemoryBackend, InMemorySession};
+//!
+//! # fn main() {
+//! let backend = InMemoryBackend::default();
+//!
+//! let save = path!(@post /)
+//!     .and(backend.clone())
+//!     .and_then(|session: Session<InMemorySession>| {
+//!         session.with(|session| {
+//!             // ...
+//!             session.flash(FlashLevel::Success, "saved successfully");
+//!             Ok("redirect")
+//!         })
+//!     });
+//!
+//! let show = path!(@get /)
+//!     .and(backend)
+//!     .and_then(|session: Session<InMemorySession>| {
+//!         session.with(|session| {
+//!             let messages: Vec<String> = session
+//!                 .take_flashes()
+//!                 .into_iter()
+//!                 .map(|flash| format!("[{}] {}", flash.level(), flash.message()))
+//!                 .collect();
+//!             Ok(messages.join("\n"))
+//!         })
+//!     });
+//!
+//! let endpoint = save.or(show);
+//! # drop(move || finchers::server::start(endpoint).serve("127.0.0.1:4000"));
+//! # }
+//! ```
+
+use std::fmt;
+
+/// The level of a flash message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FlashLevel {
+    #[allow(missing_docs)]
+    Debug,
+    #[allow(missing_docs)]
+    Info,
+    #[allow(missing_docs)]
+    Success,
+    #[allow(missing_docs)]
+    Warning,
+    #[allow(missing_docs)]
+    Error,
+}
+
+impl FlashLevel {
+    /// Returns the string representation of this level.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            FlashLevel::Debug => "debug",
+            FlashLevel::Info => "info",
+            FlashLevel::Success => "success",
+            FlashLevel::Warning => "warning",
+            FlashLevel::Error => "error",
+        }
+    }
+
+    pub(crate) fn to_char(self) -> char {
+        match self {
+            FlashLevel::Debug => 'd',
+            FlashLevel::Info => 'i',
+            FlashLevel::Success => 's',
+            FlashLevel::Warning => 'w',
+            FlashLevel::Error => 'e',
+        }
+    }
+
+    pub(crate) fn from_char(c: char) -> Option<FlashLevel> {
+        match c {
+            'd' => Some(FlashLevel::Debug),
+            'i' => Some(FlashLevel::Info),
+            's' => Some(FlashLevel::Success),
+            'w' => Some(FlashLevel::Warning),
+            'e' => Some(FlashLevel::Error),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FlashLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A flash message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Flash {
+    level: FlashLevel,
+    message: String,
+}
+
+impl Flash {
+    /// Create a new `Flash` with the specified level and message.
+    pub fn new(level: FlashLevel, message: impl Into<String>) -> Flash {
+        Flash {
+            level,
+            message: message.into(),
+        }
+    }
+
+    /// Returns the level of this message.
+    pub fn level(&self) -> FlashLevel {
+        self.level
+    }
+
+    /// Returns the content of this message.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
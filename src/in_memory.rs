@@ -29,143 +29,306 @@
 //! # }
 //! ```
 
-extern crate cookie;
-
+use std::cmp;
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::sync::{PoisonError, RwLock};
+use std::time::{Duration, Instant};
 
-use finchers;
-use finchers::endpoint::{ApplyContext, ApplyResult, Endpoint};
 use finchers::error::Error;
-use finchers::input::Input;
 
-use self::cookie::Cookie;
 use futures::future;
 use uuid::Uuid;
 
-use session::{RawSession, Session};
+use error::SessionError;
+use store::{
+    session_user_id, SessionAdmin, SessionPage, SessionStore, StoreBackend, StoreSession,
+    UserSessionIndex,
+};
 
-#[derive(Debug, Default)]
-struct Storage {
-    inner: RwLock<HashMap<Uuid, String>>,
+#[derive(Debug)]
+struct Entry {
+    value: String,
+    expires_at: Option<Instant>,
+    user_id: Option<String>,
 }
 
-impl Storage {
-    fn get(&self, session_id: &Uuid) -> Result<Option<String>, Error> {
-        let inner = self.inner.read().map_err(|e| format_err!("{}", e))?;
-        Ok(inner.get(&session_id).cloned())
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.map_or(false, |expires_at| expires_at <= now)
     }
+}
 
-    fn set(&self, session_id: Uuid, value: String) -> Result<(), Error> {
-        let mut inner = self.inner.write().map_err(|e| format_err!("{}", e))?;
-        inner.insert(session_id, value);
-        Ok(())
+// The minimum number of entries which triggers the purge of expired entries.
+const MIN_PURGE_THRESHOLD: usize = 64;
+
+#[derive(Debug)]
+struct StorageInner {
+    entries: HashMap<Uuid, Entry>,
+    // The sessions of each user, with the time when the session was
+    // associated with the user.
+    users: HashMap<String, HashMap<Uuid, Instant>>,
+    // The number of entries at which the expired entries are purged next.
+    purge_threshold: usize,
+}
+
+impl Default for StorageInner {
+    fn default() -> StorageInner {
+        StorageInner {
+            entries: HashMap::new(),
+            users: HashMap::new(),
+            purge_threshold: MIN_PURGE_THRESHOLD,
+        }
     }
+}
 
-    fn remove(&self, session_id: &Uuid) -> Result<(), Error> {
-        let mut inner = self.inner.write().map_err(|e| format_err!("{}", e))?;
-        inner.remove(&session_id);
-        Ok(())
+impl StorageInner {
+    // Removes the expired entries once the number of entries has doubled since
+    // the last purge, so that the cost of scanning is amortized over the writes.
+    fn purge_expired(&mut self) {
+        if self.entries.len() < self.purge_threshold {
+            return;
+        }
+        let now = Instant::now();
+        let expired: Vec<Uuid> = self
+            .entries
+            .iter()
+            .filter(|&(_, entry)| entry.is_expired(now))
+            .map(|(&session_id, _)| session_id)
+            .collect();
+        for session_id in &expired {
+            self.remove(session_id);
+        }
+        self.purge_threshold = cmp::max(self.entries.len() * 2, MIN_PURGE_THRESHOLD);
+    }
+
+    fn remove(&mut self, session_id: &Uuid) -> Option<Entry> {
+        let entry = self.entries.remove(session_id)?;
+        if let Some(ref user_id) = entry.user_id {
+            let is_empty = match self.users.get_mut(user_id) {
+                Some(session_ids) => {
+                    session_ids.remove(session_id);
+                    session_ids.is_empty()
+                }
+                None => false,
+            };
+            if is_empty {
+                self.users.remove(user_id);
+            }
+        }
+        Some(entry)
     }
 }
 
-#[allow(missing_docs)]
-#[derive(Debug, Clone, Default)]
-pub struct InMemoryBackend {
-    inner: Arc<Inner>,
+fn poisoned<T>(err: PoisonError<T>) -> Error {
+    SessionError::storage(format_err!("{}", err))
 }
 
 #[derive(Debug, Default)]
-struct Inner {
-    storage: Storage,
+pub(crate) struct Storage {
+    inner: RwLock<StorageInner>,
 }
 
-impl InMemoryBackend {
-    fn read_value(&self, input: &mut Input) -> Result<(Option<String>, Option<Uuid>), Error> {
-        match input.cookies()?.get("session-id") {
-            Some(cookie) => {
-                let session_id: Uuid = cookie
-                    .value()
-                    .parse()
-                    .map_err(finchers::error::bad_request)?;
-                let value = self.inner.storage.get(&session_id)?;
-                Ok((value, Some(session_id)))
-            }
-            None => Ok((None, None)),
+impl Storage {
+    pub(crate) fn get(&self, session_id: &Uuid) -> Result<Option<String>, Error> {
+        let inner = self.inner.read().map_err(poisoned)?;
+        match inner.entries.get(&session_id) {
+            Some(entry) if entry.is_expired(Instant::now()) => Ok(None),
+            Some(entry) => Ok(Some(entry.value.clone())),
+            None => Ok(None),
         }
     }
 
-    fn write_value(&self, input: &mut Input, session_id: Uuid, value: String) -> Result<(), Error> {
-        self.inner.storage.set(session_id.clone(), value)?;
-        input
-            .cookies()?
-            .add(Cookie::new("session-id", session_id.to_string()));
+    pub(crate) fn set(
+        &self,
+        session_id: Uuid,
+        value: String,
+        ttl: Option<Duration>,
+    ) -> Result<(), Error> {
+        let mut inner = self.inner.write().map_err(poisoned)?;
+        let user_id = session_user_id(&value);
+        let since = user_id
+            .as_ref()
+            .and_then(|user_id| inner.users.get(user_id)?.get(&session_id).cloned())
+            .unwrap_or_else(Instant::now);
+        inner.remove(&session_id);
+
+        if let Some(ref user_id) = user_id {
+            inner
+                .users
+                .entry(user_id.clone())
+                .or_insert_with(HashMap::new)
+                .insert(session_id, since);
+        }
+        inner.entries.insert(
+            session_id,
+            Entry {
+                value,
+                expires_at: ttl.map(|ttl| Instant::now() + ttl),
+                user_id,
+            },
+        );
+        inner.purge_expired();
         Ok(())
     }
 
-    fn remove_value(&self, input: &mut Input, session_id: Uuid) -> Result<(), Error> {
-        self.inner.storage.remove(&session_id)?;
-        input.cookies()?.remove(Cookie::named("session-id"));
+    pub(crate) fn remove(&self, session_id: &Uuid) -> Result<(), Error> {
+        let mut inner = self.inner.write().map_err(poisoned)?;
+        inner.remove(&session_id);
         Ok(())
     }
-}
 
-impl<'a> Endpoint<'a> for InMemoryBackend {
-    type Output = (Session<InMemorySession>,);
-    type Future = future::FutureResult<Self::Output, Error>;
-
-    fn apply(&self, cx: &mut ApplyContext<'_>) -> ApplyResult<Self::Future> {
-        Ok(future::result(self.read_value(cx.input()).map(
-            |(value, session_id)| {
-                (Session::new(InMemorySession {
-                    backend: self.clone(),
-                    value,
-                    session_id,
-                }),)
-            },
-        )))
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.inner.read().unwrap().entries.len()
+    }
+
+    pub(crate) fn clear(&self) -> Result<(), Error> {
+        let mut inner = self.inner.write().map_err(poisoned)?;
+        *inner = StorageInner::default();
+        Ok(())
+    }
+
+    pub(crate) fn user_sessions(&self, user_id: &str) -> Result<Vec<Uuid>, Error> {
+        let inner = self.inner.read().map_err(poisoned)?;
+        let now = Instant::now();
+        let mut sessions: Vec<(Uuid, Instant)> = inner
+            .users
+            .get(user_id)
+            .into_iter()
+            .flat_map(|sessions| sessions.iter())
+            .filter(|&(session_id, _)| {
+                inner
+                    .entries
+                    .get(session_id)
+                    .map_or(false, |entry| !entry.is_expired(now))
+            }).map(|(&session_id, &since)| (session_id, since))
+            .collect();
+        sessions.sort_by_key(|&(_, since)| since);
+        Ok(sessions
+            .into_iter()
+            .map(|(session_id, _)| session_id)
+            .collect())
+    }
+
+    pub(crate) fn list(&self, cursor: Option<&str>, count: usize) -> Result<SessionPage, Error> {
+        let after: Option<Uuid> = match cursor {
+            Some(cursor) => Some(
+                cursor
+                    .parse()
+                    .map_err(|_| SessionError::InvalidCursor(cursor.to_owned()))?,
+            ),
+            None => None,
+        };
+        let inner = self.inner.read().map_err(poisoned)?;
+        let now = Instant::now();
+
+        // The cursor is the last session id in the previous page.
+        let mut session_ids: Vec<Uuid> = inner
+            .entries
+            .iter()
+            .filter(|&(session_id, entry)| {
+                !entry.is_expired(now) && after.map_or(true, |after| *session_id > after)
+            }).map(|(&session_id, _)| session_id)
+            .collect();
+        session_ids.sort();
+
+        let next_cursor = if session_ids.len() > count {
+            session_ids.truncate(count);
+            session_ids.last().map(ToString::to_string)
+        } else {
+            None
+        };
+        Ok(SessionPage {
+            session_ids,
+            next_cursor,
+        })
+    }
+
+    pub(crate) fn count(&self) -> Result<usize, Error> {
+        let inner = self.inner.read().map_err(poisoned)?;
+        let now = Instant::now();
+        Ok(inner
+            .entries
+            .values()
+            .filter(|entry| !entry.is_expired(now))
+            .count())
+    }
+
+    pub(crate) fn remove_user_sessions(&self, user_id: &str) -> Result<Vec<Uuid>, Error> {
+        let mut inner = self.inner.write().map_err(poisoned)?;
+        let session_ids: Vec<Uuid> = match inner.users.remove(user_id) {
+            Some(sessions) => sessions.into_iter().map(|(session_id, _)| session_id).collect(),
+            None => return Ok(vec![]),
+        };
+        // The expired sessions are removed as well, but not reported as revoked.
+        let now = Instant::now();
+        Ok(session_ids
+            .into_iter()
+            .filter(|session_id| {
+                inner
+                    .entries
+                    .remove(session_id)
+                    .map_or(false, |entry| !entry.is_expired(now))
+            }).collect())
     }
 }
 
-#[allow(missing_docs)]
-#[derive(Debug)]
-pub struct InMemorySession {
-    backend: InMemoryBackend,
-    session_id: Option<Uuid>,
-    value: Option<String>,
+/// The implementor of `SessionStore` which holds the session values in memory.
+///
+/// The expired session values are never returned, and are removed from memory
+/// by the subsequent saves once the number of stored values has doubled since
+/// the last removal.
+#[derive(Debug, Default)]
+pub struct InMemoryStore {
+    storage: Storage,
 }
 
-impl InMemorySession {
-    fn write_impl(self, input: &mut Input) -> Result<(), Error> {
-        match self.value {
-            Some(value) => {
-                let session_id = self.session_id.unwrap_or_else(Uuid::new_v4);
-                self.backend.write_value(input, session_id, value)
-            }
-            None => match self.session_id {
-                Some(session_id) => self.backend.remove_value(input, session_id),
-                None => Ok(()),
-            },
-        }
+impl SessionStore for InMemoryStore {
+    type LoadFuture = future::FutureResult<Option<String>, Error>;
+    type SaveFuture = future::FutureResult<(), Error>;
+    type DeleteFuture = future::FutureResult<(), Error>;
+
+    fn load(&self, session_id: &Uuid) -> Self::LoadFuture {
+        future::result(self.storage.get(session_id))
+    }
+
+    fn save(&self, session_id: &Uuid, value: String, ttl: Option<Duration>) -> Self::SaveFuture {
+        future::result(self.storage.set(*session_id, value, ttl))
+    }
+
+    fn delete(&self, session_id: &Uuid) -> Self::DeleteFuture {
+        future::result(self.storage.remove(session_id))
     }
 }
 
-impl RawSession for InMemorySession {
-    type WriteFuture = future::FutureResult<(), Error>;
+impl UserSessionIndex for InMemoryStore {
+    type ListFuture = future::FutureResult<Vec<Uuid>, Error>;
+    type RevokeFuture = future::FutureResult<Vec<Uuid>, Error>;
 
-    fn get(&self) -> Option<&str> {
-        self.value.as_ref().map(|s| s.as_ref())
+    fn list_user_sessions(&self, user_id: &str) -> Self::ListFuture {
+        future::result(self.storage.user_sessions(user_id))
     }
 
-    fn set(&mut self, value: String) {
-        self.value = Some(value);
+    fn revoke_user_sessions(&self, user_id: &str) -> Self::RevokeFuture {
+        future::result(self.storage.remove_user_sessions(user_id))
     }
+}
 
-    fn remove(&mut self) {
-        self.value = None;
+impl SessionAdmin for InMemoryStore {
+    type ListFuture = future::FutureResult<SessionPage, Error>;
+    type CountFuture = future::FutureResult<usize, Error>;
+
+    fn list_sessions(&self, cursor: Option<&str>, count: usize) -> Self::ListFuture {
+        future::result(self.storage.list(cursor, count))
     }
 
-    fn write(self, input: &mut Input) -> Self::WriteFuture {
-        future::result(self.write_impl(input))
+    fn count_sessions(&self) -> Self::CountFuture {
+        future::result(self.storage.count())
     }
 }
+
+/// The instance of session backend which uses in-memory database.
+pub type InMemoryBackend = StoreBackend<InMemoryStore>;
+
+/// The type of raw session created by `InMemoryBackend`.
+pub type InMemorySession = StoreSession<InMemoryStore>;
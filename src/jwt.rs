@@ -0,0 +1,545 @@
+//! The stateless session backend using JSON Web Token.
+//!
+//! The session value is encoded into the claims of a JWT and the token is exchanged
+//! with the client via a header or a Cookie entry (see `IdTransport`).
+//! The token is validated on every read (including the claims `exp` and `nbf`)
+//! and re-issued with the fresh timestamps on every write.
+//!
+//! By default, an expired or invalid token is discarded and the request is handled
+//! with an empty session, as if no token is sent. If the token is carried by a
+//! Cookie entry, the entry is removed from the client when the session is written.
+//! Use `JwtBackend::invalid_value_policy` to reject such requests with
+//! `SessionError::Expired` or `SessionError::Tampered` instead, and
+//! `JwtBackend::on_invalid_value` to observe them.
+//!
+//! The following algorithms are supported:
+//!
+//! * `HS256`, `HS384` and `HS512` (see `HmacAlgorithm`), created by `JwtBackend::new`.
+//!   The same secret is used for signing and verifying the tokens.
+//! * `EdDSA` with Ed25519, created by `JwtBackend::eddsa`. The tokens are signed
+//!   with the private key, and verified with the public key. The services which
+//!   only need to read the sessions can be configured by `JwtBackend::eddsa_verifier`
+//!   without sharing the private key.
+//!
+//! # Example
+//!
+//! ```
+//! #[macro_use]
+//! extern crate finchers;
+//! extern crate finchers_session;
+//!
+//! use finchers::prelude::*;
+//! use finchers_session::Session;
+//! use finchers_session::jwt::{JwtBackend, JwtSession};
+//! use finchers_session::store::IdTransport;
+//! use std::time::Duration;
+//!
+//! # fn main() {
+//! let backend = JwtBackend::new("this-is-a-secret-key")
+//!     .transport(IdTransport::bearer())
+//!     .expires_in(Duration::from_secs(60 * 30));
+//!
+//! let endpoint = path!(@get /)
+//!     .and(backend)
+//!     .and_then(|session: Session<JwtSession>| {
+//!         session.with(|_session| {
+//!             // ...
+//! #           Ok("done")
+//!         })
+//!     });
+//! # drop(move || finchers::server::start(endpoint).serve("127.0.0.1:4000"));
+//! # }
+//! ```
+
+extern crate base64;
+extern crate jsonwebtoken;
+extern crate ring;
+extern crate serde_json;
+extern crate untrusted;
+
+use finchers::endpoint::{ApplyContext, ApplyResult, Endpoint};
+use finchers::error::Error;
+use finchers::input::Input;
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use self::jsonwebtoken::errors::ErrorKind;
+use self::jsonwebtoken::{Algorithm, Header, Validation};
+use self::ring::signature::{self, Ed25519KeyPair};
+use futures::future;
+use http::header::HeaderName;
+
+use error::{InvalidValuePolicy, SessionError};
+use session::{RawSession, Session};
+use store::IdTransport;
+use wrap::Wrap;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    iat: u64,
+    nbf: u64,
+    exp: u64,
+    session: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EdDsaHeader {
+    alg: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    typ: Option<String>,
+}
+
+/// The HMAC based algorithm used for signing the tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HmacAlgorithm {
+    /// HMAC using SHA-256.
+    HS256,
+    /// HMAC using SHA-384.
+    HS384,
+    /// HMAC using SHA-512.
+    HS512,
+}
+
+impl HmacAlgorithm {
+    fn to_algorithm(self) -> Algorithm {
+        match self {
+            HmacAlgorithm::HS256 => Algorithm::HS256,
+            HmacAlgorithm::HS384 => Algorithm::HS384,
+            HmacAlgorithm::HS512 => Algorithm::HS512,
+        }
+    }
+}
+
+/// The Ed25519 private key used for signing the tokens with `EdDSA`.
+pub struct Ed25519SigningKey(Ed25519KeyPair);
+
+impl fmt::Debug for Ed25519SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Ed25519SigningKey")
+            .field(&self.public_key())
+            .finish()
+    }
+}
+
+impl Ed25519SigningKey {
+    /// Parses the key pair from an unencrypted PKCS#8 v2 document, which contains
+    /// the public key as well as the private key.
+    pub fn from_pkcs8(pkcs8: &[u8]) -> Result<Ed25519SigningKey, Error> {
+        Ed25519KeyPair::from_pkcs8(untrusted::Input::from(pkcs8))
+            .map(Ed25519SigningKey)
+            .map_err(|_| SessionError::serialization(format_err!("invalid Ed25519 key")))
+    }
+
+    /// Creates the key pair from the 32-byte seed of the private key.
+    pub fn from_seed(seed: &[u8; 32]) -> Ed25519SigningKey {
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(untrusted::Input::from(&seed[..]))
+            .expect("the seed should have the valid length");
+        Ed25519SigningKey(key_pair)
+    }
+
+    /// Returns the public key, which is distributed to the verifiers.
+    pub fn public_key(&self) -> Ed25519VerifyingKey {
+        let mut key = [0; 32];
+        key.copy_from_slice(self.0.public_key_bytes());
+        Ed25519VerifyingKey(key)
+    }
+}
+
+/// The Ed25519 public key used for verifying the tokens signed with `EdDSA`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ed25519VerifyingKey([u8; 32]);
+
+impl Ed25519VerifyingKey {
+    /// Creates a public key from its raw 32-byte representation.
+    pub fn new(key: [u8; 32]) -> Ed25519VerifyingKey {
+        Ed25519VerifyingKey(key)
+    }
+
+    /// Returns the raw 32-byte representation of this key.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+enum JwtKey {
+    Hmac {
+        secret: Vec<u8>,
+        algorithm: HmacAlgorithm,
+    },
+    EdDsa {
+        signing: Option<Ed25519SigningKey>,
+        verifying: Ed25519VerifyingKey,
+    },
+}
+
+impl fmt::Debug for JwtKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            JwtKey::Hmac { algorithm, .. } => f
+                .debug_struct("Hmac")
+                .field("algorithm", &algorithm)
+                .finish(),
+            JwtKey::EdDsa {
+                ref signing,
+                ref verifying,
+            } => f
+                .debug_struct("EdDsa")
+                .field("can_sign", &signing.is_some())
+                .field("verifying", verifying)
+                .finish(),
+        }
+    }
+}
+
+impl JwtKey {
+    fn decode(&self, token: &str, leeway: Duration) -> Result<Claims, SessionError> {
+        match *self {
+            JwtKey::Hmac {
+                ref secret,
+                algorithm,
+            } => {
+                let validation = Validation {
+                    leeway: leeway.as_secs() as i64,
+                    validate_nbf: true,
+                    algorithms: vec![algorithm.to_algorithm()],
+                    ..Validation::default()
+                };
+                jsonwebtoken::decode::<Claims>(token, secret, &validation)
+                    .map(|data| data.claims)
+                    .map_err(|err| match *err.kind() {
+                        ErrorKind::ExpiredSignature => SessionError::Expired,
+                        _ => SessionError::Tampered,
+                    })
+            }
+            JwtKey::EdDsa { ref verifying, .. } => {
+                let claims = decode_eddsa(token, verifying).ok_or(SessionError::Tampered)?;
+                let now = unix_time().map_err(|_| SessionError::Tampered)?;
+                let leeway = leeway.as_secs();
+                if claims.exp.saturating_add(leeway) < now {
+                    return Err(SessionError::Expired);
+                }
+                if claims.nbf > now.saturating_add(leeway) {
+                    return Err(SessionError::Tampered);
+                }
+                Ok(claims)
+            }
+        }
+    }
+
+    fn encode(&self, claims: &Claims) -> Result<String, Error> {
+        match *self {
+            JwtKey::Hmac {
+                ref secret,
+                algorithm,
+            } => jsonwebtoken::encode(&Header::new(algorithm.to_algorithm()), claims, secret)
+                .map_err(|err| SessionError::serialization(format_err!("{}", err))),
+            JwtKey::EdDsa {
+                signing: Some(ref signing),
+                ..
+            } => {
+                let header = EdDsaHeader {
+                    alg: "EdDSA".into(),
+                    typ: Some("JWT".into()),
+                };
+                let mut token = encode_segment(&header)?;
+                token += ".";
+                token += &encode_segment(claims)?;
+                let sig = signing.0.sign(token.as_bytes());
+                token += ".";
+                token += &base64::encode_config(sig.as_ref(), base64::URL_SAFE_NO_PAD);
+                Ok(token)
+            }
+            JwtKey::EdDsa { signing: None, .. } => Err(SessionError::serialization(
+                format_err!("the backend has no Ed25519 private key for signing the tokens"),
+            )),
+        }
+    }
+}
+
+fn encode_segment<T: ::serde::Serialize>(value: &T) -> Result<String, Error> {
+    let json = serde_json::to_vec(value).map_err(SessionError::serialization)?;
+    Ok(base64::encode_config(&json, base64::URL_SAFE_NO_PAD))
+}
+
+// Verifies the signature and returns the claims, or `None` if the token is invalid.
+fn decode_eddsa(token: &str, key: &Ed25519VerifyingKey) -> Option<Claims> {
+    let mut parts = token.rsplitn(2, '.');
+    let sig = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+    let message = parts.next()?;
+    let mut segments = message.splitn(2, '.');
+    let header = base64::decode_config(segments.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+    let claims = base64::decode_config(segments.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+
+    // The algorithm is fixed by the key, regardless of the header.
+    let header: EdDsaHeader = serde_json::from_slice(&header).ok()?;
+    if header.alg != "EdDSA" {
+        return None;
+    }
+    signature::verify(
+        &signature::ED25519,
+        untrusted::Input::from(&key.0[..]),
+        untrusted::Input::from(message.as_bytes()),
+        untrusted::Input::from(&sig),
+    ).ok()?;
+    serde_json::from_slice(&claims).ok()
+}
+
+fn unix_time() -> Result<u64, Error> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .map_err(SessionError::serialization)
+}
+
+type InvalidValueHook = dyn Fn(&SessionError) + Send + Sync;
+
+struct JwtConfig {
+    key: JwtKey,
+    transport: IdTransport,
+    expires_in: Duration,
+    leeway: Duration,
+    invalid_value_policy: InvalidValuePolicy,
+    on_invalid_value: Option<Arc<InvalidValueHook>>,
+}
+
+impl fmt::Debug for JwtConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtConfig")
+            .field("key", &self.key)
+            .field("transport", &self.transport)
+            .field("expires_in", &self.expires_in)
+            .field("leeway", &self.leeway)
+            .field("invalid_value_policy", &self.invalid_value_policy)
+            .finish()
+    }
+}
+
+impl JwtConfig {
+    fn read_value(&self, input: &mut Input) -> Result<(Option<String>, bool), Error> {
+        let token = match self.transport.read(input)? {
+            Some(token) => token,
+            None => return Ok((None, false)),
+        };
+
+        match self.key.decode(&token, self.leeway) {
+            Ok(claims) => Ok((Some(claims.session), true)),
+            Err(err) => {
+                if let Some(ref on_invalid_value) = self.on_invalid_value {
+                    on_invalid_value(&err);
+                }
+                match self.invalid_value_policy {
+                    // The token is cleared when the session is written.
+                    InvalidValuePolicy::Discard => Ok((None, true)),
+                    InvalidValuePolicy::Reject => {
+                        self.transport.clear(input)?;
+                        Err(err.into())
+                    }
+                }
+            }
+        }
+    }
+
+    fn issue(&self, value: String, now: u64) -> Result<String, Error> {
+        let claims = Claims {
+            iat: now,
+            nbf: now,
+            exp: now + self.expires_in.as_secs(),
+            session: value,
+        };
+        self.key.encode(&claims)
+    }
+
+    fn write_value(&self, input: &mut Input, value: String) -> Result<(), Error> {
+        let token = self.issue(value, unix_time()?)?;
+        self.transport.write(input, token)
+    }
+}
+
+/// The instance of session backend which stores the session values in JWTs.
+#[derive(Debug, Clone)]
+pub struct JwtBackend {
+    config: Arc<JwtConfig>,
+}
+
+impl JwtBackend {
+    fn from_key(key: JwtKey) -> JwtBackend {
+        JwtBackend {
+            config: Arc::new(JwtConfig {
+                key,
+                transport: IdTransport::bearer()
+                    .response_header(HeaderName::from_static("x-session-token")),
+                expires_in: Duration::from_secs(60 * 60),
+                leeway: Duration::from_secs(0),
+                invalid_value_policy: InvalidValuePolicy::Discard,
+                on_invalid_value: None,
+            }),
+        }
+    }
+
+    /// Creates a `JwtBackend` which signs the tokens with the specified secret key
+    /// using `HS256`.
+    pub fn new(secret: impl Into<Vec<u8>>) -> JwtBackend {
+        JwtBackend::from_key(JwtKey::Hmac {
+            secret: secret.into(),
+            algorithm: HmacAlgorithm::HS256,
+        })
+    }
+
+    /// Creates a `JwtBackend` which signs the tokens with the specified private key
+    /// using `EdDSA`, and verifies them with the corresponding public key.
+    pub fn eddsa(signing_key: Ed25519SigningKey) -> JwtBackend {
+        let verifying = signing_key.public_key();
+        JwtBackend::from_key(JwtKey::EdDsa {
+            signing: Some(signing_key),
+            verifying,
+        })
+    }
+
+    /// Creates a `JwtBackend` which only verifies the tokens signed using `EdDSA`
+    /// with the specified public key.
+    ///
+    /// The sessions created by this backend can be read, but writing a modified
+    /// session value fails with `SessionError::Serialization` since no token
+    /// can be issued without the private key.
+    pub fn eddsa_verifier(verifying_key: Ed25519VerifyingKey) -> JwtBackend {
+        JwtBackend::from_key(JwtKey::EdDsa {
+            signing: None,
+            verifying: verifying_key,
+        })
+    }
+
+    fn config_mut(&mut self) -> &mut JwtConfig {
+        Arc::get_mut(&mut self.config).expect("The instance has already shared.")
+    }
+
+    /// Sets the algorithm used for signing the tokens.
+    ///
+    /// The default value is `HmacAlgorithm::HS256`.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the backend is not created by `JwtBackend::new`.
+    pub fn algorithm(mut self, value: HmacAlgorithm) -> JwtBackend {
+        match self.config_mut().key {
+            JwtKey::Hmac {
+                ref mut algorithm, ..
+            } => *algorithm = value,
+            _ => panic!("The backend does not use the HMAC based algorithms."),
+        }
+        self
+    }
+
+    /// Sets the transport which exchanges the token with the client.
+    ///
+    /// The default value is `Authorization: Bearer <token>` for requests
+    /// and `X-Session-Token` for responses.
+    pub fn transport(mut self, transport: IdTransport) -> JwtBackend {
+        self.config_mut().transport = transport;
+        self
+    }
+
+    /// Sets the lifetime of issued tokens.
+    ///
+    /// The default value is 1 hour.
+    pub fn expires_in(mut self, value: Duration) -> JwtBackend {
+        self.config_mut().expires_in = value;
+        self
+    }
+
+    /// Sets the leeway used when validating the claims `exp` and `nbf`.
+    ///
+    /// The default value is 0 seconds.
+    pub fn leeway(mut self, value: Duration) -> JwtBackend {
+        self.config_mut().leeway = value;
+        self
+    }
+
+    /// Sets the policy applied when the token sent by the client is expired or invalid.
+    ///
+    /// The default value is `InvalidValuePolicy::Discard`.
+    pub fn invalid_value_policy(mut self, policy: InvalidValuePolicy) -> JwtBackend {
+        self.config_mut().invalid_value_policy = policy;
+        self
+    }
+
+    /// Registers a callback which is called with `SessionError::Expired` or
+    /// `SessionError::Tampered` when the token sent by the client is rejected.
+    pub fn on_invalid_value<F>(mut self, f: F) -> JwtBackend
+    where
+        F: Fn(&SessionError) + Send + Sync + 'static,
+    {
+        self.config_mut().on_invalid_value = Some(Arc::new(f));
+        self
+    }
+
+    /// Wraps the specified endpoint so that the session is committed automatically
+    /// after the endpoint.
+    ///
+    /// See the module `wrap` for details.
+    pub fn wrap<E>(self, endpoint: E) -> Wrap<JwtBackend, E> {
+        Wrap::new(self, endpoint)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn issue_token(&self, value: &str, issued_at: SystemTime) -> String {
+        let now = issued_at.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.config.issue(value.into(), now).unwrap()
+    }
+}
+
+impl<'a> Endpoint<'a> for JwtBackend {
+    type Output = (Session<JwtSession>,);
+    type Future = future::FutureResult<Self::Output, Error>;
+
+    fn apply(&self, cx: &mut ApplyContext<'_>) -> ApplyResult<Self::Future> {
+        Ok(future::result(self.config.read_value(cx.input()).map(
+            |(value, has_token)| {
+                (Session::new(JwtSession {
+                    config: self.config.clone(),
+                    value,
+                    has_token,
+                }),)
+            },
+        )))
+    }
+}
+
+/// The type of raw session created by `JwtBackend`.
+#[derive(Debug)]
+pub struct JwtSession {
+    config: Arc<JwtConfig>,
+    value: Option<String>,
+    has_token: bool,
+}
+
+impl JwtSession {
+    fn write_impl(self, input: &mut Input) -> Result<(), Error> {
+        match self.value {
+            Some(value) => self.config.write_value(input, value),
+            None if self.has_token => self.config.transport.clear(input),
+            None => Ok(()),
+        }
+    }
+}
+
+impl RawSession for JwtSession {
+    type WriteFuture = future::FutureResult<(), Error>;
+
+    fn get(&self) -> Option<&str> {
+        self.value.as_ref().map(|s| s.as_str())
+    }
+
+    fn set(&mut self, value: String) {
+        self.value = Some(value);
+    }
+
+    fn remove(&mut self) {
+        self.value = None;
+    }
+
+    fn write(self, input: &mut Input) -> Self::WriteFuture {
+        future::result(self.write_impl(input))
+    }
+}
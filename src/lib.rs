@@ -8,10 +8,18 @@
 //! * Cookie
 //! * In-memory database
 //! * Redis (requires the feature flag `feature = "redis"`)
+//! * JSON Web Token (requires the feature flag `feature = "jwt"`)
+//! * Custom key-value stores (via the trait `store::SessionStore`)
 //!
 //! # Feature Flags
 //!
 //! * `redis` - enable Redis backend (default: off)
+//! * `jwt` - enable JSON Web Token backend (default: off)
+//! * `paseto` - enable PASETO `v4.local` tokens for Cookie values (default: off)
+//! * `admin` - enable HTTP endpoints for managing the sessions (default: off)
+//! * `cli` - build the command line tool `finchers-session-cli`, which decodes
+//!           and mints Cookie values and manages the sessions in Redis (default: off)
+//! * `testing` - enable utilities for testing the handlers which use sessions (default: off)
 //! * `secure` - enable signing and encryption support for Cookie values
 //!              (default: on. it adds the crate `ring` to dependencies).
 
@@ -29,23 +37,50 @@
 
 #[macro_use]
 extern crate failure;
+#[cfg_attr(test, macro_use)]
 extern crate finchers;
-#[cfg_attr(feature = "redis", macro_use)]
+#[macro_use]
 extern crate futures;
+extern crate http;
+#[macro_use]
+extern crate log;
+#[cfg(any(feature = "jwt", feature = "admin"))]
+#[macro_use]
+extern crate serde;
 extern crate time;
+extern crate tokio_timer;
 extern crate uuid;
 
-#[cfg(test)]
-extern crate http;
-
+mod envelope;
 mod session;
 #[cfg(test)]
 mod tests;
 mod util;
 
+#[cfg(feature = "admin")]
+pub mod admin;
+pub mod auth;
+pub mod binding;
+pub mod cache;
 pub mod cookie;
+pub mod csrf;
+pub mod error;
+pub mod event;
+pub mod fallback;
+pub mod flash;
 pub mod in_memory;
+#[cfg(feature = "jwt")]
+pub mod jwt;
+pub mod limit;
+#[cfg(feature = "paseto")]
+pub mod paseto;
+pub mod read_only;
 #[cfg(feature = "redis")]
 pub mod redis;
+pub mod store;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod wrap;
 
+pub use self::error::SessionError;
 pub use self::session::{RawSession, Session};
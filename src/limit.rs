@@ -0,0 +1,242 @@
+//! Limiting the number of concurrent sessions per user.
+//!
+//! When a session is newly associated with a user by `Session::login`,
+//! `StoreBackend` counts the other active sessions of the user by using
+//! the user index of the store (see `store::UserSessionIndex`), and then
+//! evicts the oldest sessions or rejects the new login if the limit is exceeded.
+//!
+//! The limit is enforced on a best-effort basis. The count (and the evictions)
+//! and the save of the new session are separate operations on the store, and
+//! are not performed atomically. When several logins of the same user are
+//! processed concurrently, each of them may observe the same number of sessions,
+//! so the user may temporarily have more sessions than the limit (until the
+//! next login of the user enforces it again), or `LimitPolicy::EvictOldest`
+//! may evict more sessions than necessary. Do not rely on the limit as a
+//! strict security boundary.
+//!
+//! # Example
+//!
+//! ```
+//! #[macro_use]
+//! extern crate finchers;
+//! extern crate finchers_session;
+//!
+//! use finchers::prelude::*;
+//! use finchers_session::Session;
+//! use finchers_session::in_memory::{InMemoryBackend, InMemorySession};
+//! use finchers_session::limit::{LimitPolicy, SessionLimit};
+//!
+//! # fn main() {
+//! let backend = InMemoryBackend::default().session_limit(
+//!     SessionLimit::new(3)
+//!         .policy(LimitPolicy::EvictOldest)
+//!         .on_evict(|_user_id, _session_id| -> Result<(), finchers::error::Error> {
+//!             // ... notify the evicted device ...
+//!             Ok(())
+//!         }),
+//! );
+//!
+//! let endpoint = path!(@post / "login")
+//!     .and(backend)
+//!     .and_then(|session: Session<InMemorySession>| {
+//!         session.with(|session| {
+//!             session.login("alice");
+//!             Ok("logged in")
+//!         })
+//!     });
+//! # drop(move || finchers::server::start(endpoint).serve("127.0.0.1:4000"));
+//! # }
+//! ```
+
+use finchers::error::{Error, HttpError};
+
+use std::fmt;
+use std::sync::Arc;
+
+use futures::future::{self, Either};
+use futures::{Future, IntoFuture};
+use http::StatusCode;
+use uuid::Uuid;
+
+use store::UserSessionIndex;
+
+/// The error type which represents that the user already has the maximum
+/// number of active sessions.
+#[derive(Debug)]
+pub struct SessionLimitExceeded {
+    _priv: (),
+}
+
+impl fmt::Display for SessionLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("too many active sessions")
+    }
+}
+
+impl HttpError for SessionLimitExceeded {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::FORBIDDEN
+    }
+}
+
+/// The behavior when the number of sessions exceeds the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Removes the oldest sessions of the user to make room for the new one.
+    EvictOldest,
+    /// Rejects the new login with `SessionLimitExceeded`.
+    RejectNew,
+}
+
+type EvictHook =
+    dyn Fn(&str, &Uuid) -> Box<dyn Future<Item = (), Error = Error> + Send> + Send + Sync;
+
+/// The configuration of the limit on the number of sessions per user.
+#[derive(Clone)]
+pub struct SessionLimit {
+    max_sessions: usize,
+    policy: LimitPolicy,
+    on_evict: Option<Arc<EvictHook>>,
+}
+
+impl fmt::Debug for SessionLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionLimit")
+            .field("max_sessions", &self.max_sessions)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+impl SessionLimit {
+    /// Create a new `SessionLimit` which allows at most `max_sessions`
+    /// active sessions per user.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `max_sessions` is zero.
+    pub fn new(max_sessions: usize) -> SessionLimit {
+        assert!(max_sessions > 0, "max_sessions must be greater than zero");
+        SessionLimit {
+            max_sessions,
+            policy: LimitPolicy::EvictOldest,
+            on_evict: None,
+        }
+    }
+
+    /// Set the behavior when the limit is exceeded.
+    ///
+    /// The default value is `LimitPolicy::EvictOldest`.
+    pub fn policy(mut self, policy: LimitPolicy) -> SessionLimit {
+        self.policy = policy;
+        self
+    }
+
+    /// Registers a callback which is called with the user id and the session id
+    /// after each session is evicted.
+    ///
+    /// The write of the new session fails if the returned future fails.
+    pub fn on_evict<F, R>(mut self, f: F) -> SessionLimit
+    where
+        F: Fn(&str, &Uuid) -> R + Send + Sync + 'static,
+        R: IntoFuture<Item = (), Error = Error>,
+        R::Future: Send + 'static,
+    {
+        self.on_evict = Some(Arc::new(move |user_id: &str, session_id: &Uuid| {
+            Box::new(f(user_id, session_id).into_future())
+                as Box<dyn Future<Item = (), Error = Error> + Send>
+        }));
+        self
+    }
+}
+
+type EnforceFn<K> = fn(&SessionLimit, &Arc<K>, &str, Vec<Uuid>)
+    -> Box<dyn Future<Item = (), Error = Error> + Send>;
+
+/// The limit bound to a specific store type.
+pub(crate) struct Limiter<K> {
+    limit: SessionLimit,
+    enforce: EnforceFn<K>,
+}
+
+impl<K> fmt::Debug for Limiter<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Limiter").field("limit", &self.limit).finish()
+    }
+}
+
+impl<K> Limiter<K> {
+    pub(crate) fn new(limit: SessionLimit) -> Limiter<K>
+    where
+        K: UserSessionIndex + Send + Sync + 'static,
+        K::ListFuture: Send + 'static,
+        K::DeleteFuture: Send + 'static,
+    {
+        Limiter {
+            limit,
+            enforce: enforce::<K>,
+        }
+    }
+
+    /// Returns a future which makes room for a new session of the specified user.
+    ///
+    /// The sessions in `excluded` (i.e. the session being written) are not counted.
+    /// The new session is saved after the returned future completes, so the check
+    /// is not atomic with the save (see the module documentation).
+    pub(crate) fn enforce(
+        &self,
+        store: &Arc<K>,
+        user_id: &str,
+        excluded: Vec<Uuid>,
+    ) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        (self.enforce)(&self.limit, store, user_id, excluded)
+    }
+}
+
+fn enforce<K>(
+    limit: &SessionLimit,
+    store: &Arc<K>,
+    user_id: &str,
+    excluded: Vec<Uuid>,
+) -> Box<dyn Future<Item = (), Error = Error> + Send>
+where
+    K: UserSessionIndex + Send + Sync + 'static,
+    K::ListFuture: Send + 'static,
+    K::DeleteFuture: Send + 'static,
+{
+    let limit = limit.clone();
+    let store = store.clone();
+    let user_id = user_id.to_owned();
+    Box::new(
+        store
+            .list_user_sessions(&user_id)
+            .and_then(move |session_ids| {
+                // The session ids are ordered from the oldest one.
+                let active: Vec<Uuid> = session_ids
+                    .into_iter()
+                    .filter(|session_id| !excluded.contains(session_id))
+                    .collect();
+                if active.len() < limit.max_sessions {
+                    return Either::A(future::ok(()));
+                }
+                if limit.policy == LimitPolicy::RejectNew {
+                    return Either::A(future::err(SessionLimitExceeded { _priv: () }.into()));
+                }
+
+                let num_evicted = active.len() + 1 - limit.max_sessions;
+                let evictions: Vec<_> = active
+                    .into_iter()
+                    .take(num_evicted)
+                    .map(|session_id| {
+                        let on_evict = limit.on_evict.clone();
+                        let user_id = user_id.clone();
+                        store.delete(&session_id).and_then(move |()| match on_evict {
+                            Some(on_evict) => on_evict(&user_id, &session_id),
+                            None => Box::new(future::ok(()))
+                                as Box<dyn Future<Item = (), Error = Error> + Send>,
+                        })
+                    }).collect();
+                Either::B(future::join_all(evictions).map(|_| ()))
+            }),
+    )
+}
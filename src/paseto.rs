@@ -0,0 +1,229 @@
+//! PASETO `v4.local` tokens used as an authenticated format of Cookie values.
+//!
+//! The tokens are encrypted with XChaCha20 and authenticated with keyed BLAKE2b
+//! as described in the PASETO specification, so that they can be decoded by any
+//! conforming implementation in the other languages. If the key has an id,
+//! it is stored in the footer of tokens as `{"kid":"<id>"}`.
+
+extern crate base64;
+extern crate blake2;
+extern crate chacha20;
+extern crate rand;
+extern crate stream_cipher;
+
+use std::fmt;
+
+use self::blake2::digest::{Input, VariableOutput};
+use self::blake2::VarBlake2b;
+use self::chacha20::XChaCha20;
+use self::rand::Rng;
+use self::stream_cipher::generic_array::GenericArray;
+use self::stream_cipher::{NewStreamCipher, SyncStreamCipher};
+
+use util::constant_time_eq;
+
+const HEADER: &str = "v4.local.";
+
+/// A symmetric key used for encrypting the tokens in the PASETO `v4.local` format.
+#[derive(Clone)]
+pub struct PasetoKey {
+    id: Option<String>,
+    key: [u8; 32],
+}
+
+impl fmt::Debug for PasetoKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PasetoKey").field("id", &self.id).finish()
+    }
+}
+
+impl PasetoKey {
+    /// Creates a `PasetoKey` from the specified 256-bit key material.
+    pub fn new(key: [u8; 32]) -> PasetoKey {
+        PasetoKey { id: None, key }
+    }
+
+    /// Sets the key id which is stored in the footer of tokens.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the id contains characters other than ASCII
+    /// alphanumerics, `-` and `_`.
+    pub fn with_id(mut self, id: impl Into<String>) -> PasetoKey {
+        let id = id.into();
+        assert!(
+            id.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'),
+            "invalid key id: {:?}",
+            id
+        );
+        self.id = Some(id);
+        self
+    }
+
+    /// Returns the key id, if available.
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_ref().map(|s| s.as_str())
+    }
+
+    fn footer(&self) -> Vec<u8> {
+        match self.id {
+            Some(ref id) => format!(r#"{{"kid":"{}"}}"#, id).into_bytes(),
+            None => vec![],
+        }
+    }
+
+    /// Encrypts the message into a token.
+    ///
+    /// The value of `implicit` is bound to the token as an implicit assertion,
+    /// which must be provided again when decrypting the token.
+    pub fn encrypt(&self, message: &[u8], implicit: &[u8]) -> String {
+        let mut nonce = [0u8; 32];
+        rand::thread_rng().fill(&mut nonce);
+        self.encrypt_with_nonce(message, implicit, &nonce)
+    }
+
+    pub(crate) fn encrypt_with_nonce(
+        &self,
+        message: &[u8],
+        implicit: &[u8],
+        nonce: &[u8; 32],
+    ) -> String {
+        let footer = self.footer();
+        let (ek, n2, ak) = self.derive_keys(nonce);
+
+        let mut ciphertext = message.to_vec();
+        XChaCha20::new(GenericArray::from_slice(&ek), GenericArray::from_slice(&n2))
+            .apply_keystream(&mut ciphertext);
+
+        let mut tag = [0u8; 32];
+        blake2b(
+            &ak,
+            &[&pae(&[HEADER.as_bytes(), nonce, &ciphertext, &footer, implicit])],
+            &mut tag,
+        );
+
+        let mut payload = Vec::with_capacity(nonce.len() + ciphertext.len() + tag.len());
+        payload.extend_from_slice(nonce);
+        payload.extend_from_slice(&ciphertext);
+        payload.extend_from_slice(&tag);
+
+        let mut token = String::from(HEADER);
+        token += &base64::encode_config(&payload, base64::URL_SAFE_NO_PAD);
+        if !footer.is_empty() {
+            token.push('.');
+            token += &base64::encode_config(&footer, base64::URL_SAFE_NO_PAD);
+        }
+        token
+    }
+
+    /// Decrypts the token and returns the original message.
+    ///
+    /// This method returns `None` if the token is malformed, is not authenticated
+    /// by this key, or has a footer different from the one of this key.
+    pub fn decrypt(&self, token: &str, implicit: &[u8]) -> Option<Vec<u8>> {
+        if !token.starts_with(HEADER) {
+            return None;
+        }
+        let mut parts = token[HEADER.len()..].splitn(2, '.');
+        let payload = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+        let footer = match parts.next() {
+            Some(footer) => base64::decode_config(footer, base64::URL_SAFE_NO_PAD).ok()?,
+            None => vec![],
+        };
+        if !constant_time_eq(&footer, &self.footer()) || payload.len() < 64 {
+            return None;
+        }
+
+        let (nonce, rest) = payload.split_at(32);
+        let (ciphertext, tag) = rest.split_at(rest.len() - 32);
+        let (ek, n2, ak) = self.derive_keys(nonce);
+
+        let mut expected = [0u8; 32];
+        blake2b(
+            &ak,
+            &[&pae(&[HEADER.as_bytes(), nonce, ciphertext, &footer, implicit])],
+            &mut expected,
+        );
+        if !constant_time_eq(tag, &expected) {
+            return None;
+        }
+
+        let mut message = ciphertext.to_vec();
+        XChaCha20::new(GenericArray::from_slice(&ek), GenericArray::from_slice(&n2))
+            .apply_keystream(&mut message);
+        Some(message)
+    }
+
+    fn derive_keys(&self, nonce: &[u8]) -> ([u8; 32], [u8; 24], [u8; 32]) {
+        let mut tmp = [0u8; 56];
+        blake2b(&self.key, &[b"paseto-encryption-key", nonce], &mut tmp);
+        let mut ak = [0u8; 32];
+        blake2b(&self.key, &[b"paseto-auth-key-for-aead", nonce], &mut ak);
+
+        let mut ek = [0u8; 32];
+        ek.copy_from_slice(&tmp[..32]);
+        let mut n2 = [0u8; 24];
+        n2.copy_from_slice(&tmp[32..]);
+        (ek, n2, ak)
+    }
+}
+
+/// A set of keys which consists of the key used for encryption and
+/// the previous keys which are only used for decryption.
+#[derive(Debug, Clone)]
+pub(crate) struct PasetoKeys {
+    pub(crate) current: PasetoKey,
+    pub(crate) previous: Vec<PasetoKey>,
+}
+
+impl PasetoKeys {
+    pub(crate) fn new(current: PasetoKey) -> PasetoKeys {
+        PasetoKeys {
+            current,
+            previous: vec![],
+        }
+    }
+
+    pub(crate) fn encrypt(&self, message: &str, implicit: &[u8]) -> String {
+        self.current.encrypt(message.as_bytes(), implicit)
+    }
+
+    pub(crate) fn decrypt(&self, token: &str, implicit: &[u8]) -> Option<String> {
+        // Since the key id is a part of the footer, keys with a different
+        // id are rejected before verifying the tag.
+        Some(&self.current)
+            .into_iter()
+            .chain(&self.previous)
+            .filter_map(|key| key.decrypt(token, implicit))
+            .next()
+            .and_then(|message| String::from_utf8(message).ok())
+    }
+}
+
+fn blake2b(key: &[u8], input: &[&[u8]], output: &mut [u8]) {
+    let mut hasher = VarBlake2b::new_keyed(key, output.len());
+    for data in input {
+        hasher.input(*data);
+    }
+    hasher.variable_result(|result| output.copy_from_slice(result));
+}
+
+/// Pre-Authentication Encoding
+fn pae(pieces: &[&[u8]]) -> Vec<u8> {
+    let mut output = le64(pieces.len() as u64).to_vec();
+    for piece in pieces {
+        output.extend_from_slice(&le64(piece.len() as u64));
+        output.extend_from_slice(piece);
+    }
+    output
+}
+
+fn le64(n: u64) -> [u8; 8] {
+    let n = n & 0x7FFF_FFFF_FFFF_FFFF;
+    let mut output = [0u8; 8];
+    for (i, b) in output.iter_mut().enumerate() {
+        *b = (n >> (8 * i)) as u8;
+    }
+    output
+}
@@ -0,0 +1,95 @@
+//! Read-only access to the session.
+//!
+//! `Session` must be converted into a future (typically by `Session::with`) in
+//! order to finish the session handling, which writes the session value back
+//! to the backend. The endpoint `ReadOnly` yields a `SessionRef` instead, which
+//! only exposes the loaded value and never writes anything to the backend nor
+//! to the response.
+//!
+//! Note that a stale session id sent by the client is not removed from the
+//! client by the read-only sessions. Likewise, a Cookie value or a token which
+//! cannot be verified is discarded without being removed from the client under
+//! the default `InvalidValuePolicy::Discard`. The only exception is
+//! `InvalidValuePolicy::Reject` of the Cookie and JWT backends, which removes the
+//! value from the client while reading it, so the rejected response carries
+//! a `Set-Cookie` header which removes the entry.
+//!
+//! # Example
+//!
+//! ```
+//! #[macro_use]
+//! extern crate finchers;
+//! extern crate finchers_session;
+//!
+//! use finchers::prelude::*;
+//! use finchers_session::in_memory::{InMemoryBackend, InMemorySession};
+//! use finchers_session::read_only::{ReadOnly, SessionRef};
+//!
+//! # fn main() {
+//! let backend = InMemoryBackend::default();
+//!
+//! let endpoint = path!(@get / "header")
+//!     .and(ReadOnly::new(backend))
+//!     .map(|session: SessionRef<InMemorySession>| match session.user_id() {
+//!         Some(user_id) => format!("Signed in as {}", user_id),
+//!         None => "Not signed in".to_owned(),
+//!     });
+//! # drop(move || finchers::server::start(endpoint).serve("127.0.0.1:4000"));
+//! # }
+//! ```
+
+use finchers::endpoint::{ApplyContext, ApplyResult, Endpoint};
+
+use futures::future;
+use futures::Future;
+
+use session::{RawSession, Session};
+
+/// A read-only view of the session loaded for the current request.
+#[derive(Debug)]
+pub struct SessionRef<S: RawSession> {
+    session: Session<S>,
+}
+
+impl<S> SessionRef<S>
+where
+    S: RawSession,
+{
+    /// Get the session value if available.
+    pub fn get(&self) -> Option<&str> {
+        self.session.get()
+    }
+
+    /// Returns the id of the user authenticated in this session, if available.
+    pub fn user_id(&self) -> Option<String> {
+        self.session.user_id()
+    }
+}
+
+/// An endpoint which yields a `SessionRef` from the session endpoint.
+#[derive(Debug, Clone)]
+pub struct ReadOnly<E> {
+    session: E,
+}
+
+impl<E> ReadOnly<E> {
+    /// Create a new `ReadOnly` from the endpoint which yields the session.
+    pub fn new(session: E) -> ReadOnly<E> {
+        ReadOnly { session }
+    }
+}
+
+impl<'a, E, S> Endpoint<'a> for ReadOnly<E>
+where
+    E: Endpoint<'a, Output = (Session<S>,)>,
+    S: RawSession + 'a,
+{
+    type Output = (SessionRef<S>,);
+    type Future = future::Map<E::Future, fn((Session<S>,)) -> (SessionRef<S>,)>;
+
+    fn apply(&'a self, cx: &mut ApplyContext<'_>) -> ApplyResult<Self::Future> {
+        let future = self.session.apply(cx)?;
+        // The session is dropped without being written.
+        Ok(future.map((|(session,)| (SessionRef { session },)) as fn(_) -> _))
+    }
+}
@@ -36,78 +36,683 @@
 //! # });
 //! # }
 //! ```
+//!
+//! # Key layout
+//!
+//! The keys are prefixed by the value of `RedisBackend::key_prefix`
+//! (`finchers-sesssion` by default):
+//!
+//! * `<prefix>:<session-id>` - a string which holds the session value.
+//! * `<prefix>:user:<user-id>` - a sorted set of the session ids associated
+//!   with the user, whose scores are the UNIX time (in milliseconds) when each
+//!   session was associated with the user.
+//!
+//! The session value, the index and the message for the invalidation are
+//! written in a single `MULTI`/`EXEC` transaction. The deletion of a session
+//! fetches the value beforehand in order to remove the session id from the index.
+//! The revocation of the sessions of a user deletes the sessions and the index in
+//! a transaction guarded by `WATCH` on the index, which is retried (and fails with
+//! `SessionError::Conflict` after 5 attempts) if a session is added concurrently.
+//!
+//! # Timeouts
+//!
+//! By default, the operations on Redis wait for the replies without any deadline.
+//! `RedisBackend::connect_timeout`, `RedisBackend::command_timeout` and
+//! `RedisBackend::total_timeout` bound the time of establishing the connection,
+//! of each command (a transaction counts as a single command), and of the whole
+//! operation respectively. An operation which exceeds one of them fails with
+//! `SessionError::Timeout`.
+//!
+//! A connection which has failed or timed out is closed, so that a late reply
+//! is never read as the reply of another command.
+//! The timeouts require the timer of Tokio runtime.
+//!
+//! # Connections
+//!
+//! The connections are kept in a pool after each successful operation and
+//! reused by the subsequent operations (up to `RedisBackend::max_idle_connections`).
+//! The pooled connections are bound to the reactor of the Tokio runtime on which
+//! they have been established.
+//!
+//! # Local cache
+//!
+//! `RedisStore` can be combined with `CachedStore` in order to reduce the
+//! number of round trips to Redis. The cached values on the other instances
+//! of the application are invalidated via Redis pub/sub.
+//!
+//! ```
+//! #[macro_use]
+//! extern crate finchers;
+//! extern crate finchers_session;
+//!
+//! use finchers::prelude::*;
+//! use finchers_session::Session;
+//! use finchers_session::cache::CachedStore;
+//! use finchers_session::redis::{self, Client, RedisStore};
+//! use finchers_session::store::{StoreBackend, StoreSession};
+//! use std::time::Duration;
+//!
+//! # fn main() {
+//! # drop(|| {
+//! let client = Client::open("redis://127.0.0.1/").unwrap();
+//! let store = CachedStore::new(
+//!     RedisStore::new(client.clone()).invalidation_channel("my-app-sessions"),
+//! ).local_ttl(Duration::from_secs(10));
+//! redis::listen_invalidation(&client, "my-app-sessions", store.invalidator()).unwrap();
+//!
+//! let endpoint = path!(@get /)
+//!     .and(StoreBackend::from_store(store))
+//!     .and_then(|session: Session<StoreSession<CachedStore<RedisStore>>>| {
+//!         session.with(|_session| {
+//!             // ...
+//! #           Ok("done")
+//!         })
+//!     });
+//! # finchers::server::start(endpoint).serve("127.0.0.1:4000")
+//! # });
+//! # }
+//! ```
 
-extern crate cookie;
 extern crate redis;
 
-use finchers;
-use finchers::endpoint::{ApplyContext, ApplyResult, Endpoint};
 use finchers::error::Error;
-use finchers::input::Input;
 
-use std::borrow::Cow;
+use std::cmp;
 use std::fmt;
 use std::mem;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use self::redis::async::Connection;
 #[doc(no_inline)]
 pub use self::redis::Client;
-use self::redis::RedisFuture;
+use self::redis::{RedisError, RedisFuture};
 
-use self::cookie::Cookie;
+use futures::future::{self, Either, Loop};
 use futures::{Async, Future, Poll};
+use tokio_timer::Delay;
 use uuid::Uuid;
 
-use session::{RawSession, Session};
+use cache::CacheInvalidator;
+use error::SessionError;
+use store::{
+    session_user_id, SessionAdmin, SessionPage, SessionStore, StoreBackend, StoreSession,
+    UserSessionIndex,
+};
+
+// The idle connections kept for reuse.
+struct Pool {
+    client: Client,
+    idle: Mutex<Vec<Connection>>,
+    max_idle: usize,
+}
+
+impl fmt::Debug for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pool")
+            .field("client", &self.client)
+            .field("max_idle", &self.max_idle)
+            .finish()
+    }
+}
 
+impl Pool {
+    fn new(client: Client) -> Pool {
+        Pool {
+            client,
+            idle: Mutex::new(vec![]),
+            max_idle: 16,
+        }
+    }
+
+    // Takes an idle connection, or establishes a new one if there is none.
+    //
+    // An idle connection may have been closed by the server while it sat in
+    // the pool, so it is checked with a PING before being handed out and
+    // replaced by a fresh connection if the check fails.
+    fn get(&self) -> RedisFuture<Connection> {
+        let conn = self.idle.lock().ok().and_then(|mut idle| idle.pop());
+        match conn {
+            Some(conn) => {
+                let client = self.client.clone();
+                Box::new(
+                    redis::cmd("PING")
+                        .query_async::<_, String>(conn)
+                        .map(|(conn, _)| conn)
+                        .or_else(move |_| client.get_async_connection()),
+                )
+            }
+            None => self.client.get_async_connection(),
+        }
+    }
+
+    // Returns the connection after the operation has succeeded.
+    fn put(&self, conn: Connection) {
+        if let Ok(mut idle) = self.idle.lock() {
+            if idle.len() < self.max_idle {
+                idle.push(conn);
+            }
+        }
+    }
+}
+
+/// The implementor of `SessionStore` which uses Redis.
 #[derive(Debug)]
-struct RedisSessionConfig {
+pub struct RedisStore {
+    pool: Arc<Pool>,
     key_prefix: String,
-    cookie_name: Cow<'static, str>,
-    timeout: Option<Duration>,
+    invalidation_channel: Option<String>,
+    timeouts: Timeouts,
 }
 
-impl RedisSessionConfig {
+impl RedisStore {
+    /// Create a new `RedisStore` from the specified Redis client.
+    pub fn new(client: Client) -> RedisStore {
+        RedisStore {
+            pool: Arc::new(Pool::new(client)),
+            key_prefix: "finchers-sesssion".into(),
+            invalidation_channel: None,
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    /// Set the maximum number of idle connections kept for reuse.
+    ///
+    /// The default value is 16.
+    pub fn max_idle_connections(mut self, max_idle: usize) -> RedisStore {
+        self.pool_mut().max_idle = max_idle;
+        self
+    }
+
+    fn pool_mut(&mut self) -> &mut Pool {
+        Arc::get_mut(&mut self.pool).expect("The instance has already shared.")
+    }
+
+    /// Set the timeout of establishing the connection to Redis.
+    pub fn connect_timeout(mut self, timeout: Duration) -> RedisStore {
+        self.timeouts.connect = Some(timeout);
+        self
+    }
+
+    /// Set the timeout of each command sent to Redis.
+    pub fn command_timeout(mut self, timeout: Duration) -> RedisStore {
+        self.timeouts.command = Some(timeout);
+        self
+    }
+
+    /// Set the timeout of the whole operation, including the connection
+    /// and all commands.
+    ///
+    /// This is the only timeout applied to the operations of
+    /// `UserSessionIndex` and `SessionAdmin`.
+    pub fn total_timeout(mut self, timeout: Duration) -> RedisStore {
+        self.timeouts.total = Some(timeout);
+        self
+    }
+
+    fn timed<F>(&self, future: F) -> Timed<F> {
+        Timed {
+            future,
+            deadlines: Deadlines::total_only(self.timeouts),
+        }
+    }
+
+    /// Set the name of channel to which the id of modified session is published.
+    ///
+    /// The published messages can be used to invalidate the values cached by
+    /// the other instances (see `listen_invalidation`).
+    pub fn invalidation_channel(mut self, channel: impl Into<String>) -> RedisStore {
+        self.invalidation_channel = Some(channel.into());
+        self
+    }
+
+    fn publish(&self, pipe: &mut redis::Pipeline, session_id: &Uuid) {
+        if let Some(ref channel) = self.invalidation_channel {
+            pipe.cmd("PUBLISH")
+                .arg(channel.as_str())
+                .arg(session_id.to_string());
+        }
+    }
+
     fn key_name(&self, id: &Uuid) -> String {
         format!("{}:{}", self.key_prefix, id)
     }
 
-    fn get_session_id(&self, input: &mut Input) -> Result<Option<Uuid>, Error> {
-        if let Some(cookie) = input.cookies()?.get(&self.cookie_name) {
-            let session_id: Uuid = cookie
-                .value()
-                .parse()
-                .map_err(finchers::error::bad_request)?;
-            return Ok(Some(session_id));
+    fn index_key_name(&self, user_id: &str) -> String {
+        index_key_name(&self.key_prefix, user_id)
+    }
+
+    fn list_user_sessions_impl(
+        &self,
+        user_id: &str,
+    ) -> impl Future<Item = (Connection, Vec<Uuid>), Error = RedisError> + Send {
+        let index_key = self.index_key_name(user_id);
+        let key_prefix = self.key_prefix.clone();
+        self.pool
+            .get()
+            .and_then({
+                let index_key = index_key.clone();
+                move |conn| {
+                    redis::cmd("ZRANGE")
+                        .arg(index_key)
+                        .arg(0)
+                        .arg(-1)
+                        .query_async::<_, Vec<String>>(conn)
+                }
+            }).and_then(move |(conn, members)| {
+                let session_ids: Vec<Uuid> =
+                    members.iter().filter_map(|m| m.parse().ok()).collect();
+                if session_ids.is_empty() {
+                    return Either::A(future::ok((conn, vec![])));
+                }
+
+                // The index may contain the ids of expired or deleted sessions,
+                // which are removed from the index here.
+                let keys: Vec<String> = session_ids
+                    .iter()
+                    .map(|id| format!("{}:{}", key_prefix, id))
+                    .collect();
+                Either::B(
+                    redis::cmd("MGET")
+                        .arg(keys)
+                        .query_async::<_, Vec<Option<String>>>(conn)
+                        .and_then(move |(conn, values)| {
+                            let (active, stale): (Vec<_>, Vec<_>) = session_ids
+                                .into_iter()
+                                .zip(values)
+                                .partition(|&(_, ref value)| value.is_some());
+                            let active: Vec<Uuid> = active.into_iter().map(|(id, _)| id).collect();
+                            if stale.is_empty() {
+                                return Either::A(future::ok((conn, active)));
+                            }
+                            let stale: Vec<String> =
+                                stale.into_iter().map(|(id, _)| id.to_string()).collect();
+                            Either::B(
+                                redis::cmd("ZREM")
+                                    .arg(index_key)
+                                    .arg(stale)
+                                    .query_async::<_, ()>(conn)
+                                    .map(move |(conn, ())| (conn, active)),
+                            )
+                        }),
+                )
+            })
+    }
+}
+
+impl SessionStore for RedisStore {
+    type LoadFuture = ReadFuture;
+    type SaveFuture = WriteFuture;
+    type DeleteFuture = WriteFuture;
+
+    fn load(&self, session_id: &Uuid) -> Self::LoadFuture {
+        ReadFuture::connecting(&self.pool, self.key_name(session_id), self.timeouts)
+    }
+
+    fn save(&self, session_id: &Uuid, value: String, ttl: Option<Duration>) -> Self::SaveFuture {
+        let redis_key = self.key_name(session_id);
+        let user_id = session_user_id(&value);
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        if let Some(ttl) = ttl {
+            pipe.cmd("SETEX").arg(redis_key).arg(ttl.as_secs()).arg(value);
+        } else {
+            pipe.cmd("SET").arg(redis_key).arg(value);
+        }
+
+        if let Some(user_id) = user_id {
+            // The index is a sorted set whose scores are the time when each
+            // session was associated with the user, which is not updated
+            // by the subsequent saves.
+            let index_key = self.index_key_name(&user_id);
+            pipe.cmd("ZADD")
+                .arg(&index_key)
+                .arg("NX")
+                .arg(unix_time_millis())
+                .arg(session_id.to_string());
+            // The index outlives all sessions in it, since each session
+            // expires earlier than the index at the time it is saved.
+            if let Some(ttl) = ttl {
+                pipe.cmd("EXPIRE").arg(&index_key).arg(ttl.as_secs());
+            }
         }
-        Ok(None)
+
+        self.publish(&mut pipe, session_id);
+        WriteFuture::connecting(&self.pool, pipe, None, self.timeouts)
+    }
+
+    fn delete(&self, session_id: &Uuid) -> Self::DeleteFuture {
+        let redis_key = self.key_name(session_id);
+        let mut pipe = redis::pipe();
+        pipe.atomic().cmd("DEL").arg(&redis_key);
+        self.publish(&mut pipe, session_id);
+
+        let lookup = Lookup {
+            redis_key,
+            key_prefix: self.key_prefix.clone(),
+            session_id: *session_id,
+        };
+        WriteFuture::connecting(&self.pool, pipe, Some(lookup), self.timeouts)
     }
 }
 
-/// The instance of `SessionBackend` which uses Redis.
-#[derive(Debug, Clone)]
-pub struct RedisBackend {
-    client: Client,
-    config: Arc<RedisSessionConfig>,
+impl UserSessionIndex for RedisStore {
+    type ListFuture = Box<dyn Future<Item = Vec<Uuid>, Error = Error> + Send>;
+    type RevokeFuture = Box<dyn Future<Item = Vec<Uuid>, Error = Error> + Send>;
+
+    fn list_user_sessions(&self, user_id: &str) -> Self::ListFuture {
+        let pool = self.pool.clone();
+        let future = self
+            .list_user_sessions_impl(user_id)
+            .map(move |(conn, session_ids)| {
+                pool.put(conn);
+                session_ids
+            }).map_err(SessionError::storage);
+        Box::new(self.timed(future))
+    }
+
+    fn revoke_user_sessions(&self, user_id: &str) -> Self::RevokeFuture {
+        let index_key = self.index_key_name(user_id);
+        let key_prefix = self.key_prefix.clone();
+        let channel = self.invalidation_channel.clone();
+        let pool = self.pool.clone();
+        let future = self
+            .pool
+            .get()
+            .map_err(SessionError::storage)
+            .and_then(move |conn| {
+                future::loop_fn((conn, 0), move |(conn, attempts)| {
+                    revoke_attempt(conn, &index_key, &key_prefix, channel.as_ref())
+                        .map_err(SessionError::storage)
+                        .and_then(move |(conn, revoked)| match revoked {
+                            Some(session_ids) => Ok(Loop::Break((conn, session_ids))),
+                            None if attempts + 1 < MAX_REVOKE_ATTEMPTS => {
+                                Ok(Loop::Continue((conn, attempts + 1)))
+                            }
+                            None => Err(SessionError::Conflict.into()),
+                        })
+                })
+            }).map(move |(conn, session_ids)| {
+                pool.put(conn);
+                session_ids
+            });
+        Box::new(self.timed(future))
+    }
 }
 
-impl RedisBackend {
-    /// Create a new `RedisSessionBackend` from the specified Redis client.
-    pub fn new(client: Client) -> RedisBackend {
-        RedisBackend {
-            client,
-            config: Arc::new(RedisSessionConfig {
-                key_prefix: "finchers-sesssion".into(),
-                cookie_name: "session-id".into(),
-                timeout: None,
-            }),
+// The number of attempts of the transaction in `revoke_user_sessions`, which
+// is retried when the index is modified concurrently.
+const MAX_REVOKE_ATTEMPTS: usize = 5;
+
+// Deletes all sessions in the index and the index itself in a transaction.
+//
+// The index is watched so that the transaction is discarded (and `None` is
+// returned) if a session is added to the index concurrently, which would
+// otherwise survive the revocation.
+fn revoke_attempt(
+    conn: Connection,
+    index_key: &str,
+    key_prefix: &str,
+    channel: Option<&String>,
+) -> impl Future<Item = (Connection, Option<Vec<Uuid>>), Error = RedisError> + Send {
+    let index_key = index_key.to_owned();
+    let key_prefix = key_prefix.to_owned();
+    let channel = channel.cloned();
+    redis::cmd("WATCH")
+        .arg(&index_key)
+        .query_async::<_, ()>(conn)
+        .and_then({
+            let index_key = index_key.clone();
+            move |(conn, ())| {
+                redis::cmd("ZRANGE")
+                    .arg(index_key)
+                    .arg(0)
+                    .arg(-1)
+                    .query_async::<_, Vec<String>>(conn)
+            }
+        }).and_then(move |(conn, members)| {
+            let session_ids: Vec<Uuid> = members.iter().filter_map(|m| m.parse().ok()).collect();
+            if session_ids.is_empty() {
+                return Either::A(
+                    redis::cmd("UNWATCH")
+                        .query_async::<_, ()>(conn)
+                        .map(|(conn, ())| (conn, Some(vec![]))),
+                );
+            }
+
+            let keys: Vec<String> = session_ids
+                .iter()
+                .map(|id| format!("{}:{}", key_prefix, id))
+                .collect();
+            Either::B(
+                redis::cmd("MGET")
+                    .arg(&keys)
+                    .query_async::<_, Vec<Option<String>>>(conn)
+                    .and_then(move |(conn, values)| {
+                        // The expired sessions are removed from the index, but not reported.
+                        let active: Vec<Uuid> = session_ids
+                            .into_iter()
+                            .zip(values)
+                            .filter(|&(_, ref value)| value.is_some())
+                            .map(|(id, _)| id)
+                            .collect();
+
+                        let mut pipe = redis::pipe();
+                        pipe.atomic()
+                            .cmd("DEL")
+                            .arg(keys)
+                            .arg(index_key)
+                            .ignore();
+                        if let Some(channel) = channel {
+                            if !active.is_empty() {
+                                let message = active
+                                    .iter()
+                                    .map(|id| id.to_string())
+                                    .collect::<Vec<_>>()
+                                    .join(" ");
+                                pipe.cmd("PUBLISH").arg(channel).arg(message).ignore();
+                            }
+                        }
+                        pipe.query_async::<_, Option<()>>(conn)
+                            .map(move |(conn, committed)| (conn, committed.map(|()| active)))
+                    }),
+            )
+        })
+}
+
+impl SessionAdmin for RedisStore {
+    type ListFuture = Box<dyn Future<Item = SessionPage, Error = Error> + Send>;
+    type CountFuture = Box<dyn Future<Item = usize, Error = Error> + Send>;
+
+    fn list_sessions(&self, cursor: Option<&str>, count: usize) -> Self::ListFuture {
+        let cursor: u64 = match cursor {
+            Some(cursor) => match cursor.parse() {
+                Ok(cursor) => cursor,
+                Err(..) => {
+                    let err = SessionError::InvalidCursor(cursor.to_owned());
+                    return Box::new(future::err(err.into()));
+                }
+            },
+            None => 0,
+        };
+        let cmd = scan_cmd(&self.key_prefix, cursor, count);
+        let key_prefix = self.key_prefix.clone();
+        let pool = self.pool.clone();
+        let future = self
+            .pool
+            .get()
+            .and_then(move |conn| cmd.query_async::<_, (u64, Vec<String>)>(conn))
+            .map(move |(conn, (cursor, keys))| {
+                pool.put(conn);
+                SessionPage {
+                    session_ids: keys
+                        .iter()
+                        .filter_map(|key| session_id_of_key(&key_prefix, key))
+                        .collect(),
+                    next_cursor: if cursor == 0 {
+                        None
+                    } else {
+                        Some(cursor.to_string())
+                    },
+                }
+            }).map_err(SessionError::storage);
+        Box::new(self.timed(future))
+    }
+
+    fn count_sessions(&self) -> Self::CountFuture {
+        let key_prefix = self.key_prefix.clone();
+        let pool = self.pool.clone();
+        let future = self
+            .pool
+            .get()
+            .and_then(move |conn| {
+                future::loop_fn((conn, 0, 0), move |(conn, cursor, total)| {
+                    let key_prefix = key_prefix.clone();
+                    scan_cmd(&key_prefix, cursor, 1000)
+                        .query_async::<_, (u64, Vec<String>)>(conn)
+                        .map(move |(conn, (cursor, keys))| {
+                            let total = total + keys
+                                .iter()
+                                .filter(|key| session_id_of_key(&key_prefix, key).is_some())
+                                .count();
+                            if cursor == 0 {
+                                Loop::Break((conn, total))
+                            } else {
+                                Loop::Continue((conn, cursor, total))
+                            }
+                        })
+                })
+            }).map(move |(conn, total)| {
+                pool.put(conn);
+                total
+            }).map_err(SessionError::storage);
+        Box::new(self.timed(future))
+    }
+}
+
+fn scan_cmd(key_prefix: &str, cursor: u64, count: usize) -> redis::Cmd {
+    let mut cmd = redis::cmd("SCAN");
+    cmd.arg(cursor)
+        .arg("MATCH")
+        .arg(format!("{}:*", key_prefix))
+        .arg("COUNT")
+        .arg(count);
+    cmd
+}
+
+fn index_key_name(key_prefix: &str, user_id: &str) -> String {
+    format!("{}:user:{}", key_prefix, user_id)
+}
+
+fn session_id_of_key(key_prefix: &str, key: &str) -> Option<Uuid> {
+    // The keys of user indices are skipped since the suffix is not a UUID.
+    key.get(key_prefix.len() + 1..)?.parse().ok()
+}
+
+fn unix_time_millis() -> u64 {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0));
+    elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())
+}
+
+/// Spawns a thread which subscribes the specified channel and invalidates
+/// the cached values whose session ids are published to the channel.
+///
+/// Each message contains the session ids separated by whitespaces.
+///
+/// The channel should be the same as the one set by `RedisStore::invalidation_channel`.
+///
+/// This function returns an error if the first subscription fails. After that,
+/// the lost connection is re-established with exponential backoff (up to 30 seconds),
+/// and all cached values are invalidated after reconnecting since the messages
+/// published in the meantime are missed. The failures are reported via `log`.
+pub fn listen_invalidation(
+    client: &Client,
+    channel: impl Into<String>,
+    invalidator: CacheInvalidator,
+) -> redis::RedisResult<thread::JoinHandle<()>> {
+    let channel = channel.into();
+    let mut pubsub = subscribe(client, &channel)?;
+    let client = client.clone();
+    let handle = thread::Builder::new()
+        .name("finchers-session-invalidation".into())
+        .spawn(move || loop {
+            let err = match receive_invalidation(&mut pubsub, &invalidator) {
+                Ok(()) => return,
+                Err(err) => err,
+            };
+            warn!("lost the subscription of channel {:?}: {}", channel, err);
+
+            pubsub = resubscribe(&client, &channel);
+            if let Err(err) = invalidator.invalidate_all() {
+                error!("failed to invalidate the cached session values: {}", err);
+                return;
+            }
+        })?;
+    Ok(handle)
+}
+
+fn subscribe(client: &Client, channel: &str) -> redis::RedisResult<redis::PubSub> {
+    let mut pubsub = client.get_pubsub()?;
+    pubsub.subscribe(channel)?;
+    Ok(pubsub)
+}
+
+fn resubscribe(client: &Client, channel: &str) -> redis::PubSub {
+    let mut backoff = Duration::from_millis(100);
+    loop {
+        thread::sleep(backoff);
+        match subscribe(client, channel) {
+            Ok(pubsub) => return pubsub,
+            Err(err) => warn!("failed to subscribe channel {:?}: {}", channel, err),
+        }
+        backoff = cmp::min(backoff * 2, Duration::from_secs(30));
+    }
+}
+
+// Returns an error if the connection is lost, or `Ok(())` if the cached values
+// cannot be invalidated any more.
+fn receive_invalidation(
+    pubsub: &mut redis::PubSub,
+    invalidator: &CacheInvalidator,
+) -> redis::RedisResult<()> {
+    loop {
+        let payload: String = match pubsub.get_message()?.get_payload() {
+            Ok(payload) => payload,
+            Err(err) => {
+                warn!("ignored the invalid invalidation message: {}", err);
+                continue;
+            }
+        };
+        for session_id in payload.split_whitespace() {
+            let session_id: Uuid = match session_id.parse() {
+                Ok(session_id) => session_id,
+                Err(..) => {
+                    warn!("ignored the invalid session id {:?}", session_id);
+                    continue;
+                }
+            };
+            if let Err(err) = invalidator.invalidate(&session_id) {
+                error!("failed to invalidate the cached session value: {}", err);
+                return Ok(());
+            }
         }
     }
+}
+
+/// The instance of `SessionBackend` which uses Redis.
+pub type RedisBackend = StoreBackend<RedisStore>;
 
-    fn config_mut(&mut self) -> &mut RedisSessionConfig {
-        Arc::get_mut(&mut self.config).expect("The instance has already shared.")
+/// The type of raw session created by `RedisBackend`.
+pub type RedisSession = StoreSession<RedisStore>;
+
+impl StoreBackend<RedisStore> {
+    /// Create a new `RedisSessionBackend` from the specified Redis client.
+    pub fn new(client: Client) -> RedisBackend {
+        StoreBackend::from_store(RedisStore::new(client))
     }
 
     /// Set the prefix string used in the key name when stores the session value
@@ -115,37 +720,112 @@ impl RedisBackend {
     ///
     /// The default value is "finchers-session"
     pub fn key_prefix(mut self, prefix: impl Into<String>) -> RedisBackend {
-        self.config_mut().key_prefix = prefix.into();
+        self.store_mut().key_prefix = prefix.into();
         self
     }
 
-    /// Set the name of Cookie entry which stores the session id.
+    /// Set the maximum number of idle connections kept for reuse.
     ///
-    /// The default value is "session-id"
-    pub fn cookie_name(mut self, name: impl Into<Cow<'static, str>>) -> RedisBackend {
-        self.config_mut().cookie_name = name.into();
+    /// The default value is 16.
+    pub fn max_idle_connections(mut self, max_idle: usize) -> RedisBackend {
+        self.store_mut().pool_mut().max_idle = max_idle;
         self
     }
 
-    /// Set the timeout of session value.
-    pub fn timeout(mut self, timeout: Duration) -> RedisBackend {
-        self.config_mut().timeout = Some(timeout);
+    /// Set the timeout of establishing the connection to Redis.
+    ///
+    /// By default, there is no timeout.
+    pub fn connect_timeout(mut self, timeout: Duration) -> RedisBackend {
+        self.store_mut().timeouts.connect = Some(timeout);
+        self
+    }
+
+    /// Set the timeout of each command sent to Redis.
+    ///
+    /// By default, there is no timeout.
+    pub fn command_timeout(mut self, timeout: Duration) -> RedisBackend {
+        self.store_mut().timeouts.command = Some(timeout);
+        self
+    }
+
+    /// Set the timeout of the whole operation, including the connection
+    /// and all commands.
+    ///
+    /// By default, there is no timeout.
+    pub fn total_timeout(mut self, timeout: Duration) -> RedisBackend {
+        self.store_mut().timeouts.total = Some(timeout);
         self
     }
 }
 
-impl<'a> Endpoint<'a> for RedisBackend {
-    type Output = (Session<RedisSession>,);
-    type Future = ReadFuture;
+#[derive(Debug, Clone, Copy, Default)]
+struct Timeouts {
+    connect: Option<Duration>,
+    command: Option<Duration>,
+    total: Option<Duration>,
+}
+
+// The timers of an operation in progress.
+struct Deadlines {
+    command_timeout: Option<Duration>,
+    total: Option<Delay>,
+    step: Option<Delay>,
+}
 
-    fn apply(&self, cx: &mut ApplyContext<'_>) -> ApplyResult<Self::Future> {
-        match self.config.get_session_id(cx.input()) {
-            Ok(session_id) => Ok(ReadFuture::connecting(
-                &self.client,
-                &self.config,
-                session_id,
-            )),
-            Err(err) => Ok(ReadFuture::failed(err)),
+impl Deadlines {
+    fn start(timeouts: Timeouts) -> Deadlines {
+        Deadlines {
+            command_timeout: timeouts.command,
+            total: timeouts.total.map(delay_for),
+            step: timeouts.connect.map(delay_for),
+        }
+    }
+
+    fn total_only(timeouts: Timeouts) -> Deadlines {
+        Deadlines {
+            command_timeout: None,
+            total: timeouts.total.map(delay_for),
+            step: None,
+        }
+    }
+
+    // Restarts the timer of the current step for the next command.
+    fn next_command(&mut self) {
+        self.step = self.command_timeout.map(delay_for);
+    }
+
+    // Called when the underlying future is not ready.
+    fn poll_elapsed<T>(&mut self) -> Poll<T, Error> {
+        for delay in self.total.iter_mut().chain(self.step.iter_mut()) {
+            if delay.poll().map_err(SessionError::storage)?.is_ready() {
+                return Err(SessionError::Timeout.into());
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+fn delay_for(timeout: Duration) -> Delay {
+    Delay::new(Instant::now() + timeout)
+}
+
+// Applies the total timeout to the operations composed by combinators.
+struct Timed<F> {
+    future: F,
+    deadlines: Deadlines,
+}
+
+impl<F> Future for Timed<F>
+where
+    F: Future<Error = Error>,
+{
+    type Item = F::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.future.poll()? {
+            Async::Ready(item) => Ok(Async::Ready(item)),
+            Async::NotReady => self.deadlines.poll_elapsed(),
         }
     }
 }
@@ -154,234 +834,114 @@ impl<'a> Endpoint<'a> for RedisBackend {
 #[allow(missing_debug_implementations)]
 pub struct ReadFuture {
     state: ReadFutureState,
+    deadlines: Deadlines,
+    pool: Arc<Pool>,
 }
 
 #[allow(missing_debug_implementations)]
 enum ReadFutureState {
-    Failed(Option<Error>),
     Connecting {
         future: RedisFuture<Connection>,
-        config: Arc<RedisSessionConfig>,
-        session_id: Option<Uuid>,
-    },
-    Fetch {
-        future: RedisFuture<(Connection, Option<String>)>,
-        config: Arc<RedisSessionConfig>,
-        session_id: Uuid,
+        redis_key: String,
     },
+    Fetch(RedisFuture<(Connection, Option<String>)>),
     Done,
 }
 
 impl ReadFuture {
-    fn failed(err: Error) -> ReadFuture {
-        ReadFuture {
-            state: ReadFutureState::Failed(Some(err)),
-        }
-    }
-
-    fn connecting(
-        client: &Client,
-        config: &Arc<RedisSessionConfig>,
-        session_id: Option<Uuid>,
-    ) -> ReadFuture {
+    fn connecting(pool: &Arc<Pool>, redis_key: String, timeouts: Timeouts) -> ReadFuture {
         ReadFuture {
             state: ReadFutureState::Connecting {
-                future: client.get_async_connection(),
-                config: config.clone(),
-                session_id,
+                future: pool.get(),
+                redis_key,
             },
+            deadlines: Deadlines::start(timeouts),
+            pool: pool.clone(),
         }
     }
 }
 
 impl Future for ReadFuture {
-    type Item = (Session<RedisSession>,);
+    type Item = Option<String>;
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         use self::ReadFutureState::*;
         loop {
-            let (conn, value) = match self.state {
-                Failed(ref mut err) => {
-                    return Err(err.take().expect("This future has alread polled."))
-                }
+            let conn = match self.state {
                 Connecting { ref mut future, .. } => {
-                    let conn = try_ready!(future.poll().map_err(finchers::error::fail));
-                    (Some(conn), None)
-                }
-                Fetch { ref mut future, .. } => {
-                    let (conn, value) = try_ready!(future.poll().map_err(finchers::error::fail));
-                    (Some(conn), value)
+                    match future.poll().map_err(SessionError::storage)? {
+                        Async::Ready(conn) => conn,
+                        Async::NotReady => return self.deadlines.poll_elapsed(),
+                    }
                 }
+                Fetch(ref mut future) => match future.poll().map_err(SessionError::storage)? {
+                    Async::Ready((conn, value)) => {
+                        self.pool.put(conn);
+                        return Ok(Async::Ready(value));
+                    }
+                    Async::NotReady => return self.deadlines.poll_elapsed(),
+                },
                 Done => panic!("unexpected state"),
             };
 
-            match (mem::replace(&mut self.state, Done), conn, value) {
-                (
-                    Connecting {
-                        config,
-                        session_id: Some(session_id),
-                        ..
-                    },
-                    Some(conn),
-                    None,
-                ) => {
-                    self.state = Fetch {
-                        future: redis::cmd("GET")
-                            .arg(config.key_name(&session_id))
-                            .query_async(conn),
-                        config,
-                        session_id,
-                    };
+            match mem::replace(&mut self.state, Done) {
+                Connecting { redis_key, .. } => {
+                    self.deadlines.next_command();
+                    self.state = Fetch(redis::cmd("GET").arg(redis_key).query_async(conn));
                 }
-
-                (
-                    Fetch {
-                        config, session_id, ..
-                    },
-                    Some(conn),
-                    Some(value),
-                ) => {
-                    return Ok(Async::Ready((Session::new(RedisSession {
-                        conn,
-                        config,
-                        session_id: Some(session_id),
-                        value: Some(value),
-                    }),)))
-                }
-
-                (
-                    Connecting {
-                        config,
-                        session_id: None,
-                        ..
-                    },
-                    Some(conn),
-                    None,
-                )
-                | (Fetch { config, .. }, Some(conn), None) => {
-                    return Ok(Async::Ready((Session::new(RedisSession {
-                        conn,
-                        config,
-                        session_id: None,
-                        value: None,
-                    }),)));
-                }
-
                 _ => unreachable!("unexpected condition"),
             }
         }
     }
 }
 
-// ==== RedisSession ====
-
-#[allow(missing_docs)]
-pub struct RedisSession {
-    conn: Connection,
-    config: Arc<RedisSessionConfig>,
-    session_id: Option<Uuid>,
-    value: Option<String>,
-}
-
-impl fmt::Debug for RedisSession {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("RedisSession")
-            .field("config", &self.config)
-            .field("session_id", &self.session_id)
-            .field("value", &self.value)
-            .finish()
-    }
-}
-
-impl RawSession for RedisSession {
-    type WriteFuture = WriteFuture;
-
-    fn get(&self) -> Option<&str> {
-        self.value.as_ref().map(|s| s.as_ref())
-    }
-
-    fn set(&mut self, value: String) {
-        self.value = Some(value);
-    }
-
-    fn remove(&mut self) {
-        self.value = None;
-    }
-
-    fn write(self, input: &mut Input) -> Self::WriteFuture {
-        let Self {
-            conn,
-            config,
-            session_id,
-            value,
-        } = self;
-
-        match (session_id, value) {
-            (Some(session_id), None) => {
-                match input.cookies() {
-                    Ok(jar) => jar.remove(Cookie::named(config.cookie_name.clone())),
-                    Err(err) => return WriteFuture::failed(err),
-                }
-                let redis_key = config.key_name(&session_id);
-                WriteFuture::cmd(conn, redis::cmd("DEL").arg(redis_key))
-            }
-            (session_id, Some(value)) => {
-                let session_id = session_id.unwrap_or_else(Uuid::new_v4);
-                match input.cookies() {
-                    Ok(jar) => jar.add(Cookie::new(
-                        config.cookie_name.clone(),
-                        session_id.to_string(),
-                    )),
-                    Err(err) => return WriteFuture::failed(err),
-                }
-                let redis_key = config.key_name(&session_id);
-
-                if let Some(timeout) = config.timeout {
-                    WriteFuture::cmd(
-                        conn,
-                        redis::cmd("SETEX")
-                            .arg(redis_key)
-                            .arg(timeout.as_secs())
-                            .arg(value),
-                    )
-                } else {
-                    WriteFuture::cmd(conn, redis::cmd("SET").arg(redis_key).arg(value))
-                }
-            }
-            (None, None) => WriteFuture::no_op(),
-        }
-    }
-}
-
 #[doc(hidden)]
 #[allow(missing_debug_implementations)]
 pub struct WriteFuture {
     state: WriteFutureState,
+    deadlines: Deadlines,
+    pool: Arc<Pool>,
+}
+
+// The session value fetched before the deletion, in order to remove
+// the session id from the index of the user.
+struct Lookup {
+    redis_key: String,
+    key_prefix: String,
+    session_id: Uuid,
 }
 
 enum WriteFutureState {
-    Noop,
-    Failed(Option<Error>),
-    Cmd(RedisFuture<(Connection, ())>),
+    Connecting {
+        future: RedisFuture<Connection>,
+        pipe: redis::Pipeline,
+        lookup: Option<Lookup>,
+    },
+    Fetching {
+        future: RedisFuture<(Connection, Option<String>)>,
+        pipe: redis::Pipeline,
+        lookup: Lookup,
+    },
+    Committing(RedisFuture<(Connection, ())>),
+    Done,
 }
 
 impl WriteFuture {
-    fn no_op() -> WriteFuture {
-        WriteFuture {
-            state: WriteFutureState::Noop,
-        }
-    }
-
-    fn failed(err: Error) -> WriteFuture {
-        WriteFuture {
-            state: WriteFutureState::Failed(Some(err)),
-        }
-    }
-
-    fn cmd(conn: Connection, cmd: &redis::Cmd) -> WriteFuture {
+    fn connecting(
+        pool: &Arc<Pool>,
+        pipe: redis::Pipeline,
+        lookup: Option<Lookup>,
+        timeouts: Timeouts,
+    ) -> WriteFuture {
         WriteFuture {
-            state: WriteFutureState::Cmd(cmd.query_async::<_, ()>(conn)),
+            state: WriteFutureState::Connecting {
+                future: pool.get(),
+                pipe,
+                lookup,
+            },
+            deadlines: Deadlines::start(timeouts),
+            pool: pool.clone(),
         }
     }
 }
@@ -390,16 +950,56 @@ impl Future for WriteFuture {
     type Item = ();
     type Error = Error;
 
-    #[inline]
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         use self::WriteFutureState::*;
-        match self.state {
-            Noop => Ok(().into()),
-            Failed(ref mut err) => Err(err.take().expect("The future has already polled.")),
-            Cmd(ref mut future) => {
-                let (_conn, ()) = try_ready!(future.poll().map_err(finchers::error::fail));
-                Ok(Async::Ready(()))
-            }
+        loop {
+            let (conn, value) = match self.state {
+                Connecting { ref mut future, .. } => {
+                    match future.poll().map_err(SessionError::storage)? {
+                        Async::Ready(conn) => (conn, None),
+                        Async::NotReady => return self.deadlines.poll_elapsed(),
+                    }
+                }
+                Fetching { ref mut future, .. } => {
+                    match future.poll().map_err(SessionError::storage)? {
+                        Async::Ready((conn, value)) => (conn, value),
+                        Async::NotReady => return self.deadlines.poll_elapsed(),
+                    }
+                }
+                Committing(ref mut future) => match future.poll().map_err(SessionError::storage)? {
+                    Async::Ready((conn, ())) => {
+                        self.pool.put(conn);
+                        return Ok(Async::Ready(()));
+                    }
+                    Async::NotReady => return self.deadlines.poll_elapsed(),
+                },
+                Done => panic!("unexpected state"),
+            };
+
+            self.deadlines.next_command();
+            self.state = match mem::replace(&mut self.state, Done) {
+                Connecting {
+                    pipe,
+                    lookup: Some(lookup),
+                    ..
+                } => Fetching {
+                    future: redis::cmd("GET")
+                        .arg(lookup.redis_key.as_str())
+                        .query_async(conn),
+                    pipe,
+                    lookup,
+                },
+                Connecting { pipe, .. } => Committing(pipe.query_async(conn)),
+                Fetching { mut pipe, lookup, .. } => {
+                    if let Some(user_id) = value.and_then(|value| session_user_id(&value)) {
+                        pipe.cmd("ZREM")
+                            .arg(index_key_name(&lookup.key_prefix, &user_id))
+                            .arg(lookup.session_id.to_string());
+                    }
+                    Committing(pipe.query_async(conn))
+                }
+                _ => unreachable!("unexpected condition"),
+            };
         }
     }
 }
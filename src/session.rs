@@ -4,6 +4,15 @@ use finchers::input::Input;
 
 use futures::{Future, IntoFuture, Poll};
 
+use std::cell::RefCell;
+use std::mem;
+
+use uuid::Uuid;
+
+use csrf::CsrfToken;
+use envelope::{self, Envelope};
+use flash::{Flash, FlashLevel};
+
 /// The trait representing the backend to manage session value.
 #[allow(missing_docs)]
 pub trait RawSession {
@@ -13,6 +22,26 @@ pub trait RawSession {
     fn set(&mut self, value: String);
     fn remove(&mut self);
     fn write(self, input: &mut Input) -> Self::WriteFuture;
+
+    /// Annotates to issue a new session id when writing the session value,
+    /// and to discard the data associated with the current session id.
+    ///
+    /// The default implementation does nothing, which is suitable for the
+    /// backends without session ids.
+    fn regenerate(&mut self) {}
+
+    /// Returns `true` if the session value cannot be forged by the client.
+    ///
+    /// The authenticated user is stored together with the session value, so
+    /// `Session::login` and `Session::user_id` are available only if this method
+    /// returns `true`. The client-side backends which store the value without
+    /// signing or encryption (e.g. `cookie::plain()`) must return `false`.
+    ///
+    /// The default implementation returns `true`, which is suitable for the
+    /// server-side backends.
+    fn is_trusted(&self) -> bool {
+        true
+    }
 }
 
 /// A struct which manages the session value per request.
@@ -20,6 +49,8 @@ pub trait RawSession {
 #[must_use = "The value must be convert into a Future to finish the session handling."]
 pub struct Session<S: RawSession> {
     raw: S,
+    // The metadata decoded from the raw session value (lazily loaded).
+    envelope: RefCell<Option<Envelope>>,
 }
 
 impl<S> Session<S>
@@ -28,22 +59,156 @@ where
 {
     #[allow(missing_docs)]
     pub fn new(raw: S) -> Session<S> {
-        Session { raw }
+        Session {
+            raw,
+            envelope: RefCell::new(None),
+        }
     }
 
     /// Get the session value if available.
     pub fn get(&self) -> Option<&str> {
-        self.raw.get()
+        let (envelope, value) = envelope::decode(self.raw.get());
+        self.envelope.borrow_mut().get_or_insert(envelope);
+        value
     }
 
     /// Set the session value.
+    ///
+    /// The metadata (e.g. flash messages) is stored together with the value,
+    /// so the raw value is read once via `RawSession::get` before the first
+    /// modification in order to keep it. This is cheap since the backends load
+    /// the raw value before the session is created.
     pub fn set(&mut self, value: impl Into<String>) {
-        self.raw.set(value.into());
+        let value = value.into();
+        self.store(Some(value));
     }
 
     /// Annotates to remove session value to the backend.
+    ///
+    /// The pending flash messages are kept in the backend.
     pub fn remove(&mut self) {
-        self.raw.remove();
+        self.store(None);
+    }
+
+    /// Adds a flash message, which will be available until it is taken
+    /// by `take_flashes`.
+    pub fn flash(&mut self, level: FlashLevel, message: impl Into<String>) {
+        let value = self.value();
+        self.envelope_mut().flashes.push(Flash::new(level, message));
+        self.store(value);
+    }
+
+    /// Takes the pending flash messages and removes them from the backend.
+    ///
+    /// The raw session value is not modified if there are no pending messages.
+    pub fn take_flashes(&mut self) -> Vec<Flash> {
+        if self.envelope_mut().flashes.is_empty() {
+            return vec![];
+        }
+        let flashes = mem::replace(&mut self.envelope_mut().flashes, vec![]);
+        let value = self.value();
+        self.store(value);
+        flashes
+    }
+
+    /// Returns the id of the user authenticated in this session, if available.
+    ///
+    /// This method always returns `None` if the backend is not trusted
+    /// (see `RawSession::is_trusted`), since the client could forge the user.
+    pub fn user_id(&self) -> Option<String> {
+        if !self.raw.is_trusted() {
+            return None;
+        }
+        let (envelope, _) = envelope::decode(self.raw.get());
+        let user_id = envelope.user_id.clone();
+        self.envelope.borrow_mut().get_or_insert(envelope);
+        user_id
+    }
+
+    /// Returns the CSRF token of this session, generating a new one if absent.
+    ///
+    /// The token is stored together with the session value, so the session
+    /// has to be written after a new token is generated. See the module `csrf`
+    /// for the endpoints which validate the token.
+    pub fn csrf_token(&mut self) -> CsrfToken {
+        if let Some(token) = self.stored_csrf_token() {
+            return CsrfToken::new(token);
+        }
+        let token = Uuid::new_v4().to_simple().to_string();
+        let value = self.value();
+        self.envelope_mut().csrf_token = Some(token.clone());
+        self.store(value);
+        CsrfToken::new(token)
+    }
+
+    pub(crate) fn stored_csrf_token(&self) -> Option<String> {
+        let (envelope, _) = envelope::decode(self.raw.get());
+        let csrf_token = envelope.csrf_token.clone();
+        self.envelope.borrow_mut().get_or_insert(envelope);
+        csrf_token
+    }
+
+    /// Marks the user with the specified id as authenticated in this session.
+    ///
+    /// The session id is regenerated in order to prevent session fixation,
+    /// and the CSRF token is discarded so that a new one is generated for
+    /// the authenticated session.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the backend is not trusted (see `RawSession::is_trusted`),
+    /// e.g. `cookie::plain()`, whose values (including the authenticated user) can
+    /// be modified by the client. Use a server-side backend, or a signed, private
+    /// or PASETO Cookie backend (or JWT) instead.
+    pub fn login(&mut self, user_id: impl Into<String>) {
+        assert!(
+            self.raw.is_trusted(),
+            "Session::login requires a backend whose session values cannot be forged by the client."
+        );
+        let value = self.value();
+        {
+            let envelope = self.envelope_mut();
+            envelope.user_id = Some(user_id.into());
+            envelope.csrf_token = None;
+        }
+        self.store(value);
+        self.raw.regenerate();
+    }
+
+    /// Discards all data in this session, including the session value,
+    /// the authenticated user and the CSRF token, except the pending flash messages.
+    ///
+    /// The data stored in the server-side backends is removed and the
+    /// session id is cleared from the client. If there are pending flash
+    /// messages (e.g. the ones added before or after calling this method),
+    /// they are kept in a session with a new session id.
+    pub fn logout(&mut self) {
+        let flashes = mem::replace(&mut self.envelope_mut().flashes, vec![]);
+        *self.envelope.get_mut() = Some(Envelope {
+            flashes,
+            ..Envelope::default()
+        });
+        self.raw.regenerate();
+        self.store(None);
+    }
+
+    fn value(&self) -> Option<String> {
+        envelope::decode(self.raw.get()).1.map(ToOwned::to_owned)
+    }
+
+    fn envelope_mut(&mut self) -> &mut Envelope {
+        let raw = &self.raw;
+        self.envelope
+            .get_mut()
+            .get_or_insert_with(|| envelope::decode(raw.get()).0)
+    }
+
+    fn store(&mut self, value: Option<String>) {
+        let raw = envelope::encode(self.envelope_mut(), value.as_ref().map(|s| s.as_str()));
+        match raw {
+            Some(raw) => self.raw.set(raw),
+            None => self.raw.remove(),
+        }
     }
 
     #[allow(missing_docs)]
@@ -0,0 +1,1579 @@
+//! The generic session backend built on top of a key-value store.
+//!
+//! The server-side backends in this crate (in-memory and Redis) share the same
+//! logic: the session id is carried by a Cookie entry (or a header, see
+//! `IdTransport`) and the session value is stored in an external storage keyed
+//! by the id. `StoreBackend` implements this
+//! logic once and delegates the access to the storage to an implementor of
+//! `SessionStore`, so that a custom datastore can be used as a session backend
+//! by implementing only the three basic operations.
+//!
+//! # Example
+//!
+//! ```
+//! #[macro_use]
+//! extern crate finchers;
+//! extern crate finchers_session;
+//! extern crate futures;
+//! extern crate uuid;
+//!
+//! use finchers::error::Error;
+//! use finchers::prelude::*;
+//! use finchers_session::Session;
+//! use finchers_session::store::{SessionStore, StoreBackend, StoreSession};
+//! use futures::future;
+//! use std::time::Duration;
+//! use uuid::Uuid;
+//!
+//! #[derive(Debug)]
+//! struct MyStore {
+//!     // ...
+//! }
+//!
+//! impl SessionStore for MyStore {
+//!     type LoadFuture = future::FutureResult<Option<String>, Error>;
+//!     type SaveFuture = future::FutureResult<(), Error>;
+//!     type DeleteFuture = future::FutureResult<(), Error>;
+//!
+//!     fn load(&self, _session_id: &Uuid) -> Self::LoadFuture {
+//!         // ...
+//! #       future::ok(None)
+//!     }
+//!
+//!     fn save(
+//!         &self,
+//!         _session_id: &Uuid,
+//!         _value: String,
+//!         _ttl: Option<Duration>,
+//!     ) -> Self::SaveFuture {
+//!         // ...
+//! #       future::ok(())
+//!     }
+//!
+//!     fn delete(&self, _session_id: &Uuid) -> Self::DeleteFuture {
+//!         // ...
+//! #       future::ok(())
+//!     }
+//! }
+//!
+//! # fn main() {
+//! let backend = StoreBackend::from_store(MyStore {})
+//!     .cookie_name("sid");
+//!
+//! let endpoint = path!(@get /)
+//!     .and(backend)
+//!     .and_then(|session: Session<StoreSession<MyStore>>| {
+//!         session.with(|_session| {
+//!             // ...
+//! #           Ok("done")
+//!         })
+//!     });
+//! # drop(move || finchers::server::start(endpoint).serve("127.0.0.1:4000"));
+//! # }
+//! ```
+//!
+//! # Invalid session ids
+//!
+//! A session id sent by the client may be malformed (e.g. a garbage Cookie value)
+//! or unknown to the store (e.g. the session has been expired). By default, both
+//! of them are handled as if no session id is sent: the stale session id is
+//! removed from the client, and a fresh one is issued when a session value is stored.
+//! The request with a malformed session id can be rejected with `400 Bad Request`
+//! instead by `MalformedIdPolicy::Reject`, and such ids can be observed by
+//! `StoreBackend::on_invalid_id`.
+//!
+//! # Lazy loading
+//!
+//! `StoreBackend` loads the session value from the store for every matched request.
+//! The endpoint created by `StoreBackend::lazy` yields a `LazySession` instead,
+//! which accesses the store only when the handler calls `LazySession::load`
+//! (or `LazySession::with`). This avoids the round trip to the store in the
+//! handlers which consult the session only occasionally.
+//!
+//! ```
+//! #[macro_use]
+//! extern crate finchers;
+//! extern crate finchers_session;
+//! extern crate futures;
+//!
+//! use finchers::prelude::*;
+//! use finchers_session::in_memory::{InMemoryBackend, InMemoryStore};
+//! use finchers_session::store::LazySession;
+//! use futures::future::{self, Either};
+//!
+//! # fn main() {
+//! let backend = InMemoryBackend::default();
+//!
+//! let endpoint = path!(@get / String)
+//!     .and(backend.lazy())
+//!     .and_then(|page: String, session: LazySession<InMemoryStore>| {
+//!         if page == "private" {
+//!             Either::A(session.with(|session| {
+//!                 // ...
+//! #               Ok(session.get().is_some().to_string())
+//!             }))
+//!         } else {
+//!             // The store is never accessed.
+//!             Either::B(future::ok(page))
+//!         }
+//!     });
+//! # drop(move || finchers::server::start(endpoint).serve("127.0.0.1:4000"));
+//! # }
+//! ```
+
+extern crate cookie;
+
+use finchers::endpoint::{self, ApplyContext, ApplyResult, Endpoint};
+use finchers::error::Error;
+use finchers::input::Input;
+
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use self::cookie::Cookie;
+use futures::{future, Async, Future, IntoFuture, Poll};
+use http::header::{self, HeaderName, HeaderValue};
+use tokio_timer::Delay;
+use uuid::Uuid;
+
+use binding::SessionBinding;
+use envelope;
+use error::SessionError;
+use event::{Listeners, RequestMetadata, SessionEvent, SessionEventKind};
+use fallback::{CircuitBreaker, FallbackPolicy};
+use limit::{Limiter, SessionLimit};
+use session::{RawSession, Session};
+use wrap::Wrap;
+
+/// The trait representing a key-value store which holds the session values.
+pub trait SessionStore {
+    /// The type of future returned from `load`.
+    type LoadFuture: Future<Item = Option<String>, Error = Error>;
+
+    /// The type of future returned from `save`.
+    type SaveFuture: Future<Item = (), Error = Error>;
+
+    /// The type of future returned from `delete`.
+    type DeleteFuture: Future<Item = (), Error = Error>;
+
+    /// Retrieves the session value associated with the specified session id.
+    fn load(&self, session_id: &Uuid) -> Self::LoadFuture;
+
+    /// Stores the session value with the specified session id.
+    ///
+    /// If `ttl` is provided, the stored value should be expired after the duration.
+    fn save(&self, session_id: &Uuid, value: String, ttl: Option<Duration>) -> Self::SaveFuture;
+
+    /// Removes the session value associated with the specified session id.
+    fn delete(&self, session_id: &Uuid) -> Self::DeleteFuture;
+}
+
+/// The trait representing a store which maintains an index from the users
+/// to their sessions.
+///
+/// The user of a session is the one marked by `Session::login`, which can be
+/// extracted from the value passed to `SessionStore::save` by `session_user_id`.
+pub trait UserSessionIndex: SessionStore {
+    /// The type of future returned from `list_user_sessions`.
+    type ListFuture: Future<Item = Vec<Uuid>, Error = Error>;
+
+    /// The type of future returned from `revoke_user_sessions`.
+    type RevokeFuture: Future<Item = Vec<Uuid>, Error = Error>;
+
+    /// Retrieves the ids of active sessions associated with the specified user.
+    ///
+    /// The returned ids should be ordered by the time when each session was
+    /// associated with the user, from the oldest one.
+    fn list_user_sessions(&self, user_id: &str) -> Self::ListFuture;
+
+    /// Removes all sessions associated with the specified user,
+    /// and returns the ids of removed sessions.
+    fn revoke_user_sessions(&self, user_id: &str) -> Self::RevokeFuture;
+}
+
+/// A page of session ids returned from `SessionAdmin::list_sessions`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "admin", derive(Serialize))]
+pub struct SessionPage {
+    /// The session ids in this page.
+    pub session_ids: Vec<Uuid>,
+    /// The cursor which retrieves the next page, or `None` if this page is the last one.
+    pub next_cursor: Option<String>,
+}
+
+/// The trait representing a store which can enumerate the stored sessions,
+/// for administrative purposes.
+pub trait SessionAdmin: SessionStore {
+    /// The type of future returned from `list_sessions`.
+    type ListFuture: Future<Item = SessionPage, Error = Error>;
+
+    /// The type of future returned from `count_sessions`.
+    type CountFuture: Future<Item = usize, Error = Error>;
+
+    /// Retrieves a page of the ids of active sessions.
+    ///
+    /// The cursor is the value of `SessionPage::next_cursor` returned from the
+    /// previous call, or `None` for the first page. The number of ids in a page
+    /// is approximately `count`, and the same id may be returned more than once
+    /// if the sessions are modified during the iteration.
+    fn list_sessions(&self, cursor: Option<&str>, count: usize) -> Self::ListFuture;
+
+    /// Counts the number of active sessions.
+    fn count_sessions(&self) -> Self::CountFuture;
+}
+
+/// The content of a session retrieved by `StoreBackend::get_session`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "admin", derive(Serialize))]
+pub struct SessionInfo {
+    /// The id of the user associated with the session.
+    pub user_id: Option<String>,
+    /// The session value.
+    pub value: Option<String>,
+}
+
+impl SessionInfo {
+    fn from_raw(raw: Option<String>) -> Option<SessionInfo> {
+        let raw = raw?;
+        let (envelope, value) = envelope::decode(Some(&raw));
+        Some(SessionInfo {
+            user_id: envelope.user_id,
+            value: value.map(ToOwned::to_owned),
+        })
+    }
+}
+
+/// Returns the id of user authenticated in the raw session value,
+/// which is passed to `SessionStore::save`.
+pub fn session_user_id(value: &str) -> Option<String> {
+    envelope::decode(Some(value)).0.user_id
+}
+
+/// The type representing how the session id (or token) is exchanged with the client.
+#[derive(Debug, Clone)]
+pub struct IdTransport {
+    kind: TransportKind,
+}
+
+#[derive(Debug, Clone)]
+enum TransportKind {
+    Cookie(Cow<'static, str>),
+    Header {
+        request: HeaderName,
+        response: HeaderName,
+        bearer: bool,
+    },
+}
+
+impl IdTransport {
+    /// Creates an `IdTransport` which carries the session id in the Cookie entry
+    /// with the specified name.
+    pub fn cookie(name: impl Into<Cow<'static, str>>) -> IdTransport {
+        IdTransport {
+            kind: TransportKind::Cookie(name.into()),
+        }
+    }
+
+    /// Creates an `IdTransport` which reads the session id from the specified
+    /// request header and returns the new session id in the response header
+    /// with the same name.
+    pub fn header(name: HeaderName) -> IdTransport {
+        IdTransport {
+            kind: TransportKind::Header {
+                request: name.clone(),
+                response: name,
+                bearer: false,
+            },
+        }
+    }
+
+    /// Creates an `IdTransport` which reads the session id from the header
+    /// `Authorization: Bearer <session-id>`.
+    ///
+    /// The new session id is returned in the response header `X-Session-Id`
+    /// by default.
+    pub fn bearer() -> IdTransport {
+        IdTransport {
+            kind: TransportKind::Header {
+                request: header::AUTHORIZATION,
+                response: HeaderName::from_static("x-session-id"),
+                bearer: true,
+            },
+        }
+    }
+
+    /// Sets the name of response header which returns the session id.
+    ///
+    /// This method has no effect if the transport uses Cookie.
+    pub fn response_header(mut self, name: HeaderName) -> IdTransport {
+        if let TransportKind::Header {
+            ref mut response, ..
+        } = self.kind
+        {
+            *response = name;
+        }
+        self
+    }
+
+    pub(crate) fn read(&self, input: &mut Input) -> Result<Option<String>, Error> {
+        match self.kind {
+            TransportKind::Cookie(ref name) => Ok(input
+                .cookies()?
+                .get(name)
+                .map(|cookie| cookie.value().to_owned())),
+            TransportKind::Header {
+                ref request,
+                bearer,
+                ..
+            } => {
+                let value = match input.request().headers().get(request) {
+                    Some(value) => value
+                        .to_str()
+                        .map_err(|_| SessionError::InvalidId(format!("{:?}", value)))?,
+                    None => return Ok(None),
+                };
+                if bearer {
+                    let mut parts = value.splitn(2, ' ');
+                    match (parts.next(), parts.next()) {
+                        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                            Ok(Some(token.trim().to_owned()))
+                        }
+                        _ => Ok(None),
+                    }
+                } else {
+                    Ok(Some(value.trim().to_owned()))
+                }
+            }
+        }
+    }
+
+    pub(crate) fn write(&self, input: &mut Input, value: String) -> Result<(), Error> {
+        match self.kind {
+            TransportKind::Cookie(ref name) => {
+                input.cookies()?.add(Cookie::new(name.clone(), value));
+            }
+            TransportKind::Header { ref response, .. } => {
+                let value = HeaderValue::from_str(&value).map_err(SessionError::serialization)?;
+                input.response_headers().insert(response.clone(), value);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn clear(&self, input: &mut Input) -> Result<(), Error> {
+        match self.kind {
+            TransportKind::Cookie(ref name) => {
+                input.cookies()?.remove(Cookie::named(name.clone()));
+            }
+            // The client is responsible for discarding the session id
+            // carried by the header.
+            TransportKind::Header { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// The policy applied when the session id sent by the client is malformed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MalformedIdPolicy {
+    /// Handles the request as if no session id is sent, and issues a fresh
+    /// session id when a session value is stored.
+    Renew,
+    /// Rejects the request with `400 Bad Request`.
+    Reject,
+}
+
+/// A session id sent by the client which does not identify any session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidSessionId {
+    /// The value which cannot be parsed as a session id.
+    Malformed(String),
+    /// The session id which is not found in the store, typically because
+    /// the session value has been expired.
+    Unknown(Uuid),
+}
+
+type InvalidIdHook = dyn Fn(&InvalidSessionId) + Send + Sync;
+
+// The session id extracted from the request.
+#[derive(Debug)]
+enum RequestedId {
+    Absent,
+    Valid(Uuid),
+    Malformed,
+}
+
+struct StoreConfig {
+    transport: IdTransport,
+    timeout: Option<Duration>,
+    binding: Option<SessionBinding>,
+    listeners: Listeners,
+    malformed_id_policy: MalformedIdPolicy,
+    on_invalid_id: Option<Arc<InvalidIdHook>>,
+    fallback: FallbackPolicy,
+    breaker: Option<Arc<CircuitBreaker>>,
+}
+
+impl fmt::Debug for StoreConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StoreConfig")
+            .field("transport", &self.transport)
+            .field("timeout", &self.timeout)
+            .field("binding", &self.binding)
+            .field("listeners", &self.listeners)
+            .field("malformed_id_policy", &self.malformed_id_policy)
+            .field("fallback", &self.fallback)
+            .field("breaker", &self.breaker)
+            .finish()
+    }
+}
+
+impl StoreConfig {
+    fn get_session_id(&self, input: &mut Input) -> Result<RequestedId, Error> {
+        let value = match self.transport.read(input)? {
+            Some(value) => value,
+            None => return Ok(RequestedId::Absent),
+        };
+        let parsed: Result<Uuid, _> = value.parse();
+        match parsed {
+            Ok(session_id) => Ok(RequestedId::Valid(session_id)),
+            Err(..) => {
+                self.invalid_id(InvalidSessionId::Malformed(value.clone()));
+                match self.malformed_id_policy {
+                    MalformedIdPolicy::Renew => Ok(RequestedId::Malformed),
+                    MalformedIdPolicy::Reject => Err(SessionError::InvalidId(value).into()),
+                }
+            }
+        }
+    }
+
+    fn invalid_id(&self, id: InvalidSessionId) {
+        if let Some(ref on_invalid_id) = self.on_invalid_id {
+            on_invalid_id(&id);
+        }
+    }
+
+    fn allow_access(&self) -> bool {
+        self.breaker.as_ref().map_or(true, |breaker| breaker.allow())
+    }
+
+    fn is_circuit_open(&self) -> bool {
+        self.breaker.as_ref().map_or(false, |breaker| breaker.is_open())
+    }
+
+    fn record_success(&self) {
+        if let Some(ref breaker) = self.breaker {
+            breaker.record_success();
+        }
+    }
+
+    fn record_failure(&self) {
+        if let Some(ref breaker) = self.breaker {
+            breaker.record_failure();
+        }
+    }
+}
+
+fn circuit_open() -> Error {
+    SessionError::storage(format_err!("the circuit breaker is open"))
+}
+
+/// The instance of session backend which stores the session values
+/// into a `SessionStore`.
+#[derive(Debug)]
+pub struct StoreBackend<K> {
+    store: Arc<K>,
+    config: Arc<StoreConfig>,
+    limiter: Option<Arc<Limiter<K>>>,
+}
+
+impl<K> Clone for StoreBackend<K> {
+    fn clone(&self) -> Self {
+        StoreBackend {
+            store: self.store.clone(),
+            config: self.config.clone(),
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+impl<K> Default for StoreBackend<K>
+where
+    K: SessionStore + Default,
+{
+    fn default() -> Self {
+        StoreBackend::from_store(K::default())
+    }
+}
+
+impl<K> StoreBackend<K>
+where
+    K: SessionStore,
+{
+    /// Create a new `StoreBackend` from the specified store.
+    pub fn from_store(store: K) -> StoreBackend<K> {
+        StoreBackend {
+            store: Arc::new(store),
+            config: Arc::new(StoreConfig {
+                transport: IdTransport::cookie("session-id"),
+                timeout: None,
+                binding: None,
+                listeners: Listeners::default(),
+                malformed_id_policy: MalformedIdPolicy::Renew,
+                on_invalid_id: None,
+                fallback: FallbackPolicy::default(),
+                breaker: None,
+            }),
+            limiter: None,
+        }
+    }
+
+    /// Returns a reference to the underlying store.
+    pub fn store(&self) -> &K {
+        &*self.store
+    }
+
+    pub(crate) fn store_mut(&mut self) -> &mut K {
+        Arc::get_mut(&mut self.store).expect("The instance has already shared.")
+    }
+
+    fn config_mut(&mut self) -> &mut StoreConfig {
+        Arc::get_mut(&mut self.config).expect("The instance has already shared.")
+    }
+
+    /// Set the name of Cookie entry which stores the session id.
+    ///
+    /// The default value is "session-id"
+    pub fn cookie_name(mut self, name: impl Into<Cow<'static, str>>) -> StoreBackend<K> {
+        self.config_mut().transport = IdTransport::cookie(name);
+        self
+    }
+
+    /// Set the transport which exchanges the session id with the client.
+    ///
+    /// The default value is `IdTransport::cookie("session-id")`.
+    pub fn transport(mut self, transport: IdTransport) -> StoreBackend<K> {
+        self.config_mut().transport = transport;
+        self
+    }
+
+    /// Set the timeout of session value.
+    pub fn timeout(mut self, timeout: Duration) -> StoreBackend<K> {
+        self.config_mut().timeout = Some(timeout);
+        self
+    }
+
+    /// Set the attributes of the client which the sessions are bound to.
+    ///
+    /// See the module `binding` for details.
+    pub fn binding(mut self, binding: SessionBinding) -> StoreBackend<K> {
+        self.config_mut().binding = Some(binding);
+        self
+    }
+
+    /// Registers a listener which is called with the lifecycle events of sessions.
+    ///
+    /// See the module `event` for details.
+    pub fn on_event<F, R>(mut self, f: F) -> StoreBackend<K>
+    where
+        F: Fn(&SessionEvent) -> R + Send + Sync + 'static,
+        R: IntoFuture<Item = (), Error = Error>,
+        R::Future: Send + 'static,
+    {
+        self.config_mut().listeners.push(f);
+        self
+    }
+
+    /// Set the policy applied when the session id sent by the client is malformed.
+    ///
+    /// The default value is `MalformedIdPolicy::Renew`.
+    pub fn malformed_id_policy(mut self, policy: MalformedIdPolicy) -> StoreBackend<K> {
+        self.config_mut().malformed_id_policy = policy;
+        self
+    }
+
+    /// Registers a callback which is called when the session id sent by the client
+    /// is malformed or is not found in the store.
+    pub fn on_invalid_id<F>(mut self, f: F) -> StoreBackend<K>
+    where
+        F: Fn(&InvalidSessionId) + Send + Sync + 'static,
+    {
+        self.config_mut().on_invalid_id = Some(Arc::new(f));
+        self
+    }
+
+    /// Set the behavior when the session value cannot be loaded from the store.
+    ///
+    /// The default value is `FallbackPolicy::FailFast`. See the module `fallback` for details.
+    pub fn fallback(mut self, policy: FallbackPolicy) -> StoreBackend<K> {
+        self.config_mut().fallback = policy;
+        self
+    }
+
+    /// Set the circuit breaker which stops accessing the store after consecutive failures.
+    ///
+    /// See the module `fallback` for details.
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> StoreBackend<K> {
+        self.config_mut().breaker = Some(Arc::new(breaker));
+        self
+    }
+
+    /// Wraps the specified endpoint so that the session is committed automatically
+    /// after the endpoint.
+    ///
+    /// See the module `wrap` for details.
+    pub fn wrap<E>(self, endpoint: E) -> Wrap<StoreBackend<K>, E> {
+        Wrap::new(self, endpoint)
+    }
+
+    /// Converts this backend into an endpoint which defers loading the session
+    /// value until the handler accesses it.
+    ///
+    /// See the section "Lazy loading" in the module documentation for details.
+    pub fn lazy(self) -> LazyStoreBackend<K> {
+        LazyStoreBackend { backend: self }
+    }
+
+    // Extracts the metadata of the current request used by the session.
+    fn request_metadata(
+        &self,
+        input: &mut Input,
+    ) -> (Option<String>, Option<Arc<RequestMetadata>>) {
+        let fingerprint = self
+            .config
+            .binding
+            .as_ref()
+            .map(|binding| binding.fingerprint(input));
+        let request = if self.config.listeners.is_empty() {
+            None
+        } else {
+            Some(Arc::new(RequestMetadata::from_input(input)))
+        };
+        (fingerprint, request)
+    }
+}
+
+impl<K> StoreBackend<K>
+where
+    K: UserSessionIndex,
+{
+    /// Retrieves the ids of active sessions associated with the specified user.
+    pub fn list_user_sessions(&self, user_id: &str) -> K::ListFuture {
+        self.store.list_user_sessions(user_id)
+    }
+
+    /// Removes all sessions associated with the specified user (e.g. after
+    /// changing the password), and returns the ids of removed sessions.
+    pub fn revoke_user_sessions(&self, user_id: &str) -> K::RevokeFuture {
+        self.store.revoke_user_sessions(user_id)
+    }
+
+    /// Set the limit on the number of concurrent sessions per user.
+    ///
+    /// The limit is checked when a session is associated with a new user
+    /// by `Session::login`. See the module `limit` for details.
+    pub fn session_limit(mut self, limit: SessionLimit) -> StoreBackend<K>
+    where
+        K: Send + Sync + 'static,
+        K::ListFuture: Send + 'static,
+        K::DeleteFuture: Send + 'static,
+    {
+        self.limiter = Some(Arc::new(Limiter::new(limit)));
+        self
+    }
+}
+
+impl<K> StoreBackend<K>
+where
+    K: SessionAdmin,
+{
+    /// Retrieves a page of the ids of active sessions.
+    ///
+    /// See `SessionAdmin::list_sessions` for details.
+    pub fn list_sessions(&self, cursor: Option<&str>, count: usize) -> K::ListFuture {
+        self.store.list_sessions(cursor, count)
+    }
+
+    /// Retrieves the content of the session with the specified id.
+    pub fn get_session(
+        &self,
+        session_id: &Uuid,
+    ) -> future::Map<K::LoadFuture, fn(Option<String>) -> Option<SessionInfo>> {
+        self.store
+            .load(session_id)
+            .map(SessionInfo::from_raw as fn(_) -> _)
+    }
+
+    /// Removes the session with the specified id.
+    pub fn delete_session(&self, session_id: &Uuid) -> K::DeleteFuture {
+        self.store.delete(session_id)
+    }
+
+    // Removes the session on behalf of the request, and notifies the listeners
+    // of the `Destroyed` event after the removal has been completed.
+    #[cfg(feature = "admin")]
+    pub(crate) fn destroy_session(
+        &self,
+        session_id: Uuid,
+        input: &Input,
+    ) -> impl Future<Item = (), Error = Error> {
+        let listeners = self.config.listeners.clone();
+        let request = if listeners.is_empty() {
+            None
+        } else {
+            Some(Arc::new(RequestMetadata::from_input(input)))
+        };
+        self.store.delete(&session_id).and_then(move |()| {
+            let event = request.map(|request| {
+                SessionEvent::new(SessionEventKind::Destroyed, session_id, None, request)
+            });
+            match event.and_then(|event| listeners.notify(&[event])) {
+                Some(future) => future::Either::A(future),
+                None => future::Either::B(future::ok(())),
+            }
+        })
+    }
+
+    /// Counts the number of active sessions.
+    pub fn count_sessions(&self) -> K::CountFuture {
+        self.store.count_sessions()
+    }
+}
+
+impl<'a, K> Endpoint<'a> for StoreBackend<K>
+where
+    K: SessionStore + 'a,
+{
+    type Output = (Session<StoreSession<K>>,);
+    type Future = ReadFuture<K>;
+
+    fn apply(&self, cx: &mut ApplyContext<'_>) -> ApplyResult<Self::Future> {
+        let (fingerprint, request) = self.request_metadata(cx.input());
+        Ok(match self.config.get_session_id(cx.input()) {
+            Ok(requested) => ReadFuture::start(self, requested, fingerprint, request),
+            Err(err) => ReadFuture::failed(err),
+        })
+    }
+}
+
+/// An endpoint which yields a `LazySession`.
+///
+/// This is created by `StoreBackend::lazy`.
+#[derive(Debug)]
+pub struct LazyStoreBackend<K> {
+    backend: StoreBackend<K>,
+}
+
+impl<K> Clone for LazyStoreBackend<K> {
+    fn clone(&self) -> Self {
+        LazyStoreBackend {
+            backend: self.backend.clone(),
+        }
+    }
+}
+
+impl<'a, K> Endpoint<'a> for LazyStoreBackend<K>
+where
+    K: SessionStore + 'a,
+{
+    type Output = (LazySession<K>,);
+    type Future = future::FutureResult<Self::Output, Error>;
+
+    fn apply(&self, cx: &mut ApplyContext<'_>) -> ApplyResult<Self::Future> {
+        // The session id is extracted here, so that the request with a rejected
+        // session id fails without accessing the store.
+        let (fingerprint, request) = self.backend.request_metadata(cx.input());
+        Ok(future::result(
+            self.backend
+                .config
+                .get_session_id(cx.input())
+                .map(|requested| {
+                    (LazySession {
+                        backend: self.backend.clone(),
+                        requested,
+                        fingerprint,
+                        request,
+                    },)
+                }),
+        ))
+    }
+}
+
+/// A handle which loads the session value from the store on demand.
+///
+/// If the session is never loaded, nothing is written to the store nor to
+/// the response (including the removal of an invalid session id).
+#[derive(Debug)]
+pub struct LazySession<K> {
+    backend: StoreBackend<K>,
+    requested: RequestedId,
+    fingerprint: Option<String>,
+    request: Option<Arc<RequestMetadata>>,
+}
+
+impl<K> LazySession<K>
+where
+    K: SessionStore,
+{
+    /// Returns `true` if the request carries a well-formed session id.
+    ///
+    /// This can be used to skip loading when the client has no session,
+    /// although `load` does not access the store in that case either.
+    pub fn has_session_id(&self) -> bool {
+        match self.requested {
+            RequestedId::Valid(..) => true,
+            _ => false,
+        }
+    }
+
+    /// Loads the session value from the store.
+    pub fn load(self) -> impl Future<Item = Session<StoreSession<K>>, Error = Error> {
+        let LazySession {
+            backend,
+            requested,
+            fingerprint,
+            request,
+        } = self;
+        ReadFuture::start(&backend, requested, fingerprint, request).map(|(session,)| session)
+    }
+
+    /// Loads the session value and calls `Session::with` with the specified function.
+    pub fn with<R>(
+        self,
+        f: impl FnOnce(&mut Session<StoreSession<K>>) -> R,
+    ) -> impl Future<Item = R::Item, Error = Error>
+    where
+        R: IntoFuture<Error = Error>,
+    {
+        self.load().and_then(move |session| session.with(f))
+    }
+}
+
+#[doc(hidden)]
+#[allow(missing_debug_implementations)]
+pub struct ReadFuture<K: SessionStore> {
+    state: ReadFutureState<K>,
+    // The fingerprint of the current request.
+    fingerprint: Option<String>,
+    // The metadata of the current request, used only if there are listeners.
+    request: Option<Arc<RequestMetadata>>,
+}
+
+#[allow(missing_debug_implementations)]
+enum ReadFutureState<K: SessionStore> {
+    Failed(Option<Error>),
+    Empty {
+        backend: Option<StoreBackend<K>>,
+        malformed: bool,
+        degraded: bool,
+    },
+    Loading {
+        future: K::LoadFuture,
+        backend: Option<StoreBackend<K>>,
+        session_id: Uuid,
+        attempt: u32,
+        started: Instant,
+    },
+    Retrying {
+        delay: Delay,
+        backend: Option<StoreBackend<K>>,
+        session_id: Uuid,
+        attempt: u32,
+        started: Instant,
+    },
+    Notifying {
+        future: Box<dyn Future<Item = (), Error = Error> + Send>,
+        session: Option<Session<StoreSession<K>>>,
+    },
+}
+
+// The result of loading, before verifying the binding and notifying the event.
+struct Loaded<K> {
+    backend: StoreBackend<K>,
+    session_id: Option<Uuid>,
+    value: Option<String>,
+    expired: Option<Uuid>,
+    malformed: bool,
+    degraded: bool,
+}
+
+enum ReadStep<K> {
+    Loaded(Loaded<K>),
+    Load {
+        backend: StoreBackend<K>,
+        session_id: Uuid,
+        attempt: u32,
+        started: Instant,
+    },
+    Wait {
+        at: Instant,
+        backend: StoreBackend<K>,
+        session_id: Uuid,
+        attempt: u32,
+        started: Instant,
+    },
+}
+
+impl<K> ReadFuture<K>
+where
+    K: SessionStore,
+{
+    fn start(
+        backend: &StoreBackend<K>,
+        requested: RequestedId,
+        fingerprint: Option<String>,
+        request: Option<Arc<RequestMetadata>>,
+    ) -> ReadFuture<K> {
+        let future = match requested {
+            RequestedId::Valid(session_id) => ReadFuture::loading(backend, session_id),
+            RequestedId::Absent => ReadFuture::empty(backend, false),
+            RequestedId::Malformed => ReadFuture::empty(backend, true),
+        };
+        ReadFuture {
+            fingerprint,
+            request,
+            ..future
+        }
+    }
+
+    fn failed(err: Error) -> ReadFuture<K> {
+        ReadFuture {
+            state: ReadFutureState::Failed(Some(err)),
+            fingerprint: None,
+            request: None,
+        }
+    }
+
+    fn empty(backend: &StoreBackend<K>, malformed: bool) -> ReadFuture<K> {
+        ReadFuture {
+            state: ReadFutureState::Empty {
+                backend: Some(backend.clone()),
+                malformed,
+                // The new sessions are not stored while the circuit is open.
+                degraded: backend.config.fallback == FallbackPolicy::Degrade
+                    && backend.config.is_circuit_open(),
+            },
+            fingerprint: None,
+            request: None,
+        }
+    }
+
+    fn loading(backend: &StoreBackend<K>, session_id: Uuid) -> ReadFuture<K> {
+        if !backend.config.allow_access() {
+            return match backend.config.fallback {
+                FallbackPolicy::Degrade => ReadFuture {
+                    state: ReadFutureState::Empty {
+                        backend: Some(backend.clone()),
+                        malformed: false,
+                        degraded: true,
+                    },
+                    fingerprint: None,
+                    request: None,
+                },
+                _ => ReadFuture::failed(circuit_open()),
+            };
+        }
+        ReadFuture {
+            state: ReadFutureState::Loading {
+                future: backend.store.load(&session_id),
+                backend: Some(backend.clone()),
+                session_id,
+                attempt: 0,
+                started: Instant::now(),
+            },
+            fingerprint: None,
+            request: None,
+        }
+    }
+
+    fn complete(&mut self, loaded: Loaded<K>) -> Poll<(Session<StoreSession<K>>,), Error> {
+        let Loaded {
+            backend,
+            session_id,
+            value,
+            expired,
+            malformed,
+            degraded,
+        } = loaded;
+
+        let (session_id, value, fingerprint) =
+            verify_binding(&backend, session_id, value, self.fingerprint.take());
+        let user_id = value.as_ref().and_then(|value| session_user_id(value));
+
+        let event = match (session_id, expired, &self.request) {
+            (Some(session_id), _, &Some(ref request)) => Some(SessionEvent::new(
+                SessionEventKind::Loaded,
+                session_id,
+                None,
+                request.clone(),
+            )),
+            (None, Some(session_id), &Some(ref request)) => Some(SessionEvent::new(
+                SessionEventKind::Expired,
+                session_id,
+                None,
+                request.clone(),
+            )),
+            _ => None,
+        };
+        let notify = event.and_then(|event| backend.config.listeners.notify(&[event]));
+        if let Some(expired) = expired {
+            backend.config.invalid_id(InvalidSessionId::Unknown(expired));
+        }
+
+        let session = Session::new(StoreSession {
+            backend,
+            session_id,
+            value,
+            stale_session_id: None,
+            user_id,
+            fingerprint,
+            request: self.request.take(),
+            clear_id: malformed || expired.is_some(),
+            read_only: degraded,
+        });
+        match notify {
+            Some(future) => {
+                self.state = ReadFutureState::Notifying {
+                    future,
+                    session: Some(session),
+                };
+                self.poll()
+            }
+            None => Ok(Async::Ready((session,))),
+        }
+    }
+}
+
+impl<K> Future for ReadFuture<K>
+where
+    K: SessionStore,
+{
+    type Item = (Session<StoreSession<K>>,);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        use self::ReadFutureState::*;
+        loop {
+            let step = match self.state {
+                Failed(ref mut err) => {
+                    return Err(err.take().expect("This future has already polled."))
+                }
+                Empty {
+                    ref mut backend,
+                    malformed,
+                    degraded,
+                } => {
+                    let backend = backend.take().expect("This future has already polled.");
+                    ReadStep::Loaded(Loaded {
+                        backend,
+                        session_id: None,
+                        value: None,
+                        expired: None,
+                        malformed,
+                        degraded,
+                    })
+                }
+                Loading {
+                    ref mut future,
+                    ref mut backend,
+                    session_id,
+                    attempt,
+                    started,
+                } => {
+                    let result = match future.poll() {
+                        Ok(Async::Ready(value)) => Ok(value),
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(err) => Err(err),
+                    };
+                    let backend = backend.take().expect("This future has already polled.");
+                    match result {
+                        // The session id is reused only if the corresponding value is
+                        // found in the store.
+                        Ok(value) => {
+                            backend.config.record_success();
+                            let (session_id, expired) = match value {
+                                Some(..) => (Some(session_id), None),
+                                None => (None, Some(session_id)),
+                            };
+                            ReadStep::Loaded(Loaded {
+                                backend,
+                                session_id,
+                                value,
+                                expired,
+                                malformed: false,
+                                degraded: false,
+                            })
+                        }
+                        Err(err) => {
+                            backend.config.record_failure();
+                            let fallback = backend.config.fallback;
+                            match fallback {
+                                FallbackPolicy::Degrade => ReadStep::Loaded(Loaded {
+                                    backend,
+                                    session_id: None,
+                                    value: None,
+                                    expired: None,
+                                    malformed: false,
+                                    degraded: true,
+                                }),
+                                _ => match fallback.next_retry(attempt, started) {
+                                    Some(at) => ReadStep::Wait {
+                                        at,
+                                        backend,
+                                        session_id,
+                                        attempt,
+                                        started,
+                                    },
+                                    None => return Err(err),
+                                },
+                            }
+                        }
+                    }
+                }
+                Retrying {
+                    ref mut delay,
+                    ref mut backend,
+                    session_id,
+                    attempt,
+                    started,
+                } => {
+                    try_ready!(delay.poll().map_err(SessionError::storage));
+                    let backend = backend.take().expect("This future has already polled.");
+                    ReadStep::Load {
+                        backend,
+                        session_id,
+                        attempt: attempt + 1,
+                        started,
+                    }
+                }
+                Notifying {
+                    ref mut future,
+                    ref mut session,
+                } => {
+                    try_ready!(future.poll());
+                    let session = session.take().expect("This future has already polled.");
+                    return Ok(Async::Ready((session,)));
+                }
+            };
+
+            match step {
+                ReadStep::Loaded(loaded) => return self.complete(loaded),
+                ReadStep::Load {
+                    backend,
+                    session_id,
+                    attempt,
+                    started,
+                } => {
+                    // The retries are also rejected while the circuit is open.
+                    if !backend.config.allow_access() {
+                        return Err(circuit_open());
+                    }
+                    self.state = Loading {
+                        future: backend.store.load(&session_id),
+                        backend: Some(backend),
+                        session_id,
+                        attempt,
+                        started,
+                    };
+                }
+                ReadStep::Wait {
+                    at,
+                    backend,
+                    session_id,
+                    attempt,
+                    started,
+                } => {
+                    self.state = Retrying {
+                        delay: Delay::new(at),
+                        backend: Some(backend),
+                        session_id,
+                        attempt,
+                        started,
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Splits the fingerprint from the loaded session value and checks it against
+/// the fingerprint of the current request.
+///
+/// The returned fingerprint is the one which will be stored with the session value.
+fn verify_binding<K>(
+    backend: &StoreBackend<K>,
+    session_id: Option<Uuid>,
+    value: Option<String>,
+    current: Option<String>,
+) -> (Option<Uuid>, Option<String>, Option<String>) {
+    let binding = match backend.config.binding {
+        Some(ref binding) => binding,
+        None => return (session_id, value, None),
+    };
+    let current = current.expect("the fingerprint should be computed");
+    let (session_id, value) = match (session_id, value) {
+        (Some(session_id), Some(value)) => (session_id, value),
+        _ => return (None, None, Some(current)),
+    };
+
+    match envelope::split_fingerprint(&value) {
+        (Some(stored), value) => {
+            if binding.accepts(&session_id, &stored, &current) {
+                (Some(session_id), value, Some(stored))
+            } else {
+                (None, None, Some(current))
+            }
+        }
+        // The session was created before enabling the binding.
+        (None, value) => (Some(session_id), value, Some(current)),
+    }
+}
+
+// ==== StoreSession ====
+
+/// The type of raw session created by `StoreBackend`.
+#[derive(Debug)]
+pub struct StoreSession<K> {
+    backend: StoreBackend<K>,
+    session_id: Option<Uuid>,
+    value: Option<String>,
+    // The session id discarded by `regenerate`.
+    stale_session_id: Option<Uuid>,
+    // The user associated with the loaded session value.
+    user_id: Option<String>,
+    // The fingerprint stored together with the session value.
+    fingerprint: Option<String>,
+    // The metadata of the current request, used only if there are listeners.
+    request: Option<Arc<RequestMetadata>>,
+    // Whether the session id sent by the client is malformed or unknown.
+    clear_id: bool,
+    // Whether the session is not written back, since the store is unavailable.
+    read_only: bool,
+}
+
+impl<K> RawSession for StoreSession<K>
+where
+    K: SessionStore,
+{
+    type WriteFuture = WriteFuture<K>;
+
+    fn get(&self) -> Option<&str> {
+        self.value.as_ref().map(|s| s.as_ref())
+    }
+
+    fn set(&mut self, value: String) {
+        self.value = Some(value);
+    }
+
+    fn remove(&mut self) {
+        self.value = None;
+    }
+
+    fn regenerate(&mut self) {
+        if let Some(session_id) = self.session_id.take() {
+            self.stale_session_id.get_or_insert(session_id);
+        }
+    }
+
+    fn write(self, input: &mut Input) -> Self::WriteFuture {
+        let StoreSession {
+            backend,
+            session_id,
+            value,
+            stale_session_id,
+            user_id,
+            fingerprint,
+            request,
+            clear_id,
+            read_only,
+        } = self;
+        if read_only {
+            return WriteFuture::no_op();
+        }
+        let config = backend.config.clone();
+        let event = |kind, session_id, previous_session_id| {
+            request.as_ref().map(|request| {
+                SessionEvent::new(kind, session_id, previous_session_id, request.clone())
+            })
+        };
+
+        match (session_id.or(stale_session_id), value) {
+            (Some(session_id), None) => {
+                if !config.allow_access() {
+                    return WriteFuture::failed(circuit_open());
+                }
+                if let Err(err) = config.transport.clear(input) {
+                    return WriteFuture::failed(err);
+                }
+                WriteFuture::delete(backend.store.delete(&session_id))
+                    .notify(config, event(SessionEventKind::Destroyed, session_id, None))
+            }
+            (_, Some(value)) => {
+                if !config.allow_access() {
+                    return WriteFuture::failed(circuit_open());
+                }
+                let new_session_id = session_id.unwrap_or_else(Uuid::new_v4);
+
+                // The limit is checked only when the session is associated
+                // with a new user.
+                let limit = match (&backend.limiter, session_user_id(&value)) {
+                    (&Some(ref limiter), Some(ref new_user_id))
+                        if user_id.as_ref() != Some(new_user_id) =>
+                    {
+                        let excluded = session_id.into_iter().chain(stale_session_id).collect();
+                        Some(limiter.enforce(&backend.store, new_user_id, excluded))
+                    }
+                    _ => None,
+                };
+
+                let value = match fingerprint {
+                    Some(ref fingerprint) => envelope::with_fingerprint(&value, fingerprint),
+                    None => value,
+                };
+                let event = match (session_id, stale_session_id) {
+                    (_, Some(stale_session_id)) => event(
+                        SessionEventKind::Regenerated,
+                        new_session_id,
+                        Some(stale_session_id),
+                    ),
+                    (Some(..), None) => event(SessionEventKind::Updated, new_session_id, None),
+                    (None, None) => event(SessionEventKind::Created, new_session_id, None),
+                };
+
+                let pending = PendingSave {
+                    backend,
+                    session_id: new_session_id,
+                    value,
+                    stale_session_id,
+                };
+                let future = match limit {
+                    Some(future) => WriteFuture::limiting(future, pending),
+                    None => pending.start(),
+                };
+                // The session id is sent to the client only after the session
+                // value has been saved successfully.
+                future.issue(new_session_id).notify(config, event)
+            }
+            (None, None) => {
+                // Removes the stale session id from the client.
+                if clear_id {
+                    if let Err(err) = config.transport.clear(input) {
+                        return WriteFuture::failed(err);
+                    }
+                }
+                WriteFuture::no_op()
+            }
+        }
+    }
+}
+
+// The session value which will be saved after checking the limit.
+struct PendingSave<K> {
+    backend: StoreBackend<K>,
+    session_id: Uuid,
+    value: String,
+    stale_session_id: Option<Uuid>,
+}
+
+impl<K> PendingSave<K>
+where
+    K: SessionStore,
+{
+    fn start(self) -> WriteFuture<K> {
+        let PendingSave {
+            backend,
+            session_id,
+            value,
+            stale_session_id,
+        } = self;
+        let save = backend
+            .store
+            .save(&session_id, value, backend.config.timeout);
+        match stale_session_id {
+            Some(stale_session_id) => {
+                WriteFuture::rotate(backend.store.delete(&stale_session_id), save)
+            }
+            None => WriteFuture::save(save),
+        }
+    }
+}
+
+#[doc(hidden)]
+#[allow(missing_debug_implementations)]
+pub struct WriteFuture<K: SessionStore> {
+    state: WriteFutureState<K>,
+    config: Option<Arc<StoreConfig>>,
+    // The session id written to the response after the store has been updated.
+    issued: Option<Uuid>,
+    // The event notified after the store has been updated.
+    event: Option<SessionEvent>,
+}
+
+#[allow(missing_debug_implementations)]
+enum WriteFutureState<K: SessionStore> {
+    Noop,
+    Failed(Option<Error>),
+    Limiting {
+        future: Box<dyn Future<Item = (), Error = Error> + Send>,
+        pending: Option<PendingSave<K>>,
+    },
+    Save(K::SaveFuture),
+    Delete(K::DeleteFuture),
+    Rotate(future::Join<K::DeleteFuture, K::SaveFuture>),
+    Notifying(Box<dyn Future<Item = (), Error = Error> + Send>),
+}
+
+impl<K> WriteFuture<K>
+where
+    K: SessionStore,
+{
+    fn no_op() -> WriteFuture<K> {
+        WriteFuture {
+            state: WriteFutureState::Noop,
+            config: None,
+            issued: None,
+            event: None,
+        }
+    }
+
+    fn failed(err: Error) -> WriteFuture<K> {
+        WriteFuture {
+            state: WriteFutureState::Failed(Some(err)),
+            config: None,
+            issued: None,
+            event: None,
+        }
+    }
+
+    fn limiting(
+        future: Box<dyn Future<Item = (), Error = Error> + Send>,
+        pending: PendingSave<K>,
+    ) -> WriteFuture<K> {
+        WriteFuture {
+            state: WriteFutureState::Limiting {
+                future,
+                pending: Some(pending),
+            },
+            config: None,
+            issued: None,
+            event: None,
+        }
+    }
+
+    fn save(future: K::SaveFuture) -> WriteFuture<K> {
+        WriteFuture {
+            state: WriteFutureState::Save(future),
+            config: None,
+            issued: None,
+            event: None,
+        }
+    }
+
+    fn delete(future: K::DeleteFuture) -> WriteFuture<K> {
+        WriteFuture {
+            state: WriteFutureState::Delete(future),
+            config: None,
+            issued: None,
+            event: None,
+        }
+    }
+
+    fn rotate(delete: K::DeleteFuture, save: K::SaveFuture) -> WriteFuture<K> {
+        WriteFuture {
+            state: WriteFutureState::Rotate(delete.join(save)),
+            config: None,
+            issued: None,
+            event: None,
+        }
+    }
+
+    fn issue(self, session_id: Uuid) -> WriteFuture<K> {
+        WriteFuture {
+            issued: Some(session_id),
+            ..self
+        }
+    }
+
+    fn notify(self, config: Arc<StoreConfig>, event: Option<SessionEvent>) -> WriteFuture<K> {
+        WriteFuture {
+            config: Some(config),
+            event,
+            ..self
+        }
+    }
+}
+
+impl<K> Future for WriteFuture<K>
+where
+    K: SessionStore,
+{
+    type Item = ();
+    type Error = Error;
+
+    #[inline]
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        use self::WriteFutureState::*;
+        loop {
+            let pending = match self.state {
+                Noop => None,
+                Failed(ref mut err) => {
+                    return Err(err.take().expect("The future has already polled."))
+                }
+                Limiting {
+                    ref mut future,
+                    ref mut pending,
+                } => {
+                    try_ready!(future.poll());
+                    Some(pending.take().expect("The future has already polled."))
+                }
+                Save(ref mut future) => {
+                    try_ready!(record(&self.config, future.poll()));
+                    None
+                }
+                Delete(ref mut future) => {
+                    try_ready!(record(&self.config, future.poll()));
+                    None
+                }
+                Rotate(ref mut future) => {
+                    try_ready!(record(&self.config, future.poll()));
+                    None
+                }
+                Notifying(ref mut future) => return future.poll(),
+            };
+
+            if let Some(pending) = pending {
+                self.state = pending.start().state;
+                continue;
+            }
+
+            if let (Some(session_id), &Some(ref config)) = (self.issued.take(), &self.config) {
+                endpoint::with_get_cx(|input| {
+                    config.transport.write(input, session_id.to_string())
+                })?;
+            }
+
+            let notify = match (&self.config, self.event.take()) {
+                (&Some(ref config), Some(event)) => config.listeners.notify(&[event]),
+                _ => None,
+            };
+            match notify {
+                Some(future) => self.state = Notifying(future),
+                None => return Ok(Async::Ready(())),
+            }
+        }
+    }
+}
+
+// Records the result of an access to the store to the circuit breaker.
+fn record<T>(config: &Option<Arc<StoreConfig>>, polled: Poll<T, Error>) -> Poll<T, Error> {
+    if let Some(ref config) = *config {
+        match polled {
+            Ok(Async::Ready(..)) => config.record_success(),
+            Err(..) => config.record_failure(),
+            Ok(Async::NotReady) => {}
+        }
+    }
+    polled
+}
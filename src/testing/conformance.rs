@@ -0,0 +1,254 @@
+//! A conformance test suite for the session backends.
+//!
+//! `Conformance` drives an endpoint which produces `Session<S>` through
+//! `finchers::test::runner`, and checks the following behaviors:
+//!
+//! * A new session sets the session cookie when a value is stored.
+//! * The stored value is returned in the subsequent requests with the cookie.
+//! * `Session::remove` clears both the stored value and the cookie.
+//! * A request with an invalid cookie value is handled as if the session does
+//!   not exist (or is rejected with a client error).
+//! * No cookie is emitted when an empty session is left untouched or is only read.
+//!
+//! Each check panics on failure, and is also exposed as an individual method.
+//! The session id must be exchanged by Cookie (the default of `StoreBackend`).
+//!
+//! # Example
+//!
+//! ```
+//! extern crate finchers_session;
+//!
+//! use finchers_session::in_memory::InMemoryBackend;
+//! use finchers_session::testing::conformance::Conformance;
+//!
+//! # fn main() {
+//! Conformance::new(InMemoryBackend::default(), "session-id").run();
+//! # }
+//! ```
+
+use finchers::endpoint::Endpoint;
+use finchers::prelude::*;
+use finchers::test;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::cookie::Cookie;
+use http::Request;
+
+use super::{is_removal, set_cookie, RequestBuilderExt};
+use session::{RawSession, Session};
+
+const VALUE: &str = "conformance-value";
+const UPDATED_VALUE: &str = "conformance-updated-value";
+
+#[derive(Debug, Clone)]
+enum Action {
+    Noop,
+    Get,
+    Set(&'static str),
+    Remove,
+}
+
+#[derive(Debug)]
+struct Outcome {
+    status: u16,
+    cookie: Option<Cookie<'static>>,
+    observed: Option<String>,
+}
+
+/// The conformance test suite for a session backend.
+#[derive(Debug)]
+pub struct Conformance<E> {
+    backend: E,
+    cookie_name: String,
+    invalid_value: Option<String>,
+    client_side: bool,
+}
+
+impl<E, S> Conformance<E>
+where
+    E: for<'a> Endpoint<'a, Output = (Session<S>,)> + Clone,
+    S: RawSession,
+{
+    /// Create a new test suite for the backend, which exchanges the session
+    /// with the Cookie of the specified name.
+    pub fn new(backend: E, cookie_name: impl Into<String>) -> Conformance<E> {
+        Conformance {
+            backend,
+            cookie_name: cookie_name.into(),
+            invalid_value: Some("invalid-session-id".into()),
+            client_side: false,
+        }
+    }
+
+    /// Sets the Cookie value which the backend should reject.
+    ///
+    /// The default value is `"invalid-session-id"`.
+    pub fn invalid_value(mut self, value: impl Into<String>) -> Conformance<E> {
+        self.invalid_value = Some(value.into());
+        self
+    }
+
+    /// Skips the check for invalid Cookie values.
+    ///
+    /// This is useful for the backends which accept any Cookie value,
+    /// such as `cookie::plain()`.
+    pub fn skip_invalid_value(mut self) -> Conformance<E> {
+        self.invalid_value = None;
+        self
+    }
+
+    /// Marks the backend as storing the session value in the Cookie itself.
+    ///
+    /// The stale Cookie sent after the session is removed still carries the
+    /// value with such backends, and hence the check is skipped.
+    pub fn client_side(mut self) -> Conformance<E> {
+        self.client_side = true;
+        self
+    }
+
+    /// Runs all checks.
+    pub fn run(&self) {
+        self.check_new_session_creates_cookie();
+        self.check_value_round_trip();
+        self.check_remove_clears_session();
+        if self.invalid_value.is_some() {
+            self.check_invalid_value();
+        }
+        self.check_untouched_empty_session();
+    }
+
+    /// Checks that storing a value in a new session sets the Cookie.
+    pub fn check_new_session_creates_cookie(&self) {
+        self.set_value(VALUE, None);
+    }
+
+    /// Checks that the stored value is returned in the subsequent requests,
+    /// and can be updated.
+    pub fn check_value_round_trip(&self) {
+        let cookie = self.set_value(VALUE, None);
+        let outcome = self.perform(Action::Get, Some(&cookie));
+        assert_eq!(
+            outcome.observed.as_ref().map(|s| s.as_str()),
+            Some(VALUE),
+            "the stored value is not returned"
+        );
+
+        let cookie = self.set_value(UPDATED_VALUE, Some(&cookie));
+        let outcome = self.perform(Action::Get, Some(&cookie));
+        assert_eq!(
+            outcome.observed.as_ref().map(|s| s.as_str()),
+            Some(UPDATED_VALUE),
+            "the updated value is not returned"
+        );
+    }
+
+    /// Checks that removing the session clears both the Cookie and the stored value.
+    pub fn check_remove_clears_session(&self) {
+        let cookie = self.set_value(VALUE, None);
+
+        let outcome = self.perform(Action::Remove, Some(&cookie));
+        assert!(
+            outcome.cookie.as_ref().map_or(false, is_removal),
+            "the response does not remove the Cookie `{}`",
+            self.cookie_name
+        );
+
+        if !self.client_side {
+            let outcome = self.perform(Action::Get, Some(&cookie));
+            assert_eq!(
+                outcome.observed, None,
+                "the removed value is returned with the stale Cookie"
+            );
+        }
+    }
+
+    /// Checks that a request with an invalid Cookie value is handled as if
+    /// the session does not exist, or is rejected with a client error.
+    pub fn check_invalid_value(&self) {
+        let invalid_value = match self.invalid_value {
+            Some(ref value) => value.clone(),
+            None => return,
+        };
+        let outcome = self.perform(Action::Get, Some(&invalid_value));
+        assert!(
+            outcome.status < 500,
+            "the invalid Cookie value causes a server error (status = {})",
+            outcome.status
+        );
+        if outcome.status < 400 {
+            assert_eq!(
+                outcome.observed, None,
+                "the invalid Cookie value is accepted"
+            );
+        }
+    }
+
+    /// Checks that no Cookie is emitted for an empty session which is
+    /// left untouched or is only read.
+    pub fn check_untouched_empty_session(&self) {
+        for action in vec![Action::Noop, Action::Get] {
+            let outcome = self.perform(action, None);
+            if let Some(cookie) = outcome.cookie {
+                panic!("the response unexpectedly contains the Cookie: {}", cookie);
+            }
+        }
+    }
+
+    // Stores the value and returns the Cookie value to be sent back.
+    fn set_value(&self, value: &'static str, cookie: Option<&str>) -> String {
+        let outcome = self.perform(Action::Set(value), cookie);
+        assert!(
+            outcome.status >= 200 && outcome.status < 300,
+            "failed to store the value (status = {})",
+            outcome.status
+        );
+        match (outcome.cookie, cookie) {
+            (Some(ref new_cookie), _) if !is_removal(new_cookie) => new_cookie.value().to_owned(),
+            // The backends which keep the session id may not send the Cookie again.
+            (None, Some(cookie)) => cookie.to_owned(),
+            _ => panic!("the response does not set the Cookie `{}`", self.cookie_name),
+        }
+    }
+
+    fn perform(&self, action: Action, cookie: Option<&str>) -> Outcome {
+        let observed = Rc::new(RefCell::new(None));
+        let mut runner = test::runner({
+            let observed = observed.clone();
+            self.backend
+                .clone()
+                .and_then(move |session: Session<S>| {
+                    let action = action.clone();
+                    let observed = observed.clone();
+                    session.with(move |session| {
+                        match action {
+                            Action::Noop => {}
+                            Action::Get => {
+                                *observed.borrow_mut() = session.get().map(ToOwned::to_owned);
+                            }
+                            Action::Set(value) => session.set(value),
+                            Action::Remove => session.remove(),
+                        }
+                        Ok("done")
+                    })
+                })
+        });
+
+        let mut request = Request::get("/");
+        request.header("host", "localhost:3000");
+        if let Some(cookie) = cookie {
+            request.session_cookie(&self.cookie_name, cookie);
+        }
+        let response = runner
+            .perform(&mut request)
+            .expect("failed to perform the request");
+
+        let observed = observed.borrow_mut().take();
+        Outcome {
+            status: response.status().as_u16(),
+            cookie: set_cookie(&response, &self.cookie_name),
+            observed,
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! Utilities for testing the handlers which use sessions
+//! (requires the feature flag `feature = "testing"`).
+//!
+//! * `MockBackend` is an endpoint which creates `Session<MockSession>` from
+//!   a pre-seeded value, and records the operations applied to the session.
+//! * `RequestBuilderExt` and the functions `set_cookie`, `assert_set_cookie`,
+//!   `assert_no_set_cookie` and `assert_cookie_removed` help with exchanging
+//!   the session cookie with the handlers via `finchers::test::runner`.
+//! * `conformance::Conformance` checks that a session backend behaves
+//!   in the same way as the ones provided by this crate.
+//!
+//! # Example
+//!
+//! ```
+//! #[macro_use]
+//! extern crate finchers;
+//! extern crate finchers_session;
+//! extern crate http;
+//!
+//! use finchers::prelude::*;
+//! use finchers::test;
+//! use finchers_session::Session;
+//! use finchers_session::testing::{MockBackend, MockSession, Op};
+//! use http::Request;
+//!
+//! # fn main() {
+//! let backend = MockBackend::with_value("41");
+//!
+//! let mut runner = test::runner({
+//!     path!(@get /)
+//!         .and(backend.clone())
+//!         .and_then(|session: Session<MockSession>| {
+//!             session.with(|session| {
+//!                 let count: usize = session.get().map_or(0, |s| s.parse().unwrap());
+//!                 session.set((count + 1).to_string());
+//!                 Ok("done")
+//!             })
+//!         })
+//! });
+//!
+//! runner
+//!     .perform(Request::get("/").header("host", "localhost:3000"))
+//!     .unwrap();
+//!
+//! assert_eq!(backend.value(), Some("42".into()));
+//! assert_eq!(backend.ops(), vec![Op::Get, Op::Set("42".into()), Op::Write]);
+//! # }
+//! ```
+
+extern crate cookie;
+
+pub mod conformance;
+
+use finchers::endpoint::{ApplyContext, ApplyResult, Endpoint};
+use finchers::error::Error;
+use finchers::input::Input;
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use self::cookie::Cookie;
+use futures::future;
+use http::header::{COOKIE, SET_COOKIE};
+use http::request;
+use http::Response;
+
+use session::{RawSession, Session};
+
+/// An operation applied to `MockSession`.
+///
+/// Note that `Session` reads the raw session value not only in `Session::get`,
+/// but also when it modifies the value (e.g. `Session::set`, `Session::remove`
+/// and `Session::flash`) in order to keep the metadata stored together with
+/// the value. Such reads are also recorded as `Op::Get`, so the recorded
+/// operations may contain more `Op::Get`s than the calls of `Session::get`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// `RawSession::get` was called.
+    Get,
+    /// `RawSession::set` was called with the value.
+    Set(String),
+    /// `RawSession::remove` was called.
+    Remove,
+    /// `RawSession::write` was called.
+    Write,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    value: Option<String>,
+    ops: Vec<Op>,
+}
+
+/// An endpoint which creates `MockSession`s sharing a session value.
+///
+/// The value written by a request is visible to the subsequent requests,
+/// as if the client sends back the session cookie.
+#[derive(Debug, Clone, Default)]
+pub struct MockBackend {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockBackend {
+    /// Create a new `MockBackend` without the session value.
+    pub fn new() -> MockBackend {
+        MockBackend::default()
+    }
+
+    /// Create a new `MockBackend` pre-seeded with the specified session value.
+    pub fn with_value(value: impl Into<String>) -> MockBackend {
+        let backend = MockBackend::new();
+        backend.set_value(Some(value.into()));
+        backend
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().expect("the mutex has been poisoned")
+    }
+
+    /// Returns the current session value.
+    pub fn value(&self) -> Option<String> {
+        self.state().value.clone()
+    }
+
+    /// Replaces the current session value.
+    pub fn set_value(&self, value: Option<String>) {
+        self.state().value = value;
+    }
+
+    /// Returns the operations applied to the sessions created by this backend.
+    pub fn ops(&self) -> Vec<Op> {
+        self.state().ops.clone()
+    }
+
+    /// Clears the recorded operations.
+    pub fn clear_ops(&self) {
+        self.state().ops.clear();
+    }
+}
+
+impl<'a> Endpoint<'a> for MockBackend {
+    type Output = (Session<MockSession>,);
+    type Future = future::FutureResult<Self::Output, Error>;
+
+    fn apply(&self, _: &mut ApplyContext<'_>) -> ApplyResult<Self::Future> {
+        Ok(future::ok((Session::new(MockSession {
+            state: self.state.clone(),
+            value: self.value(),
+        }),)))
+    }
+}
+
+/// The type of raw session created by `MockBackend`.
+#[derive(Debug)]
+pub struct MockSession {
+    state: Arc<Mutex<MockState>>,
+    value: Option<String>,
+}
+
+impl MockSession {
+    fn register(&self, op: Op) {
+        self.state
+            .lock()
+            .expect("the mutex has been poisoned")
+            .ops
+            .push(op);
+    }
+}
+
+impl RawSession for MockSession {
+    type WriteFuture = future::FutureResult<(), Error>;
+
+    fn get(&self) -> Option<&str> {
+        self.register(Op::Get);
+        self.value.as_ref().map(|s| s.as_str())
+    }
+
+    fn set(&mut self, value: String) {
+        self.register(Op::Set(value.clone()));
+        self.value = Some(value);
+    }
+
+    fn remove(&mut self) {
+        self.register(Op::Remove);
+        self.value = None;
+    }
+
+    fn write(self, _: &mut Input) -> Self::WriteFuture {
+        self.register(Op::Write);
+        self.state.lock().expect("the mutex has been poisoned").value = self.value;
+        future::ok(())
+    }
+}
+
+/// A helper trait for attaching a session cookie to the test request.
+pub trait RequestBuilderExt {
+    /// Appends a `Cookie` header with the specified entry.
+    fn session_cookie(&mut self, name: &str, value: &str) -> &mut Self;
+}
+
+impl RequestBuilderExt for request::Builder {
+    fn session_cookie(&mut self, name: &str, value: &str) -> &mut request::Builder {
+        self.header(COOKIE, Cookie::new(name, value).to_string().as_str())
+    }
+}
+
+/// Returns the Cookie entry with the specified name in `Set-Cookie` headers
+/// of the response, if any.
+pub fn set_cookie<T>(response: &Response<T>, name: &str) -> Option<Cookie<'static>> {
+    response
+        .headers()
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter_map(|value| Cookie::parse(value.to_owned()).ok())
+        .find(|cookie| cookie.name() == name)
+}
+
+/// Asserts that the response sets the Cookie entry with the specified name,
+/// and returns the entry.
+///
+/// # Panics
+///
+/// This function panics if the entry is not set or is removed by the response.
+pub fn assert_set_cookie<T>(response: &Response<T>, name: &str) -> Cookie<'static> {
+    let cookie = set_cookie(response, name)
+        .unwrap_or_else(|| panic!("the response does not set the Cookie `{}`", name));
+    assert!(
+        !is_removal(&cookie),
+        "the response removes the Cookie `{}`",
+        name
+    );
+    cookie
+}
+
+/// Asserts that the response does not contain the Cookie entry with the specified name.
+///
+/// # Panics
+///
+/// This function panics if the response sets or removes the entry.
+pub fn assert_no_set_cookie<T>(response: &Response<T>, name: &str) {
+    if let Some(cookie) = set_cookie(response, name) {
+        panic!("the response unexpectedly contains the Cookie: {}", cookie);
+    }
+}
+
+/// Asserts that the response removes the Cookie entry with the specified name.
+///
+/// # Panics
+///
+/// This function panics if the response does not remove the entry.
+pub fn assert_cookie_removed<T>(response: &Response<T>, name: &str) {
+    match set_cookie(response, name) {
+        Some(ref cookie) if is_removal(cookie) => {}
+        Some(cookie) => panic!("the response sets the Cookie instead of removing: {}", cookie),
+        None => panic!("the response does not remove the Cookie `{}`", name),
+    }
+}
+
+/// Returns `true` if the Cookie entry in `Set-Cookie` header removes the entry.
+fn is_removal(cookie: &Cookie<'_>) -> bool {
+    cookie.value().is_empty() && cookie.max_age().map_or(false, |age| age.num_seconds() <= 0)
+}
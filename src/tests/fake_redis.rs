@@ -0,0 +1,601 @@
+//! An in-process fake Redis server, which implements a subset of RESP and
+//! the commands used by `RedisStore` and `listen_invalidation`.
+//!
+//! The injected failures of the commands queued in a transaction are reported
+//! when queueing, and abort the transaction as Redis does for invalid commands.
+//! `WATCH` is supported by versioning the keys on every write command (even if
+//! it does not change the value), which may abort more transactions than Redis.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use redis::Client;
+
+#[derive(Debug)]
+enum Value {
+    Str(String),
+    ZSet(Vec<(u64, String)>),
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: Value,
+    expires_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+enum Reply {
+    Nil,
+    Status(&'static str),
+    Error(String),
+    Int(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        match *self {
+            Reply::Nil => buf.extend(b"*-1\r\n"),
+            Reply::Status(status) => buf.extend(format!("+{}\r\n", status).bytes()),
+            Reply::Error(ref message) => buf.extend(format!("-{}\r\n", message).bytes()),
+            Reply::Int(n) => buf.extend(format!(":{}\r\n", n).bytes()),
+            Reply::Bulk(None) => buf.extend(b"$-1\r\n"),
+            Reply::Bulk(Some(ref s)) => buf.extend(format!("${}\r\n{}\r\n", s.len(), s).bytes()),
+            Reply::Array(ref items) => {
+                buf.extend(format!("*{}\r\n", items.len()).bytes());
+                for item in items {
+                    item.write_to(buf);
+                }
+            }
+        }
+    }
+}
+
+// The commands queued after `MULTI` on a connection.
+#[derive(Debug, Default)]
+struct Transaction {
+    commands: Vec<Vec<String>>,
+    // Set if any command has been rejected while queueing.
+    aborted: bool,
+}
+
+// The state of each connection.
+#[derive(Debug, Default)]
+struct Conn {
+    transaction: Option<Transaction>,
+    // The keys watched by `WATCH`, with their versions at that time.
+    watched: Vec<(String, u64)>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: HashMap<String, Entry>,
+    // Incremented on every write to the key, used for `WATCH`.
+    versions: HashMap<String, u64>,
+    commands: Vec<Vec<String>>,
+    failing: Vec<String>,
+    hanging: Vec<String>,
+    // The commands run just before the first command with the name.
+    injected: Vec<(String, Vec<String>)>,
+    elapsed: Duration,
+    connections: usize,
+    // All accepted connections, used to close them from the test.
+    clients: Vec<TcpStream>,
+    // The connections subscribing each channel.
+    subscribers: Vec<(String, TcpStream)>,
+}
+
+impl State {
+    fn now(&self) -> Instant {
+        Instant::now() + self.elapsed
+    }
+
+    fn purge_expired(&mut self) {
+        let now = self.now();
+        let expired: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|&(_, entry)| entry.expires_at.map_or(false, |expires_at| expires_at <= now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.entries.remove(&key);
+            self.touch(&key);
+        }
+    }
+
+    fn version(&self, key: &str) -> u64 {
+        self.versions.get(key).cloned().unwrap_or(0)
+    }
+
+    fn touch(&mut self, key: &str) {
+        *self.versions.entry(key.to_owned()).or_insert(0) += 1;
+    }
+
+    fn expire_after(&self, secs: &str) -> Result<Option<Instant>, Reply> {
+        let secs: u64 = secs
+            .parse()
+            .map_err(|_| Reply::Error("ERR value is not an integer".into()))?;
+        Ok(Some(self.now() + Duration::from_secs(secs)))
+    }
+
+    fn set(&mut self, key: &str, value: &str, expires_at: Option<Instant>) {
+        self.entries.insert(
+            key.to_owned(),
+            Entry {
+                value: Value::Str(value.to_owned()),
+                expires_at,
+            },
+        );
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>, Reply> {
+        match self.entries.get(key).map(|entry| &entry.value) {
+            Some(&Value::Str(ref s)) => Ok(Some(s.clone())),
+            Some(..) => Err(wrong_type()),
+            None => Ok(None),
+        }
+    }
+
+    fn zset_mut(&mut self, key: &str) -> Result<&mut Vec<(u64, String)>, Reply> {
+        let entry = self.entries.entry(key.to_owned()).or_insert_with(|| Entry {
+            value: Value::ZSet(vec![]),
+            expires_at: None,
+        });
+        match entry.value {
+            Value::ZSet(ref mut members) => Ok(members),
+            _ => Err(wrong_type()),
+        }
+    }
+
+    // Records the command, and returns the reply of the injected failure.
+    // `Err(None)` means that the command never replies.
+    fn accept(&mut self, args: &[String]) -> Result<(), Option<Reply>> {
+        let name = args[0].to_uppercase();
+        let (injected, rest) = mem::replace(&mut self.injected, vec![])
+            .into_iter()
+            .partition(|&(ref trigger, _)| *trigger == name);
+        self.injected = rest;
+        for (_, injected) in injected {
+            self.run(&injected);
+        }
+        self.commands.push(args.to_vec());
+        if self.hanging.contains(&name) {
+            return Err(None);
+        }
+        if self.failing.contains(&name) {
+            return Err(Some(Reply::Error("ERR injected failure".into())));
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, args: &[String]) -> Reply {
+        self.purge_expired();
+        let name = args[0].to_uppercase();
+        match self.execute_command(&name, &args[1..]) {
+            Ok(reply) => {
+                let written: &[String] = match &name[..] {
+                    "SET" | "SETEX" | "EXPIRE" | "ZADD" | "ZREM" => &args[1..2],
+                    "DEL" => &args[1..],
+                    _ => &[],
+                };
+                for key in written {
+                    self.touch(key);
+                }
+                reply
+            }
+            Err(reply) => reply,
+        }
+    }
+
+    // Returns `None` if the command never replies.
+    fn execute(&mut self, args: &[String], conn: &mut Conn) -> Option<Reply> {
+        let name = args[0].to_uppercase();
+        match (&name[..], conn.transaction.take()) {
+            ("WATCH", None) if args.len() >= 2 => {
+                self.commands.push(args.to_vec());
+                self.purge_expired();
+                for key in &args[1..] {
+                    let version = self.version(key);
+                    conn.watched.push((key.clone(), version));
+                }
+                Some(Reply::Status("OK"))
+            }
+            ("UNWATCH", None) => {
+                self.commands.push(args.to_vec());
+                conn.watched.clear();
+                Some(Reply::Status("OK"))
+            }
+            ("MULTI", None) => {
+                self.commands.push(args.to_vec());
+                conn.transaction = Some(Transaction::default());
+                Some(Reply::Status("OK"))
+            }
+            ("EXEC", Some(queued)) => {
+                self.commands.push(args.to_vec());
+                self.purge_expired();
+                let watched = mem::replace(&mut conn.watched, vec![]);
+                if queued.aborted {
+                    return Some(Reply::Error(
+                        "EXECABORT Transaction discarded because of previous errors.".into(),
+                    ));
+                }
+                if watched
+                    .iter()
+                    .any(|&(ref key, version)| self.version(key) != version)
+                {
+                    return Some(Reply::Nil);
+                }
+                Some(Reply::Array(
+                    queued.commands.iter().map(|args| self.run(args)).collect(),
+                ))
+            }
+            (_, Some(mut queued)) => {
+                let reply = match self.accept(args) {
+                    Ok(()) => {
+                        queued.commands.push(args.to_vec());
+                        Some(Reply::Status("QUEUED"))
+                    }
+                    Err(reply) => {
+                        queued.aborted |= reply.is_some();
+                        reply
+                    }
+                };
+                conn.transaction = Some(queued);
+                reply
+            }
+            (_, None) => match self.accept(args) {
+                Ok(()) => Some(self.run(args)),
+                Err(reply) => reply,
+            },
+        }
+    }
+
+    fn execute_command(&mut self, name: &str, args: &[String]) -> Result<Reply, Reply> {
+        match (name, args) {
+            ("PING", []) => Ok(Reply::Status("PONG")),
+            ("GET", [key]) => Ok(Reply::Bulk(self.get(key)?)),
+            ("MGET", keys) if !keys.is_empty() => Ok(Reply::Array(
+                keys.iter()
+                    .map(|key| Reply::Bulk(self.get(key).unwrap_or(None)))
+                    .collect(),
+            )),
+            ("SET", [key, value]) => {
+                self.set(key, value, None);
+                Ok(Reply::Status("OK"))
+            }
+            ("SETEX", [key, secs, value]) => {
+                let expires_at = self.expire_after(secs)?;
+                self.set(key, value, expires_at);
+                Ok(Reply::Status("OK"))
+            }
+            ("DEL", keys) if !keys.is_empty() => {
+                let removed = keys
+                    .iter()
+                    .filter(|key| self.entries.remove(&key[..]).is_some())
+                    .count();
+                Ok(Reply::Int(removed as i64))
+            }
+            ("EXPIRE", [key, secs]) => {
+                let expires_at = self.expire_after(secs)?;
+                match self.entries.get_mut(&key[..]) {
+                    Some(entry) => {
+                        entry.expires_at = expires_at;
+                        Ok(Reply::Int(1))
+                    }
+                    None => Ok(Reply::Int(0)),
+                }
+            }
+            ("ZADD", [key, nx, score, member]) if nx.eq_ignore_ascii_case("NX") => {
+                let score: u64 = score
+                    .parse()
+                    .map_err(|_| Reply::Error("ERR value is not a valid float".into()))?;
+                let members = self.zset_mut(key)?;
+                if members.iter().any(|&(_, ref m)| m == member) {
+                    return Ok(Reply::Int(0));
+                }
+                members.push((score, member.clone()));
+                members.sort();
+                Ok(Reply::Int(1))
+            }
+            ("ZRANGE", [key, start, stop]) => {
+                let (start, stop): (i64, i64) = match (start.parse(), stop.parse()) {
+                    (Ok(start), Ok(stop)) => (start, stop),
+                    _ => return Err(Reply::Error("ERR value is not an integer".into())),
+                };
+                let members: Vec<String> = match self.entries.get(&key[..]) {
+                    Some(&Entry {
+                        value: Value::ZSet(ref members),
+                        ..
+                    }) => members.iter().map(|&(_, ref m)| m.clone()).collect(),
+                    Some(..) => return Err(wrong_type()),
+                    None => vec![],
+                };
+                let len = members.len() as i64;
+                let index = |i: i64| if i < 0 { len + i } else { i };
+                let (start, stop) = (index(start).max(0), index(stop).min(len - 1));
+                Ok(Reply::Array(
+                    members
+                        .into_iter()
+                        .enumerate()
+                        .filter(|&(i, _)| i as i64 >= start && i as i64 <= stop)
+                        .map(|(_, m)| Reply::Bulk(Some(m)))
+                        .collect(),
+                ))
+            }
+            ("ZREM", args) if args.len() >= 2 => {
+                let (removed, is_empty) = {
+                    let members = self.zset_mut(&args[0])?;
+                    let len = members.len();
+                    members.retain(|&(_, ref m)| !args[1..].contains(m));
+                    (len - members.len(), members.is_empty())
+                };
+                // The empty sorted set is removed as Redis does.
+                if is_empty {
+                    self.entries.remove(&args[0]);
+                }
+                Ok(Reply::Int(removed as i64))
+            }
+            ("PUBLISH", [channel, message]) => {
+                let mut buf = vec![];
+                Reply::Array(vec![
+                    Reply::Bulk(Some("message".into())),
+                    Reply::Bulk(Some(channel.clone())),
+                    Reply::Bulk(Some(message.clone())),
+                ]).write_to(&mut buf);
+                let mut received = 0;
+                self.subscribers.retain(|&(ref ch, ref stream)| {
+                    if ch != channel {
+                        return true;
+                    }
+                    let mut stream: &TcpStream = stream;
+                    let delivered = stream.write_all(&buf).is_ok();
+                    if delivered {
+                        received += 1;
+                    }
+                    delivered
+                });
+                Ok(Reply::Int(received))
+            }
+            ("SCAN", [_cursor, m, pattern, c, _count])
+                if m.eq_ignore_ascii_case("MATCH") && c.eq_ignore_ascii_case("COUNT") =>
+            {
+                // All matched keys are returned in a single page.
+                let prefix = pattern.trim_end_matches('*');
+                let mut keys: Vec<String> = self
+                    .entries
+                    .keys()
+                    .filter(|key| key.starts_with(prefix))
+                    .cloned()
+                    .collect();
+                keys.sort();
+                Ok(Reply::Array(vec![
+                    Reply::Bulk(Some("0".into())),
+                    Reply::Array(keys.into_iter().map(|key| Reply::Bulk(Some(key))).collect()),
+                ]))
+            }
+            (name, _) => Err(Reply::Error(format!(
+                "ERR unknown command or wrong number of arguments for '{}'",
+                name
+            ))),
+        }
+    }
+}
+
+fn wrong_type() -> Reply {
+    Reply::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())
+}
+
+/// A fake Redis server listening on an ephemeral port.
+#[derive(Debug, Clone)]
+pub struct FakeRedis {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeRedis {
+    /// Starts a new server in background threads.
+    pub fn start() -> FakeRedis {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::default()));
+
+        thread::spawn({
+            let state = state.clone();
+            move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(..) => return,
+                    };
+                    {
+                        let mut state = state.lock().unwrap();
+                        state.connections += 1;
+                        if let Ok(client) = stream.try_clone() {
+                            state.clients.push(client);
+                        }
+                    }
+                    let state = state.clone();
+                    thread::spawn(move || {
+                        let _ = serve(stream, &state);
+                    });
+                }
+            }
+        });
+
+        FakeRedis { addr, state }
+    }
+
+    /// Creates a client connected to this server.
+    pub fn client(&self) -> Client {
+        Client::open(&format!("redis://{}/", self.addr)[..]).unwrap()
+    }
+
+    /// Returns the commands received so far (without `PING`s).
+    pub fn commands(&self) -> Vec<Vec<String>> {
+        self.state
+            .lock()
+            .unwrap()
+            .commands
+            .iter()
+            .filter(|args| !args[0].eq_ignore_ascii_case("PING"))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the names of the commands received so far.
+    pub fn command_names(&self) -> Vec<String> {
+        self.commands()
+            .into_iter()
+            .map(|args| args[0].to_uppercase())
+            .collect()
+    }
+
+    /// Makes the specified command fail with an error reply.
+    pub fn fail_command(&self, name: &str) {
+        self.state.lock().unwrap().failing.push(name.to_uppercase());
+    }
+
+    /// Makes the specified command never reply.
+    pub fn hang_command(&self, name: &str) {
+        self.state.lock().unwrap().hanging.push(name.to_uppercase());
+    }
+
+    /// Runs the specified command (as if sent by another client) just before
+    /// the next command with the name is received.
+    pub fn run_before(&self, name: &str, args: &[&str]) {
+        self.state.lock().unwrap().injected.push((
+            name.to_uppercase(),
+            args.iter().map(|&arg| arg.to_owned()).collect(),
+        ));
+    }
+
+    /// Advances the clock of the server, used for the expiration of keys.
+    pub fn advance(&self, duration: Duration) {
+        self.state.lock().unwrap().elapsed += duration;
+    }
+
+    /// Returns the number of connections accepted so far.
+    pub fn connection_count(&self) -> usize {
+        self.state.lock().unwrap().connections
+    }
+
+    /// Returns the number of connections subscribing the specified channel.
+    pub fn subscriber_count(&self, channel: &str) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .subscribers
+            .iter()
+            .filter(|&&(ref ch, _)| ch == channel)
+            .count()
+    }
+
+    /// Closes all subscribing connections.
+    pub fn disconnect_subscribers(&self) {
+        for (_, stream) in self.state.lock().unwrap().subscribers.drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Closes all connections accepted so far, as a restarted server does.
+    pub fn disconnect_clients(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.subscribers.clear();
+        for stream in state.clients.drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Returns `true` if the specified key exists.
+    pub fn contains_key(&self, key: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.purge_expired();
+        state.entries.contains_key(key)
+    }
+}
+
+/// Returns an address on which no server is listening.
+pub fn unused_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+fn serve(stream: TcpStream, state: &Mutex<State>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut conn = Conn::default();
+    while let Some(args) = read_command(&mut reader)? {
+        let mut buf = vec![];
+        if args.is_empty() {
+            Reply::Error("ERR empty command".into()).write_to(&mut buf);
+        } else if args[0].eq_ignore_ascii_case("SUBSCRIBE") && args.len() == 2 {
+            // The reply is written while holding the lock, so that it is not
+            // interleaved with the messages published by other connections.
+            let mut state = state.lock().unwrap();
+            state.commands.push(args.clone());
+            Reply::Array(vec![
+                Reply::Bulk(Some("subscribe".into())),
+                Reply::Bulk(Some(args[1].clone())),
+                Reply::Int(1),
+            ]).write_to(&mut buf);
+            writer.write_all(&buf)?;
+            state.subscribers.push((args[1].clone(), writer.try_clone()?));
+            continue;
+        } else {
+            match state.lock().unwrap().execute(&args, &mut conn) {
+                Some(reply) => reply.write_to(&mut buf),
+                None => continue,
+            }
+        }
+        writer.write_all(&buf)?;
+    }
+    Ok(())
+}
+
+fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<String>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let len = parse_header(&line, '*')?;
+    let mut args = Vec::with_capacity(len);
+    for _ in 0..len {
+        let line = read_line(reader)?.ok_or_else(|| invalid_data("unexpected EOF"))?;
+        let len = parse_header(&line, '$')?;
+        let mut data = vec![0; len + 2];
+        reader.read_exact(&mut data)?;
+        data.truncate(len);
+        args.push(String::from_utf8(data).map_err(|_| invalid_data("invalid UTF-8"))?);
+    }
+    Ok(Some(args))
+}
+
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(line.trim_end().to_owned()))
+}
+
+fn parse_header(line: &str, prefix: char) -> io::Result<usize> {
+    if !line.starts_with(prefix) {
+        return Err(invalid_data("unexpected RESP type"));
+    }
+    line[1..]
+        .parse()
+        .map_err(|_| invalid_data("invalid length"))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,2280 @@
+#[cfg(feature = "redis")]
+mod fake_redis;
+#[cfg(feature = "redis")]
+mod redis_store;
+
+use finchers::error::Error;
+use finchers::input::Input;
+use finchers::prelude::*;
+use finchers::test;
+
+use futures::{future, Future};
+use http::{Request, Response};
+use uuid::Uuid;
+
+use csrf::{Csrf, CsrfForm, CsrfToken};
+use flash::{Flash, FlashLevel};
+use in_memory::{InMemoryBackend, InMemorySession};
+use session::{RawSession, Session};
+use store::{IdTransport, SessionStore};
+use testing::{assert_cookie_removed, assert_no_set_cookie, assert_set_cookie, RequestBuilderExt};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Op {
+    Get,
+    Set(String),
+    Remove,
+    Write,
+}
+
+#[derive(Default)]
+struct CallChain {
+    chain: RefCell<Vec<Op>>,
+}
+
+impl CallChain {
+    fn register(&self, op: Op) {
+        self.chain.borrow_mut().push(op);
+    }
+
+    fn result(&self) -> Vec<Op> {
+        self.chain.borrow().clone()
+    }
+}
+
+struct MockSession {
+    call_chain: Rc<CallChain>,
+}
+
+impl RawSession for MockSession {
+    type WriteFuture = future::FutureResult<(), Error>;
+
+    fn get(&self) -> Option<&str> {
+        self.call_chain.register(Op::Get);
+        None
+    }
+
+    fn set(&mut self, value: String) {
+        self.call_chain.register(Op::Set(value));
+    }
+
+    fn remove(&mut self) {
+        self.call_chain.register(Op::Remove);
+    }
+
+    fn write(self, _: &mut Input) -> Self::WriteFuture {
+        self.call_chain.register(Op::Write);
+        future::ok(())
+    }
+}
+
+#[test]
+fn test_session_with() {
+    let call_chain = Rc::new(CallChain::default());
+
+    let mut runner = test::runner({
+        let session_endpoint = endpoint::apply({
+            let call_chain = call_chain.clone();
+            move |_cx| {
+                Ok(Ok(Session::new(MockSession {
+                    call_chain: call_chain.clone(),
+                })))
+            }
+        });
+        let endpoint = session_endpoint.and_then(|session: Session<MockSession>| {
+            session.with(|session| {
+                session.get();
+                session.set("foo");
+                session.remove();
+                Ok("done")
+            })
+        });
+
+        endpoint
+    });
+
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    assert!(!response.headers().contains_key("set-cookie"));
+
+    assert_eq!(
+        call_chain.result(),
+        vec![Op::Get, Op::Set("foo".into()), Op::Remove, Op::Write,]
+    );
+}
+
+#[test]
+fn test_testing_mock_backend() {
+    use testing::{MockBackend, MockSession, Op};
+
+    let backend = MockBackend::new();
+    let mut runner = test::runner({
+        backend
+            .clone()
+            .and_then(|session: Session<MockSession>| {
+                session.with(|session| {
+                    session.get();
+                    session.set("foo");
+                    session.remove();
+                    Ok("done")
+                })
+            })
+    });
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    assert!(!response.headers().contains_key("set-cookie"));
+    assert_eq!(
+        backend.ops(),
+        vec![Op::Get, Op::Set("foo".into()), Op::Remove, Op::Write]
+    );
+    assert_eq!(backend.value(), None);
+}
+
+#[test]
+fn test_testing_mock_backend_reads_before_first_write() {
+    use testing::{MockBackend, MockSession, Op};
+
+    // `Session` reads the raw value once before modifying it in order to keep
+    // the metadata (e.g. flash messages), which is recorded as `Op::Get`.
+    let backend = MockBackend::with_value("foo");
+    let mut runner = test::runner({
+        backend
+            .clone()
+            .and_then(|session: Session<MockSession>| {
+                session.with(|session| {
+                    session.set("bar");
+                    session.set("baz");
+                    Ok("done")
+                })
+            })
+    });
+    runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    assert_eq!(
+        backend.ops(),
+        vec![
+            Op::Get,
+            Op::Set("bar".into()),
+            Op::Set("baz".into()),
+            Op::Write,
+        ]
+    );
+    assert_eq!(backend.value(), Some("baz".into()));
+}
+
+fn session_id_from_set_cookie<T>(response: &Response<T>) -> Uuid {
+    assert_set_cookie(response, "session-id")
+        .value()
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn test_store_backend_round_trip() {
+    let backend = InMemoryBackend::default();
+
+    let mut runner = test::runner({
+        backend
+            .clone()
+            .and_then(|session: Session<InMemorySession>| {
+                session.with(|session| {
+                    let count: usize = session.get().map_or(0, |s| s.parse().unwrap());
+                    session.set((count + 1).to_string());
+                    Ok("done")
+                })
+            })
+    });
+
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    let session_id = session_id_from_set_cookie(&response);
+    assert_eq!(
+        backend.store().load(&session_id).wait().unwrap(),
+        Some("1".into())
+    );
+
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", &session_id.to_string()),
+        )
+        .unwrap();
+    assert_set_cookie(&response, "session-id");
+    assert_eq!(
+        backend.store().load(&session_id).wait().unwrap(),
+        Some("2".into())
+    );
+}
+
+#[test]
+fn test_store_backend_header_transport() {
+    let backend = InMemoryBackend::default().transport(IdTransport::bearer());
+
+    let mut runner = test::runner({
+        backend
+            .clone()
+            .and_then(|session: Session<InMemorySession>| {
+                session.with(|session| {
+                    session.set("foo");
+                    Ok("done")
+                })
+            })
+    });
+
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    assert!(!response.headers().contains_key("set-cookie"));
+    let session_id: Uuid = response.headers()["x-session-id"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .header("authorization", format!("Bearer {}", session_id)),
+        )
+        .unwrap();
+    assert_eq!(
+        response.headers()["x-session-id"],
+        session_id.to_string().as_str()
+    );
+}
+
+#[cfg(feature = "jwt")]
+fn jwt_handler(
+    session: Session<::jwt::JwtSession>,
+) -> impl Future<Item = String, Error = Error> {
+    session.with(|session| {
+        let count: usize = session.get().map_or(0, |s| s.parse().unwrap());
+        session.set((count + 1).to_string());
+        Ok(count.to_string())
+    })
+}
+
+#[cfg(feature = "jwt")]
+#[test]
+fn test_jwt_round_trip() {
+    use jwt::{JwtBackend, JwtSession};
+    use std::sync::{Arc, Mutex};
+
+    let counts = Arc::new(Mutex::new(vec![]));
+    let mut runner = test::runner({
+        let counts = counts.clone();
+        JwtBackend::new("secret").and_then(move |session: Session<JwtSession>| {
+            let counts = counts.clone();
+            session.with(move |session| {
+                let count: usize = session.get().map_or(0, |s| s.parse().unwrap());
+                counts.lock().unwrap().push(count);
+                session.set((count + 1).to_string());
+                Ok("done")
+            })
+        })
+    });
+
+    // The token is exchanged via the bearer token and the response header.
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(!response.headers().contains_key("set-cookie"));
+    let token = response.headers()["x-session-token"]
+        .to_str()
+        .unwrap()
+        .to_owned();
+    assert_eq!(token.split('.').count(), 3);
+
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .header("authorization", format!("Bearer {}", token)),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let token = response.headers()["x-session-token"]
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .header("authorization", format!("Bearer {}", token)),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(*counts.lock().unwrap(), vec![0, 1, 2]);
+}
+
+#[cfg(feature = "jwt")]
+#[test]
+fn test_jwt_rejects_invalid_tokens() {
+    use error::InvalidValuePolicy;
+    use jwt::{HmacAlgorithm, JwtBackend};
+    use std::sync::{Arc, Mutex};
+
+    let mut runner = test::runner(JwtBackend::new("secret").and_then(jwt_handler));
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    let token = response.headers()["x-session-token"]
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    // By default, the invalid token is discarded and a fresh token is issued.
+    let rejected = Arc::new(Mutex::new(vec![]));
+    let mut runner = test::runner({
+        let rejected = rejected.clone();
+        JwtBackend::new("another-secret")
+            .on_invalid_value(move |err| rejected.lock().unwrap().push(err.to_string()))
+            .and_then(jwt_handler)
+    });
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .header("authorization", format!("Bearer {}", token)),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_ne!(response.headers()["x-session-token"], token.as_str());
+    assert_eq!(
+        *rejected.lock().unwrap(),
+        vec!["the session value has been tampered"]
+    );
+
+    // signed with another key.
+    let mut runner = test::runner(
+        JwtBackend::new("another-secret")
+            .invalid_value_policy(InvalidValuePolicy::Reject)
+            .and_then(jwt_handler),
+    );
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .header("authorization", format!("Bearer {}", token)),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+
+    // signed with another algorithm.
+    let mut runner = test::runner(
+        JwtBackend::new("secret")
+            .algorithm(HmacAlgorithm::HS512)
+            .invalid_value_policy(InvalidValuePolicy::Reject)
+            .and_then(jwt_handler),
+    );
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .header("authorization", format!("Bearer {}", token)),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+
+    // malformed.
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .header("authorization", "Bearer garbage"),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[cfg(feature = "jwt")]
+#[test]
+fn test_jwt_expired_token() {
+    use error::InvalidValuePolicy;
+    use jwt::JwtBackend;
+    use std::time::{Duration, SystemTime};
+
+    let backend = JwtBackend::new("secret").expires_in(Duration::from_secs(60));
+    let token = backend.issue_token("1", SystemTime::now() - Duration::from_secs(120));
+
+    let mut runner = test::runner(
+        backend()
+            .invalid_value_policy(InvalidValuePolicy::Reject)
+            .and_then(jwt_handler),
+    );
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .header("authorization", format!("Bearer {}", token)),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+
+    let mut runner = test::runner(
+        JwtBackend::new("secret")
+            .leeway(Duration::from_secs(120))
+            .invalid_value_policy(InvalidValuePolicy::Reject)
+            .and_then(jwt_handler),
+    );
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .header("authorization", format!("Bearer {}", token)),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[cfg(feature = "jwt")]
+#[test]
+fn test_jwt_eddsa() {
+    use error::InvalidValuePolicy;
+    use jwt::{Ed25519SigningKey, JwtBackend};
+    use std::time::SystemTime;
+
+    let signing_key = Ed25519SigningKey::from_seed(&[0x42; 32]);
+    let verifying_key = signing_key.public_key();
+    let mut runner = test::runner(JwtBackend::eddsa(signing_key).and_then(jwt_handler));
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    let token = response.headers()["x-session-token"]
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    // The token can be read with the public key only.
+    let mut runner = test::runner(
+        JwtBackend::eddsa_verifier(verifying_key)
+            .invalid_value_policy(InvalidValuePolicy::Reject)
+            .and_then(|session: Session<::jwt::JwtSession>| {
+                session.with(|session| Ok(session.get().unwrap_or("").to_owned()))
+            }),
+    );
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .header("authorization", format!("Bearer {}", token)),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    // ... but no token can be issued without the private key.
+    let mut runner = test::runner(JwtBackend::eddsa_verifier(verifying_key).and_then(jwt_handler));
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 500);
+
+    // signed with another key.
+    let another_key = Ed25519SigningKey::from_seed(&[0x43; 32]).public_key();
+    let mut runner = test::runner(
+        JwtBackend::eddsa_verifier(another_key)
+            .invalid_value_policy(InvalidValuePolicy::Reject)
+            .and_then(jwt_handler),
+    );
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .header("authorization", format!("Bearer {}", token)),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+
+    // signed with HMAC using the public key as the secret.
+    let forged = JwtBackend::new(&verifying_key.as_bytes()[..]).issue_token("1", SystemTime::now());
+    let mut runner = test::runner(
+        JwtBackend::eddsa_verifier(verifying_key)
+            .invalid_value_policy(InvalidValuePolicy::Reject)
+            .and_then(jwt_handler),
+    );
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .header("authorization", format!("Bearer {}", forged)),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[cfg(feature = "paseto")]
+#[test]
+fn test_paseto_key_round_trip() {
+    use paseto::PasetoKey;
+
+    let key = PasetoKey::new([0x42; 32]).with_id("k1");
+    let token = key.encrypt(b"session value", b"finchers-session");
+    assert!(token.starts_with("v4.local."));
+    assert_eq!(
+        key.decrypt(&token, b"finchers-session"),
+        Some(b"session value".to_vec())
+    );
+
+    // bound to the implicit assertion and the key id.
+    assert_eq!(key.decrypt(&token, b"another-cookie"), None);
+    let other = PasetoKey::new([0x42; 32]).with_id("k2");
+    assert_eq!(other.decrypt(&token, b"finchers-session"), None);
+}
+
+// The test vectors of `v4.local` from https://github.com/paseto-standard/test-vectors
+#[cfg(feature = "paseto")]
+#[test]
+fn test_paseto_v4_local_vectors() {
+    use paseto::PasetoKey;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&hex(
+        "707172737475767778797a7b7c7d7e7f808182838485868788898a8b8c8d8e8f",
+    ));
+    let secret = r#"{"data":"this is a secret message","exp":"2022-01-01T00:00:00+00:00"}"#;
+    let hidden = r#"{"data":"this is a hidden message","exp":"2022-01-01T00:00:00+00:00"}"#;
+    let kid = "zVhMiPBP9fRf2snEcT7gFTioeA9COcNy9DfgL1W60haN";
+
+    let vectors: &[(&str, &str, &str, Option<&str>, &str, &str)] = &[
+        (
+            "4-E-1",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            secret,
+            None,
+            "",
+            "v4.local.AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAQAr68PS4AXe7If_ZgesdkUMvSwscFlAl1pk5HC0e8kApeaqMfGo_7OpBnwJOAbY9V7WU6abu74MmcUE8YWAiaArVI8XJ5hOb_4v9RmDkneN0S92dx0OW4pgy7omxgf3S8c3LlQg",
+        ),
+        (
+            "4-E-2",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            hidden,
+            None,
+            "",
+            "v4.local.AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAQAr68PS4AXe7If_ZgesdkUMvS2csCgglvpk5HC0e8kApeaqMfGo_7OpBnwJOAbY9V7WU6abu74MmcUE8YWAiaArVI8XIemu9chy3WVKvRBfg6t8wwYHK0ArLxxfZP73W_vfwt5A",
+        ),
+        (
+            "4-E-3",
+            "df654812bac492663825520ba2f6e67cf5ca5bdc13d4e7507a98cc4c2fcc3ad8",
+            secret,
+            None,
+            "",
+            "v4.local.32VIErrEkmY4JVILovbmfPXKW9wT1OdQepjMTC_MOtjA4kiqw7_tcaOM5GNEcnTxl60WkwMsYXw6FSNb_UdJPXjpzm0KW9ojM5f4O2mRvE2IcweP-PRdoHjd5-RHCiExR1IK6t6-tyebyWG6Ov7kKvBdkrrAJ837lKP3iDag2hzUPHuMKA",
+        ),
+        (
+            "4-E-4",
+            "df654812bac492663825520ba2f6e67cf5ca5bdc13d4e7507a98cc4c2fcc3ad8",
+            hidden,
+            None,
+            "",
+            "v4.local.32VIErrEkmY4JVILovbmfPXKW9wT1OdQepjMTC_MOtjA4kiqw7_tcaOM5GNEcnTxl60WiA8rd3wgFSNb_UdJPXjpzm0KW9ojM5f4O2mRvE2IcweP-PRdoHjd5-RHCiExR1IK6t4gt6TiLm55vIH8c_lGxxZpE3AWlH4WTR0v45nsWoU3gQ",
+        ),
+        (
+            "4-E-5",
+            "df654812bac492663825520ba2f6e67cf5ca5bdc13d4e7507a98cc4c2fcc3ad8",
+            secret,
+            Some(kid),
+            "",
+            "v4.local.32VIErrEkmY4JVILovbmfPXKW9wT1OdQepjMTC_MOtjA4kiqw7_tcaOM5GNEcnTxl60WkwMsYXw6FSNb_UdJPXjpzm0KW9ojM5f4O2mRvE2IcweP-PRdoHjd5-RHCiExR1IK6t4x-RMNXtQNbz7FvFZ_G-lFpk5RG3EOrwDL6CgDqcerSQ.eyJraWQiOiJ6VmhNaVBCUDlmUmYyc25FY1Q3Z0ZUaW9lQTlDT2NOeTlEZmdMMVc2MGhhTiJ9",
+        ),
+        (
+            "4-E-6",
+            "df654812bac492663825520ba2f6e67cf5ca5bdc13d4e7507a98cc4c2fcc3ad8",
+            hidden,
+            Some(kid),
+            "",
+            "v4.local.32VIErrEkmY4JVILovbmfPXKW9wT1OdQepjMTC_MOtjA4kiqw7_tcaOM5GNEcnTxl60WiA8rd3wgFSNb_UdJPXjpzm0KW9ojM5f4O2mRvE2IcweP-PRdoHjd5-RHCiExR1IK6t4kEvgMl-11QjLXJnuaOcEq4v47wPicBSR6ivocJlY9xA.eyJraWQiOiJ6VmhNaVBCUDlmUmYyc25FY1Q3Z0ZUaW9lQTlDT2NOeTlEZmdMMVc2MGhhTiJ9",
+        ),
+        (
+            "4-E-7",
+            "df654812bac492663825520ba2f6e67cf5ca5bdc13d4e7507a98cc4c2fcc3ad8",
+            secret,
+            Some(kid),
+            r#"{"test-vector":"4-E-7"}"#,
+            "v4.local.32VIErrEkmY4JVILovbmfPXKW9wT1OdQepjMTC_MOtjA4kiqw7_tcaOM5GNEcnTxl60WkwMsYXw6FSNb_UdJPXjpzm0KW9ojM5f4O2mRvE2IcweP-PRdoHjd5-RHCiExR1IK6t40KCCWLA7GYL9KFHzKlwY9_RnIfRrMQpueydLEAZGGcA.eyJraWQiOiJ6VmhNaVBCUDlmUmYyc25FY1Q3Z0ZUaW9lQTlDT2NOeTlEZmdMMVc2MGhhTiJ9",
+        ),
+        (
+            "4-E-8",
+            "df654812bac492663825520ba2f6e67cf5ca5bdc13d4e7507a98cc4c2fcc3ad8",
+            hidden,
+            Some(kid),
+            r#"{"test-vector":"4-E-8"}"#,
+            "v4.local.32VIErrEkmY4JVILovbmfPXKW9wT1OdQepjMTC_MOtjA4kiqw7_tcaOM5GNEcnTxl60WiA8rd3wgFSNb_UdJPXjpzm0KW9ojM5f4O2mRvE2IcweP-PRdoHjd5-RHCiExR1IK6t5uvqQbMGlLLNYBc7A6_x7oqnpUK5WLvj24eE4DVPDZjw.eyJraWQiOiJ6VmhNaVBCUDlmUmYyc25FY1Q3Z0ZUaW9lQTlDT2NOeTlEZmdMMVc2MGhhTiJ9",
+        ),
+    ];
+
+    for &(name, nonce, payload, kid, implicit, token) in vectors {
+        let key = match kid {
+            Some(kid) => PasetoKey::new(key).with_id(kid),
+            None => PasetoKey::new(key),
+        };
+        let mut n = [0u8; 32];
+        n.copy_from_slice(&hex(nonce));
+
+        let encrypted = key.encrypt_with_nonce(payload.as_bytes(), implicit.as_bytes(), &n);
+        assert_eq!(encrypted, token, "{}", name);
+        assert_eq!(
+            key.decrypt(token, implicit.as_bytes()),
+            Some(payload.as_bytes().to_vec()),
+            "{}",
+            name
+        );
+    }
+}
+
+#[cfg(feature = "paseto")]
+#[test]
+fn test_cookie_backend_paseto() {
+    use cookie::CookieSession;
+    use paseto::PasetoKey;
+
+    let backend = ::cookie::CookieBackend::paseto(PasetoKey::new([0x42; 32]).with_id("k2"))
+        .paseto_previous_key(PasetoKey::new([0x24; 32]).with_id("k1"))
+        .name("session")
+        .secure(false);
+    let mut runner = test::runner({
+        backend
+            .clone()
+            .and_then(|session: Session<CookieSession>| {
+                session.with(|session| {
+                    let count: usize = session.get().map_or(0, |s| s.parse().unwrap());
+                    session.set((count + 1).to_string());
+                    Ok("done")
+                })
+            })
+    });
+
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    let cookie = assert_set_cookie(&response, "session");
+    assert!(cookie.value().starts_with("v4.local."));
+    assert_eq!(backend.decode_value(cookie.value()), Some("1".into()));
+
+    // The token is bound to the name of Cookie entry.
+    let other = ::cookie::CookieBackend::paseto(PasetoKey::new([0x42; 32]).with_id("k2"));
+    assert_eq!(other.decode_value(cookie.value()), None);
+
+    // The token encrypted with the previous key is accepted and re-encrypted.
+    let previous = ::cookie::CookieBackend::paseto(PasetoKey::new([0x24; 32]).with_id("k1"))
+        .name("session");
+    let old = previous.encode_value("5");
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session", old.value()),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let cookie = assert_set_cookie(&response, "session");
+    assert_eq!(backend.decode_value(cookie.value()), Some("6".into()));
+    assert_eq!(previous.decode_value(cookie.value()), None);
+
+    // The tampered token is discarded.
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session", &format!("{}x", cookie.value())),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let cookie = assert_set_cookie(&response, "session");
+    assert_eq!(backend.decode_value(cookie.value()), Some("1".into()));
+}
+
+#[test]
+fn test_csrf_guard() {
+    use std::sync::{Arc, Mutex};
+
+    let backend = InMemoryBackend::default();
+    let csrf = Csrf::new(backend.clone());
+    let token = Arc::new(Mutex::new(String::new()));
+    let mut runner = test::runner({
+        let token = token.clone();
+        let form = path!(@get / "form").and(csrf.clone()).map(move |t: CsrfToken| {
+            *token.lock().unwrap() = t.to_string();
+            "form"
+        });
+        let login = path!(@get / "login").and(backend.clone()).and_then(
+            |session: Session<InMemorySession>| {
+                session.with(|session| {
+                    session.login("alice");
+                    Ok("logged in")
+                })
+            },
+        );
+        let submit = path!(@post /).and(csrf.guard()).map(|| "submitted");
+        form.or(login).or(submit)
+    });
+
+    // The token is stored in the session of the application.
+    let response = runner
+        .perform(Request::get("/form").header("host", "localhost:3000"))
+        .unwrap();
+    let cookie = assert_set_cookie(&response, "session-id");
+    let issued = token.lock().unwrap().clone();
+    let raw = backend.store().load(&cookie.value().parse().unwrap()).wait().unwrap();
+    let (envelope, _) = ::envelope::decode(raw.as_ref().map(|s| s.as_str()));
+    assert_eq!(envelope.csrf_token, Some(issued.clone()));
+
+    let response = runner
+        .perform(
+            Request::post("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", cookie.value()),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+
+    let response = runner
+        .perform(
+            Request::post("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", cookie.value())
+                .header("x-csrf-token", issued.as_str()),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    // The token is rotated on login.
+    let response = runner
+        .perform(
+            Request::get("/login")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", cookie.value()),
+        ).unwrap();
+    let cookie = assert_set_cookie(&response, "session-id");
+    runner
+        .perform(
+            Request::get("/form")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", cookie.value()),
+        ).unwrap();
+    let rotated = token.lock().unwrap().clone();
+    assert_ne!(rotated, issued);
+
+    let response = runner
+        .perform(
+            Request::post("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", cookie.value())
+                .header("x-csrf-token", issued.as_str()),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+}
+
+#[test]
+fn test_csrf_guard_form_field() {
+    use std::sync::{Arc, Mutex};
+
+    let backend = InMemoryBackend::default();
+    let csrf = Csrf::new(backend.clone());
+    let token = Arc::new(Mutex::new(String::new()));
+    let mut runner = test::runner({
+        let submit = path!(@post /)
+            .and(csrf.form_guard())
+            .map(|form: CsrfForm| {
+                assert_eq!(form.get("name"), Some("John Doe"));
+                "submitted"
+            });
+        let token = token.clone();
+        let form = path!(@get /).and(csrf.clone()).map(move |t: CsrfToken| {
+            *token.lock().unwrap() = t.to_string();
+            "form"
+        });
+        form.or(submit)
+    });
+
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    let cookie = assert_set_cookie(&response, "session-id");
+    let issued = token.lock().unwrap().clone();
+
+    let response = runner
+        .perform(
+            Request::post("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", cookie.value())
+                .header("content-type", "application/x-www-form-urlencoded")
+                .body("csrf-token=invalid&name=x".to_owned())
+                .unwrap(),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+
+    let response = runner
+        .perform(
+            Request::post("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", cookie.value())
+                .header("content-type", "application/x-www-form-urlencoded")
+                .body(format!("csrf-token={}&name=John+Doe", issued))
+                .unwrap(),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[test]
+fn test_flash_messages() {
+    let backend = InMemoryBackend::default();
+
+    let mut runner = test::runner({
+        let add = path!(@post /).and(backend.clone()).and_then(
+            |session: Session<InMemorySession>| {
+                session.with(|session| {
+                    session.set("value");
+                    session.flash(FlashLevel::Success, "saved: 1");
+                    session.flash(FlashLevel::Info, "");
+                    Ok("added".to_owned())
+                })
+            },
+        );
+        let take = path!(@get /).and(backend.clone()).and_then(
+            |session: Session<InMemorySession>| {
+                session.with(|session| {
+                    let flashes = session.take_flashes();
+                    Ok(format!("{:?} {:?}", session.get(), flashes.len()))
+                })
+            },
+        );
+        add.or(take)
+    });
+
+    let response = runner
+        .perform(Request::post("/").header("host", "localhost:3000"))
+        .unwrap();
+    let session_id = session_id_from_set_cookie(&response);
+
+    let flashes = {
+        let raw = backend.store().load(&session_id).wait().unwrap().unwrap();
+        let (envelope, value) = ::envelope::decode(Some(&raw));
+        assert_eq!(value, Some("value"));
+        envelope.flashes
+    };
+    assert_eq!(
+        flashes,
+        vec![
+            Flash::new(FlashLevel::Success, "saved: 1"),
+            Flash::new(FlashLevel::Info, ""),
+        ]
+    );
+
+    runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", &session_id.to_string()),
+        )
+        .unwrap();
+    assert_eq!(
+        backend.store().load(&session_id).wait().unwrap(),
+        Some("value".into())
+    );
+}
+
+#[test]
+fn test_envelope_legacy_flash_marker() {
+    let raw = "\u{1f}flash\u{1f}s8:saved: 1\u{1e}+value";
+    let (envelope, value) = ::envelope::decode(Some(raw));
+    assert_eq!(value, Some("value"));
+    assert_eq!(
+        envelope.flashes,
+        vec![Flash::new(FlashLevel::Success, "saved: 1")]
+    );
+
+    // The value beginning with the legacy marker is escaped.
+    let escaped = ::envelope::encode(&Default::default(), Some(raw)).unwrap();
+    assert_ne!(escaped, raw);
+    assert_eq!(::envelope::decode(Some(&escaped)).1, Some(raw));
+}
+
+#[test]
+fn test_login_rotates_session_id() {
+    let backend = InMemoryBackend::default();
+
+    let mut runner = test::runner({
+        let visit = path!(@get /).and(backend.clone()).and_then(
+            |session: Session<InMemorySession>| {
+                session.with(|session| {
+                    session.set("visited");
+                    Ok("visit")
+                })
+            },
+        );
+        let login = path!(@post /).and(backend.clone()).and_then(
+            |session: Session<InMemorySession>| {
+                session.with(|session| {
+                    session.login("alice");
+                    Ok("login")
+                })
+            },
+        );
+        visit.or(login)
+    });
+
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    let old_session_id = session_id_from_set_cookie(&response);
+
+    let response = runner
+        .perform(
+            Request::post("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", &old_session_id.to_string()),
+        )
+        .unwrap();
+    let new_session_id = session_id_from_set_cookie(&response);
+
+    assert_ne!(old_session_id, new_session_id);
+    assert_eq!(backend.store().load(&old_session_id).wait().unwrap(), None);
+    let raw = backend.store().load(&new_session_id).wait().unwrap().unwrap();
+    let (envelope, value) = ::envelope::decode(Some(&raw));
+    assert_eq!(envelope.user_id, Some("alice".into()));
+    assert_eq!(value, Some("visited"));
+}
+
+#[test]
+fn test_logout_keeps_flashes() {
+    let backend = InMemoryBackend::default();
+
+    let mut runner = test::runner({
+        let login = path!(@post / "login").and(backend.clone()).and_then(
+            |session: Session<InMemorySession>| {
+                session.with(|session| {
+                    session.set("value");
+                    session.login("alice");
+                    Ok("login")
+                })
+            },
+        );
+        let logout = path!(@post / "logout").and(backend.clone()).and_then(
+            |session: Session<InMemorySession>| {
+                session.with(|session| {
+                    session.logout();
+                    session.flash(FlashLevel::Info, "logged out");
+                    Ok("logout")
+                })
+            },
+        );
+        let take = path!(@get /).and(backend.clone()).and_then(
+            |session: Session<InMemorySession>| {
+                session.with(|session| {
+                    assert_eq!(session.user_id(), None);
+                    let flashes = session.take_flashes();
+                    Ok(format!("{:?}", flashes))
+                })
+            },
+        );
+        login.or(logout).or(take)
+    });
+
+    let response = runner
+        .perform(Request::post("/login").header("host", "localhost:3000"))
+        .unwrap();
+    let login_session_id = session_id_from_set_cookie(&response);
+
+    let response = runner
+        .perform(
+            Request::post("/logout")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", &login_session_id.to_string()),
+        )
+        .unwrap();
+    let logout_session_id = session_id_from_set_cookie(&response);
+    assert_ne!(login_session_id, logout_session_id);
+    assert_eq!(backend.store().load(&login_session_id).wait().unwrap(), None);
+
+    // Only the flash messages are kept.
+    let raw = backend.store().load(&logout_session_id).wait().unwrap().unwrap();
+    let (envelope, value) = ::envelope::decode(Some(&raw));
+    assert_eq!(value, None);
+    assert_eq!(envelope.user_id, None);
+    assert_eq!(
+        envelope.flashes,
+        vec![Flash::new(FlashLevel::Info, "logged out")]
+    );
+
+    // The session is removed once the flash messages are taken.
+    runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", &logout_session_id.to_string()),
+        )
+        .unwrap();
+    assert_eq!(backend.store().load(&logout_session_id).wait().unwrap(), None);
+}
+
+#[test]
+#[should_panic(expected = "Session::login requires a backend")]
+fn test_login_rejects_plain_cookie() {
+    use cookie::CookieSession;
+
+    let mut runner = test::runner({
+        ::cookie::plain().and_then(|session: Session<CookieSession>| {
+            session.with(|session| {
+                session.login("alice");
+                Ok("login")
+            })
+        })
+    });
+    let _ = runner.perform(Request::get("/").header("host", "localhost:3000"));
+}
+
+#[test]
+fn test_revoke_user_sessions() {
+    let backend = InMemoryBackend::default();
+    let store = backend.store();
+
+    let value_of = |user_id: &str| {
+        let envelope = ::envelope::Envelope {
+            user_id: Some(user_id.to_owned()),
+            ..Default::default()
+        };
+        ::envelope::encode(&envelope, Some("value")).unwrap()
+    };
+    let alice_1 = Uuid::new_v4();
+    let alice_2 = Uuid::new_v4();
+    let bob = Uuid::new_v4();
+    store.save(&alice_1, value_of("alice"), None).wait().unwrap();
+    store.save(&alice_2, value_of("alice"), None).wait().unwrap();
+    store.save(&bob, value_of("bob"), None).wait().unwrap();
+    // The expired session is neither listed nor reported as revoked.
+    let expired = Uuid::new_v4();
+    store
+        .save(&expired, value_of("alice"), Some(::std::time::Duration::from_secs(0)))
+        .wait()
+        .unwrap();
+
+    let mut sessions = backend.list_user_sessions("alice").wait().unwrap();
+    sessions.sort();
+    let mut expected = vec![alice_1, alice_2];
+    expected.sort();
+    assert_eq!(sessions, expected);
+
+    let mut revoked = backend.revoke_user_sessions("alice").wait().unwrap();
+    revoked.sort();
+    assert_eq!(revoked, expected);
+    assert_eq!(store.load(&alice_1).wait().unwrap(), None);
+    assert_eq!(store.load(&alice_2).wait().unwrap(), None);
+    assert!(store.load(&bob).wait().unwrap().is_some());
+    assert!(backend.list_user_sessions("alice").wait().unwrap().is_empty());
+}
+
+#[test]
+fn test_session_limit_evicts_oldest() {
+    use limit::SessionLimit;
+    use std::sync::{Arc, Mutex};
+
+    let evicted = Arc::new(Mutex::new(vec![]));
+    let backend = InMemoryBackend::default().session_limit(SessionLimit::new(2).on_evict({
+        let evicted = evicted.clone();
+        move |user_id: &str, session_id: &Uuid| -> Result<(), Error> {
+            evicted
+                .lock()
+                .unwrap()
+                .push((user_id.to_owned(), *session_id));
+            Ok(())
+        }
+    }));
+
+    let mut runner = test::runner({
+        path!(@post /)
+            .and(backend.clone())
+            .and_then(|session: Session<InMemorySession>| {
+                session.with(|session| {
+                    session.login("alice");
+                    Ok("login")
+                })
+            })
+    });
+
+    let session_ids: Vec<Uuid> = (0..3)
+        .map(|_| {
+            let response = runner
+                .perform(Request::post("/").header("host", "localhost:3000"))
+                .unwrap();
+            session_id_from_set_cookie(&response)
+        }).collect();
+
+    assert_eq!(
+        *evicted.lock().unwrap(),
+        vec![("alice".to_owned(), session_ids[0])]
+    );
+    assert_eq!(
+        backend.list_user_sessions("alice").wait().unwrap(),
+        vec![session_ids[1], session_ids[2]]
+    );
+}
+
+#[test]
+fn test_session_limit_rejects_new_login() {
+    use limit::{LimitPolicy, SessionLimit};
+
+    let backend = InMemoryBackend::default()
+        .session_limit(SessionLimit::new(1).policy(LimitPolicy::RejectNew));
+
+    let mut runner = test::runner({
+        path!(@post /)
+            .and(backend.clone())
+            .and_then(|session: Session<InMemorySession>| {
+                session.with(|session| {
+                    session.login("alice");
+                    Ok("login")
+                })
+            })
+    });
+
+    let response = runner
+        .perform(Request::post("/").header("host", "localhost:3000"))
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    let response = runner
+        .perform(Request::post("/").header("host", "localhost:3000"))
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+    // The session id of the rejected session is not sent to the client.
+    assert!(!response.headers().contains_key("set-cookie"));
+    assert_eq!(backend.list_user_sessions("alice").wait().unwrap().len(), 1);
+}
+
+#[test]
+fn test_session_binding_user_agent() {
+    use binding::SessionBinding;
+
+    let backend = InMemoryBackend::default().binding(SessionBinding::new().user_agent());
+
+    let mut runner = test::runner({
+        backend
+            .clone()
+            .and_then(|session: Session<InMemorySession>| {
+                session.with(|session| {
+                    let count: usize = session.get().map_or(0, |s| s.parse().unwrap());
+                    session.set((count + 1).to_string());
+                    Ok("done")
+                })
+            })
+    });
+
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .header("user-agent", "agent-a"),
+        ).unwrap();
+    let session_id = session_id_from_set_cookie(&response);
+
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .header("user-agent", "agent-a")
+                .session_cookie("session-id", &session_id.to_string()),
+        ).unwrap();
+    assert_eq!(
+        session_id_from_set_cookie(&response),
+        session_id
+    );
+
+    // The session is not available from another client.
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .header("user-agent", "agent-b")
+                .session_cookie("session-id", &session_id.to_string()),
+        ).unwrap();
+    let new_session_id = session_id_from_set_cookie(&response);
+    assert_ne!(session_id, new_session_id);
+
+    let raw = backend.store().load(&session_id).wait().unwrap().unwrap();
+    let (envelope, value) = ::envelope::decode(Some(&raw));
+    assert!(envelope.fingerprint.is_some());
+    assert_eq!(value, Some("2"));
+    let raw = backend.store().load(&new_session_id).wait().unwrap().unwrap();
+    assert_eq!(::envelope::decode(Some(&raw)).1, Some("1"));
+}
+
+#[test]
+fn test_session_events() {
+    use event::{SessionEvent, SessionEventKind};
+    use std::sync::{Arc, Mutex};
+
+    let events = Arc::new(Mutex::new(vec![]));
+    let backend = InMemoryBackend::default().on_event({
+        let events = events.clone();
+        move |event: &SessionEvent| -> Result<(), Error> {
+            events
+                .lock()
+                .unwrap()
+                .push((event.kind(), *event.session_id()));
+            Ok(())
+        }
+    });
+
+    let mut runner = test::runner({
+        backend
+            .clone()
+            .and_then(|session: Session<InMemorySession>| {
+                session.with(|session| {
+                    session.set("foo");
+                    Ok("done")
+                })
+            })
+    });
+
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    let session_id = session_id_from_set_cookie(&response);
+
+    runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", &session_id.to_string()),
+        ).unwrap();
+
+    let unknown_session_id = Uuid::new_v4();
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", &unknown_session_id.to_string()),
+        ).unwrap();
+    let new_session_id = session_id_from_set_cookie(&response);
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            (SessionEventKind::Created, session_id),
+            (SessionEventKind::Loaded, session_id),
+            (SessionEventKind::Updated, session_id),
+            (SessionEventKind::Expired, unknown_session_id),
+            (SessionEventKind::Created, new_session_id),
+        ]
+    );
+}
+
+#[test]
+fn test_admin_list_and_count_sessions() {
+    let backend = InMemoryBackend::default();
+    let mut session_ids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+    for session_id in &session_ids {
+        backend
+            .store()
+            .save(session_id, "value".into(), None)
+            .wait()
+            .unwrap();
+    }
+    session_ids.sort();
+
+    assert_eq!(backend.count_sessions().wait().unwrap(), 5);
+
+    let mut listed = vec![];
+    let mut cursor: Option<String> = None;
+    loop {
+        let page = backend
+            .list_sessions(cursor.as_ref().map(|s| s.as_str()), 2)
+            .wait()
+            .unwrap();
+        assert!(page.session_ids.len() <= 2);
+        listed.extend(page.session_ids);
+        match page.next_cursor {
+            Some(next_cursor) => cursor = Some(next_cursor),
+            None => break,
+        }
+    }
+    assert_eq!(listed, session_ids);
+
+    let info = backend.get_session(&session_ids[0]).wait().unwrap().unwrap();
+    assert_eq!(info.value, Some("value".into()));
+    assert_eq!(info.user_id, None);
+
+    backend.delete_session(&session_ids[0]).wait().unwrap();
+    assert_eq!(backend.get_session(&session_ids[0]).wait().unwrap(), None);
+    assert_eq!(backend.count_sessions().wait().unwrap(), 4);
+}
+
+#[cfg(feature = "admin")]
+#[test]
+fn test_admin_endpoint_routing() {
+    use admin::AdminEndpoint;
+
+    let backend = InMemoryBackend::default();
+    let mut runner = test::runner({
+        let admin = AdminEndpoint::new(backend.clone(), |input: &Input| {
+            input.request().headers().contains_key("x-admin-token")
+        });
+        admin.or(path!(@get / "hello").map(|| "hello"))
+    });
+
+    // The requests outside the routes are not handled by the admin endpoint.
+    let response = runner
+        .perform(Request::get("/hello").header("host", "localhost:3000"))
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let response = runner
+        .perform(Request::get("/sessionsfoo").header("host", "localhost:3000"))
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+    let response = runner
+        .perform(Request::get("/sessions/not-a-uuid").header("host", "localhost:3000"))
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+
+    let response = runner
+        .perform(Request::get("/sessions/count").header("host", "localhost:3000"))
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+    let response = runner
+        .perform(
+            Request::get("/sessions/count")
+                .header("host", "localhost:3000")
+                .header("x-admin-token", "secret"),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let response = runner
+        .perform(
+            Request::post("/sessions/count")
+                .header("host", "localhost:3000")
+                .header("x-admin-token", "secret"),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 405);
+}
+
+#[cfg(feature = "admin")]
+#[test]
+fn test_admin_endpoint_list_count_bounds() {
+    use admin::AdminEndpoint;
+
+    let backend = InMemoryBackend::default();
+    for _ in 0..3 {
+        backend
+            .store()
+            .save(&Uuid::new_v4(), "value".into(), None)
+            .wait()
+            .unwrap();
+    }
+    let mut runner = test::runner(AdminEndpoint::new(backend.clone(), |_: &Input| true));
+
+    let response = runner
+        .perform(Request::get("/sessions?count=0").header("host", "localhost:3000"))
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+
+    let response = runner
+        .perform(Request::get("/sessions?count=100000").header("host", "localhost:3000"))
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[cfg(feature = "admin")]
+#[test]
+fn test_admin_endpoint_delete_emits_event() {
+    use admin::AdminEndpoint;
+    use event::{SessionEvent, SessionEventKind};
+    use std::sync::{Arc, Mutex};
+
+    let events = Arc::new(Mutex::new(vec![]));
+    let backend = InMemoryBackend::default().on_event({
+        let events = events.clone();
+        move |event: &SessionEvent| -> Result<(), Error> {
+            events
+                .lock()
+                .unwrap()
+                .push((event.kind(), *event.session_id(), event.request().uri().clone()));
+            Ok(())
+        }
+    });
+    let session_id = Uuid::new_v4();
+    backend
+        .store()
+        .save(&session_id, "value".into(), None)
+        .wait()
+        .unwrap();
+
+    let mut runner = test::runner(AdminEndpoint::new(backend.clone(), |_: &Input| true));
+    let path = format!("/sessions/{}", session_id);
+    let response = runner
+        .perform(Request::delete(&path[..]).header("host", "localhost:3000"))
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(backend.get_session(&session_id).wait().unwrap(), None);
+
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].0, SessionEventKind::Destroyed);
+    assert_eq!(events[0].1, session_id);
+    assert_eq!(events[0].2.path(), &path[..]);
+}
+
+#[test]
+fn test_testing_cookie_helpers() {
+    use testing::{MockBackend, MockSession, Op};
+
+    let mock = MockBackend::with_value("seeded");
+    let mut runner = test::runner({
+        mock.clone().and_then(|session: Session<MockSession>| {
+            session.with(|session| {
+                assert_eq!(session.get(), Some("seeded"));
+                session.remove();
+                Ok("done")
+            })
+        })
+    });
+    runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    assert_eq!(mock.value(), None);
+    assert_eq!(mock.ops(), vec![Op::Get, Op::Remove, Op::Write]);
+
+    let backend = InMemoryBackend::default();
+    let mut runner = test::runner({
+        path!(@get / "login")
+            .and(backend.clone())
+            .and_then(|session: Session<InMemorySession>| {
+                session.with(|session| {
+                    session.set("value");
+                    Ok("done")
+                })
+            }).or(path!(@get / "logout").and(backend.clone()).and_then(
+                |session: Session<InMemorySession>| {
+                    session.with(|session| {
+                        session.remove();
+                        Ok("done")
+                    })
+                },
+            )).or(path!(@get / "noop").and(backend.clone()).and_then(
+                |session: Session<InMemorySession>| session.with(|_session| Ok("done")),
+            ))
+    });
+
+    let response = runner
+        .perform(Request::get("/login").header("host", "localhost:3000"))
+        .unwrap();
+    let cookie = assert_set_cookie(&response, "session-id");
+
+    let response = runner
+        .perform(
+            Request::get("/logout")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", cookie.value()),
+        ).unwrap();
+    assert_cookie_removed(&response, "session-id");
+
+    let response = runner
+        .perform(Request::get("/noop").header("host", "localhost:3000"))
+        .unwrap();
+    assert_no_set_cookie(&response, "session-id");
+}
+
+#[test]
+fn test_conformance_builtin_backends() {
+    use testing::conformance::Conformance;
+
+    Conformance::new(InMemoryBackend::default(), "session-id").run();
+
+    #[cfg(feature = "secure")]
+    Conformance::new(::cookie::signed([0u8; 32]), "finchers-session")
+        .client_side()
+        .run();
+    #[cfg(feature = "secure")]
+    Conformance::new(::cookie::private([0u8; 32]), "finchers-session")
+        .client_side()
+        .run();
+
+    Conformance::new(::cookie::plain(), "finchers-session")
+        .client_side()
+        .skip_invalid_value()
+        .run();
+}
+
+#[test]
+fn test_malformed_session_id() {
+    use std::sync::{Arc, Mutex};
+    use store::{InvalidSessionId, MalformedIdPolicy};
+
+    let invalid_ids = Arc::new(Mutex::new(vec![]));
+    let backend = InMemoryBackend::default().on_invalid_id({
+        let invalid_ids = invalid_ids.clone();
+        move |id: &InvalidSessionId| invalid_ids.lock().unwrap().push(id.clone())
+    });
+    let mut runner = test::runner({
+        backend
+            .clone()
+            .and_then(|session: Session<InMemorySession>| {
+                session.with(|session| {
+                    assert_eq!(session.get(), None);
+                    Ok("done")
+                })
+            })
+    });
+
+    // The malformed session id is removed from the client.
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", "garbage"),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_cookie_removed(&response, "session-id");
+
+    let unknown_session_id = Uuid::new_v4();
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", &unknown_session_id.to_string()),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    assert_eq!(
+        *invalid_ids.lock().unwrap(),
+        vec![
+            InvalidSessionId::Malformed("garbage".into()),
+            InvalidSessionId::Unknown(unknown_session_id),
+        ]
+    );
+
+    let mut runner = test::runner({
+        InMemoryBackend::default()
+            .malformed_id_policy(MalformedIdPolicy::Reject)
+            .and_then(|session: Session<InMemorySession>| session.with(|_session| Ok("done")))
+    });
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", "garbage"),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[test]
+fn test_session_error_status_codes() {
+    use error::SessionError;
+    use finchers::error::HttpError;
+
+    let status_of = |err: SessionError| err.status_code().as_u16();
+    assert_eq!(status_of(SessionError::StorageUnavailable(format_err!("down"))), 503);
+    assert_eq!(status_of(SessionError::Serialization(format_err!("bad"))), 500);
+    assert_eq!(status_of(SessionError::InvalidId("garbage".into())), 400);
+    assert_eq!(status_of(SessionError::InvalidCursor("garbage".into())), 400);
+    assert_eq!(status_of(SessionError::Tampered), 400);
+    assert_eq!(status_of(SessionError::Expired), 401);
+    assert_eq!(status_of(SessionError::TooLarge { size: 8192, limit: 4096 }), 413);
+    assert_eq!(status_of(SessionError::Conflict), 409);
+    assert_eq!(status_of(SessionError::Timeout), 504);
+}
+
+#[test]
+fn test_session_error_downcast() {
+    use error::SessionError;
+
+    let err: Error = SessionError::Timeout.into();
+    match err.downcast_ref::<SessionError>() {
+        Some(&SessionError::Timeout) => {}
+        e => panic!("unexpected error: {:?}", e),
+    }
+
+    let err = InMemoryBackend::default()
+        .list_sessions(Some("garbage"), 10)
+        .wait()
+        .unwrap_err();
+    match err.downcast_ref::<SessionError>() {
+        Some(&SessionError::InvalidCursor(ref cursor)) => assert_eq!(cursor, "garbage"),
+        e => panic!("unexpected error: {:?}", e),
+    }
+}
+
+#[cfg(feature = "secure")]
+#[test]
+fn test_cookie_tampered_value() {
+    use error::InvalidValuePolicy;
+    use std::sync::{Arc, Mutex};
+
+    let backend = || {
+        ::cookie::signed("this-is-a-very-very-very-long-secret-key")
+            .name("session")
+            .secure(false)
+    };
+    let cookie = backend().encode_value("value");
+    let tampered = format!("{}x", cookie.value());
+
+    // By default, the tampered value is discarded, and the Cookie entry
+    // is removed when the session is written.
+    let rejected = Arc::new(Mutex::new(vec![]));
+    let mut runner = test::runner({
+        let rejected = rejected.clone();
+        backend()
+            .on_invalid_value(move |err| rejected.lock().unwrap().push(err.to_string()))
+            .and_then(|session: Session<::cookie::CookieSession>| {
+                session.with(|session| {
+                    assert_eq!(session.get(), None);
+                    Ok("done")
+                })
+            })
+    });
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session", &tampered),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_cookie_removed(&response, "session");
+    assert_eq!(
+        *rejected.lock().unwrap(),
+        vec!["the session value has been tampered"]
+    );
+
+    // The request is rejected and the broken Cookie entry is removed from the client.
+    let mut runner = test::runner({
+        backend
+            .invalid_value_policy(InvalidValuePolicy::Reject)
+            .and_then(|session: Session<::cookie::CookieSession>| {
+                session.with(|_session| Ok("done"))
+            })
+    });
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session", &tampered),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+    assert_cookie_removed(&response, "session");
+}
+
+#[test]
+fn test_cookie_value_too_large() {
+    let mut runner = test::runner({
+        ::cookie::plain().and_then(|session: Session<::cookie::CookieSession>| {
+            session.with(|session| {
+                session.set("x".repeat(5000));
+                Ok("done")
+            })
+        })
+    });
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 413);
+    assert!(!response.headers().contains_key("set-cookie"));
+}
+
+#[derive(Debug, Default)]
+struct UnavailableStore {
+    loads: ::std::sync::atomic::AtomicUsize,
+    saves: ::std::sync::atomic::AtomicUsize,
+}
+
+impl SessionStore for UnavailableStore {
+    type LoadFuture = future::FutureResult<Option<String>, Error>;
+    type SaveFuture = future::FutureResult<(), Error>;
+    type DeleteFuture = future::FutureResult<(), Error>;
+
+    fn load(&self, _: &Uuid) -> Self::LoadFuture {
+        self.loads
+            .fetch_add(1, ::std::sync::atomic::Ordering::SeqCst);
+        future::err(::error::SessionError::storage(format_err!("unavailable")))
+    }
+
+    fn save(&self, _: &Uuid, _: String, _: Option<::std::time::Duration>) -> Self::SaveFuture {
+        self.saves
+            .fetch_add(1, ::std::sync::atomic::Ordering::SeqCst);
+        future::err(::error::SessionError::storage(format_err!("unavailable")))
+    }
+
+    fn delete(&self, _: &Uuid) -> Self::DeleteFuture {
+        future::err(::error::SessionError::storage(format_err!("unavailable")))
+    }
+}
+
+// A store which fails to load the session value for the first `failures` attempts.
+#[derive(Debug, Default)]
+struct FlakyStore {
+    failures: usize,
+    loads: ::std::sync::atomic::AtomicUsize,
+}
+
+impl SessionStore for FlakyStore {
+    type LoadFuture = future::FutureResult<Option<String>, Error>;
+    type SaveFuture = future::FutureResult<(), Error>;
+    type DeleteFuture = future::FutureResult<(), Error>;
+
+    fn load(&self, _: &Uuid) -> Self::LoadFuture {
+        let attempt = self
+            .loads
+            .fetch_add(1, ::std::sync::atomic::Ordering::SeqCst);
+        if attempt < self.failures {
+            future::err(::error::SessionError::storage(format_err!("unavailable")))
+        } else {
+            future::ok(Some("value".into()))
+        }
+    }
+
+    fn save(&self, _: &Uuid, _: String, _: Option<::std::time::Duration>) -> Self::SaveFuture {
+        future::ok(())
+    }
+
+    fn delete(&self, _: &Uuid) -> Self::DeleteFuture {
+        future::ok(())
+    }
+}
+
+#[test]
+fn test_fallback_degrade_with_circuit_breaker() {
+    use fallback::{CircuitBreaker, FallbackPolicy};
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use store::{StoreBackend, StoreSession};
+
+    let session_id = Uuid::new_v4().to_string();
+
+    // The request fails by default.
+    let mut runner = test::runner({
+        StoreBackend::from_store(UnavailableStore::default()).and_then(
+            |session: Session<StoreSession<UnavailableStore>>| session.with(|_session| Ok("done")),
+        )
+    });
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", &session_id),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 503);
+
+    let backend = StoreBackend::from_store(UnavailableStore::default())
+        .fallback(FallbackPolicy::Degrade)
+        .circuit_breaker(CircuitBreaker::new(2, Duration::from_secs(3600)));
+    let mut runner = test::runner({
+        backend
+            .clone()
+            .and_then(|session: Session<StoreSession<UnavailableStore>>| {
+                session.with(|session| {
+                    assert_eq!(session.get(), None);
+                    // The modification is discarded.
+                    session.set("value");
+                    Ok("done")
+                })
+            })
+    });
+    for _ in 0..3 {
+        let response = runner
+            .perform(
+                Request::get("/")
+                    .header("host", "localhost:3000")
+                    .session_cookie("session-id", &session_id),
+            ).unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(!response.headers().contains_key("set-cookie"));
+    }
+
+    // The third request does not access the store.
+    assert_eq!(backend.store().loads.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_fallback_retry() {
+    use fallback::FallbackPolicy;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use store::{StoreBackend, StoreSession};
+
+    let session_id = Uuid::new_v4().to_string();
+
+    // The loading succeeds at the third attempt.
+    let backend = StoreBackend::from_store(FlakyStore {
+        failures: 2,
+        ..Default::default()
+    }).fallback(FallbackPolicy::Retry {
+        backoff: Duration::from_millis(1),
+        deadline: Duration::from_secs(10),
+    });
+    let mut runner = test::runner({
+        backend
+            .clone()
+            .and_then(|session: Session<StoreSession<FlakyStore>>| {
+                session.with(|session| {
+                    assert_eq!(session.get(), Some("value"));
+                    Ok("done")
+                })
+            })
+    });
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", &session_id),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(backend.store().loads.load(Ordering::SeqCst), 3);
+
+    // The request fails if the next retry exceeds the deadline.
+    let backend = StoreBackend::from_store(FlakyStore {
+        failures: 2,
+        ..Default::default()
+    }).fallback(FallbackPolicy::Retry {
+        backoff: Duration::from_secs(1),
+        deadline: Duration::from_millis(500),
+    });
+    let mut runner = test::runner({
+        backend
+            .clone()
+            .and_then(|session: Session<StoreSession<FlakyStore>>| {
+                session.with(|_session| Ok("done"))
+            })
+    });
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", &session_id),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(backend.store().loads.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_circuit_breaker_on_write() {
+    use fallback::CircuitBreaker;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use store::{StoreBackend, StoreSession};
+
+    let backend = StoreBackend::from_store(UnavailableStore::default())
+        .circuit_breaker(CircuitBreaker::new(1, Duration::from_secs(3600)));
+    let mut runner = test::runner({
+        backend
+            .clone()
+            .and_then(|session: Session<StoreSession<UnavailableStore>>| {
+                session.with(|session| {
+                    session.set("value");
+                    Ok("done")
+                })
+            })
+    });
+
+    // The failure of saving opens the circuit.
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 503);
+    assert!(!response.headers().contains_key("set-cookie"));
+    assert_eq!(backend.store().saves.load(Ordering::SeqCst), 1);
+
+    // The second request does not access the store.
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(backend.store().saves.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_circuit_breaker_abandoned_trial() {
+    use fallback::CircuitBreaker;
+    use std::thread;
+    use std::time::Duration;
+
+    let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+    breaker.record_failure();
+    assert!(breaker.is_open());
+    assert!(!breaker.allow());
+
+    thread::sleep(Duration::from_millis(50));
+    assert!(breaker.allow());
+    // Only one trial is allowed at a time.
+    assert!(!breaker.allow());
+
+    // The trial which never finishes does not keep the circuit open forever.
+    thread::sleep(Duration::from_millis(50));
+    assert!(breaker.allow());
+    breaker.record_success();
+    assert!(!breaker.is_open());
+}
+
+#[test]
+fn test_lazy_session_loading() {
+    use std::sync::atomic::Ordering;
+    use store::{LazySession, StoreBackend};
+
+    let backend = StoreBackend::from_store(UnavailableStore::default());
+    let mut runner = test::runner({
+        let skip = path!(@get / "skip")
+            .and(backend.clone().lazy())
+            .map(|session: LazySession<UnavailableStore>| {
+                assert!(session.has_session_id());
+                "skipped"
+            });
+        let load = path!(@get / "load")
+            .and(backend.clone().lazy())
+            .and_then(|session: LazySession<UnavailableStore>| {
+                session.with(|_session| Ok("loaded"))
+            });
+        skip.or(load)
+    });
+    let session_id = Uuid::new_v4().to_string();
+
+    // The store is not accessed unless the session is loaded.
+    let response = runner
+        .perform(
+            Request::get("/skip")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", &session_id),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.headers().get("set-cookie").is_none());
+    assert_eq!(backend.store().loads.load(Ordering::SeqCst), 0);
+
+    let response = runner
+        .perform(
+            Request::get("/load")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", &session_id),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(backend.store().loads.load(Ordering::SeqCst), 1);
+
+    // The loaded session is written back as usual.
+    let backend = InMemoryBackend::default();
+    let mut runner = test::runner({
+        backend
+            .clone()
+            .lazy()
+            .and_then(|session: LazySession<::in_memory::InMemoryStore>| {
+                session.with(|session| {
+                    let count = session.get().map_or(0, |n| n.parse::<u32>().unwrap());
+                    session.set((count + 1).to_string());
+                    Ok("done")
+                })
+            })
+    });
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    let session_id = session_id_from_set_cookie(&response);
+
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session-id", &session_id.to_string()),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let raw = backend.store().load(&session_id).wait().unwrap();
+    assert_eq!(::envelope::decode(raw.as_ref().map(|s| s.as_str())).1, Some("2"));
+}
+
+#[test]
+fn test_read_only_session() {
+    use read_only::{ReadOnly, SessionRef};
+    use testing::{MockBackend, MockSession, Op};
+
+    let backend = MockBackend::new();
+    let mut runner = test::runner({
+        ReadOnly::new(backend.clone()).map(|session: SessionRef<MockSession>| {
+            assert_eq!(session.get(), None);
+            "done"
+        })
+    });
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(backend.ops(), vec![Op::Get]);
+
+    // No Cookie is emitted even if the session id is invalid.
+    let backend = InMemoryBackend::default();
+    let mut runner = test::runner({
+        ReadOnly::new(backend).map(|session: SessionRef<InMemorySession>| {
+            assert_eq!(session.user_id(), None);
+            "done"
+        })
+    });
+    for session_id in vec![Uuid::new_v4().to_string(), "garbage".into()] {
+        let response = runner
+            .perform(
+                Request::get("/")
+                    .header("host", "localhost:3000")
+                    .session_cookie("session-id", &session_id),
+            ).unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(!response.headers().contains_key("set-cookie"));
+    }
+}
+
+#[cfg(feature = "secure")]
+#[test]
+fn test_read_only_tampered_cookie() {
+    use error::InvalidValuePolicy;
+    use read_only::{ReadOnly, SessionRef};
+
+    let backend = || {
+        ::cookie::signed("this-is-a-very-very-very-long-secret-key")
+            .name("session")
+            .secure(false)
+    };
+    let tampered = format!("{}x", backend().encode_value("value").value());
+
+    // The discarded value is kept in the client.
+    let mut runner = test::runner({
+        ReadOnly::new(backend()).map(|session: SessionRef<::cookie::CookieSession>| {
+            assert_eq!(session.get(), None);
+            "done"
+        })
+    });
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session", &tampered),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(!response.headers().contains_key("set-cookie"));
+
+    // The rejected value is removed from the client while reading it.
+    let mut runner = test::runner({
+        ReadOnly::new(backend().invalid_value_policy(InvalidValuePolicy::Reject))
+            .map(|_session: SessionRef<::cookie::CookieSession>| "done")
+    });
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .session_cookie("session", &tampered),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+    assert_cookie_removed(&response, "session");
+}
+
+#[test]
+fn test_wrap_commits_session() {
+    use wrap::{with_session, CommitPolicy};
+
+    assert!(with_session(|_: &mut Session<InMemorySession>| ()).is_none());
+
+    let backend = InMemoryBackend::default();
+    let mut runner = test::runner({
+        backend.clone().wrap(path!(@get /).map(|| {
+            with_session(|session: &mut Session<InMemorySession>| session.set("foo"))
+                .expect("the session should be available");
+            "done"
+        }))
+    });
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    let session_id = session_id_from_set_cookie(&response);
+    let raw = backend.store().load(&session_id).wait().unwrap();
+    assert_eq!(::envelope::decode(raw.as_ref().map(|s| s.as_str())).1, Some("foo"));
+
+    // The session is committed on errors depending on the policy.
+    for &(policy, committed) in &[(CommitPolicy::Always, 1), (CommitPolicy::OnSuccess, 0)] {
+        let backend = InMemoryBackend::default();
+        let mut runner = test::runner({
+            backend
+                .clone()
+                .wrap(path!(@get /).and_then(|| {
+                    with_session(|session: &mut Session<InMemorySession>| session.set("foo"));
+                    Err::<&str, _>(::finchers::error::bad_request("failed"))
+                })).commit_policy(policy)
+        });
+        let response = runner
+            .perform(Request::get("/").header("host", "localhost:3000"))
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 400);
+        assert_eq!(backend.count_sessions().wait().unwrap(), committed);
+    }
+}
+
+#[test]
+fn test_wrap_future_is_send() {
+    use futures::future::FutureResult;
+    use wrap::WrapFuture;
+
+    fn assert_send<T: Send>() {}
+    assert_send::<
+        WrapFuture<
+            FutureResult<(Session<InMemorySession>,), Error>,
+            FutureResult<&'static str, Error>,
+            InMemorySession,
+        >,
+    >();
+}
+
+#[cfg(feature = "jwt")]
+#[test]
+fn test_jwt_wrap() {
+    use jwt::{JwtBackend, JwtSession};
+    use std::sync::{Arc, Mutex};
+    use wrap::with_session;
+
+    let counts = Arc::new(Mutex::new(vec![]));
+    let mut runner = test::runner({
+        let counts = counts.clone();
+        JwtBackend::new("secret").wrap(path!(@get /).map(move || {
+            with_session(|session: &mut Session<JwtSession>| {
+                let count: usize = session.get().map_or(0, |s| s.parse().unwrap());
+                counts.lock().unwrap().push(count);
+                session.set((count + 1).to_string());
+            }).expect("the session should be available");
+            "done"
+        }))
+    });
+
+    let response = runner
+        .perform(Request::get("/").header("host", "localhost:3000"))
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let token = response.headers()["x-session-token"]
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    let response = runner
+        .perform(
+            Request::get("/")
+                .header("host", "localhost:3000")
+                .header("authorization", format!("Bearer {}", token)),
+        ).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(*counts.lock().unwrap(), vec![0, 1]);
+}
+
+#[test]
+fn test_in_memory_purges_expired_entries() {
+    use in_memory::Storage;
+    use std::thread;
+    use std::time::Duration;
+
+    let storage = Storage::default();
+    for _ in 0..63 {
+        storage
+            .set(Uuid::new_v4(), "foo".into(), Some(Duration::from_millis(1)))
+            .unwrap();
+    }
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(storage.len(), 63);
+
+    // The 64th entry triggers the purge.
+    let session_id = Uuid::new_v4();
+    storage.set(session_id, "bar".into(), None).unwrap();
+    assert_eq!(storage.len(), 1);
+    assert_eq!(storage.get(&session_id).unwrap(), Some("bar".into()));
+}
+
+#[test]
+fn test_store_futures_are_send() {
+    use in_memory::InMemoryStore;
+    use store::{ReadFuture, WriteFuture};
+
+    fn assert_send<T: Send>() {}
+    assert_send::<ReadFuture<InMemoryStore>>();
+    assert_send::<WriteFuture<InMemoryStore>>();
+}
+
+#[test]
+fn test_wrap_removes_session_on_panic() {
+    use std::panic::{self, AssertUnwindSafe};
+    use wrap::with_session;
+
+    let backend = InMemoryBackend::default();
+    let mut runner = test::runner({
+        backend.wrap(path!(@get /).map(|| -> &'static str {
+            assert!(with_session(|_: &mut Session<InMemorySession>| ()).is_some());
+            panic!("the handler panicked");
+        }))
+    });
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        runner.perform(Request::get("/").header("host", "localhost:3000"))
+    }));
+    assert!(result.is_err());
+
+    assert!(with_session(|_: &mut Session<InMemorySession>| ()).is_none());
+}
+
+#[test]
+fn test_session_binding_ip_ignores_spoofed_entries() {
+    use binding::SessionBinding;
+
+    let backend = InMemoryBackend::default()
+        .binding(SessionBinding::new().ip_prefix(32, 128).trusted_proxies(1));
+    let mut runner = test::runner({
+        backend
+            .clone()
+            .and_then(|session: Session<InMemorySession>| {
+                session.with(|session| {
+                    session.set("value");
+                    Ok("done")
+                })
+            })
+    });
+    let mut perform = |forwarded_for: &str, session_id: Option<Uuid>| {
+        let mut request = Request::get("/");
+        request
+            .header("host", "localhost:3000")
+            .header("x-forwarded-for", forwarded_for);
+        if let Some(session_id) = session_id {
+            request.session_cookie("session-id", &session_id.to_string());
+        }
+        let response = runner.perform(&mut request).unwrap();
+        session_id_from_set_cookie(&response)
+    };
+
+    // The trusted proxy appends the address of the victim.
+    let session_id = perform("192.0.2.1", None);
+
+    // The entries on the left of the one appended by the proxy are ignored.
+    assert_eq!(perform("203.0.113.9, 192.0.2.1", Some(session_id)), session_id);
+
+    // The attacker cannot forge the address of the victim.
+    assert_ne!(perform("192.0.2.1, 198.51.100.7", Some(session_id)), session_id);
+}
+
+#[test]
+fn test_session_binding_ip_peer_address() {
+    use binding::SessionBinding;
+    use std::net::SocketAddr;
+
+    let backend = InMemoryBackend::default().binding(SessionBinding::new().ip_prefix(24, 64));
+    let mut runner = test::runner({
+        backend
+            .clone()
+            .and_then(|session: Session<InMemorySession>| {
+                session.with(|session| {
+                    session.set("value");
+                    Ok("done")
+                })
+            })
+    });
+    let mut perform = |peer_addr: &str, session_id: Option<Uuid>| {
+        let peer_addr: SocketAddr = peer_addr.parse().unwrap();
+        let mut request = Request::get("/");
+        request.header("host", "localhost:3000").extension(peer_addr);
+        if let Some(session_id) = session_id {
+            request.session_cookie("session-id", &session_id.to_string());
+        }
+        let response = runner.perform(&mut request).unwrap();
+        session_id_from_set_cookie(&response)
+    };
+
+    let session_id = perform("192.0.2.1:50000", None);
+
+    // The session is kept within the same network prefix.
+    assert_eq!(perform("192.0.2.99:50001", Some(session_id)), session_id);
+
+    // The session is not used from another network.
+    assert_ne!(perform("198.51.100.7:50000", Some(session_id)), session_id);
+}
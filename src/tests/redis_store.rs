@@ -0,0 +1,474 @@
+extern crate tokio;
+
+use finchers::error::Error;
+use futures::Future;
+use uuid::Uuid;
+
+use std::cell::RefCell;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use self::tokio::runtime::current_thread::Runtime;
+
+use super::fake_redis::{self, FakeRedis};
+use cache::CachedStore;
+use envelope::{self, Envelope};
+use error::SessionError;
+use redis::{listen_invalidation, Client, RedisStore};
+use store::{SessionAdmin, SessionStore, UserSessionIndex};
+
+thread_local! {
+    // The runtime is shared within each test, since the pooled connections
+    // are bound to the reactor of the runtime.
+    static RUNTIME: RefCell<Runtime> = RefCell::new(Runtime::new().unwrap());
+}
+
+fn block_on<F: Future>(future: F) -> Result<F::Item, F::Error> {
+    RUNTIME.with(|runtime| runtime.borrow_mut().block_on(future))
+}
+
+// Waits until the condition holds, or panics after 5 seconds.
+fn eventually(mut cond: impl FnMut() -> bool) {
+    let started = Instant::now();
+    while !cond() {
+        assert!(started.elapsed() < Duration::from_secs(5), "timed out");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn value_of(user_id: &str) -> String {
+    let envelope = Envelope {
+        user_id: Some(user_id.to_owned()),
+        ..Default::default()
+    };
+    envelope::encode(&envelope, Some("value")).unwrap()
+}
+
+#[test]
+fn test_redis_load_save_delete() {
+    let server = FakeRedis::start();
+    let store = RedisStore::new(server.client());
+    let session_id = Uuid::new_v4();
+    let key = format!("finchers-sesssion:{}", session_id);
+
+    assert_eq!(block_on(store.load(&session_id)).unwrap(), None);
+
+    block_on(store.save(&session_id, "foo".into(), None)).unwrap();
+    assert_eq!(
+        block_on(store.load(&session_id)).unwrap(),
+        Some("foo".into())
+    );
+
+    block_on(store.delete(&session_id)).unwrap();
+    assert_eq!(block_on(store.load(&session_id)).unwrap(), None);
+
+    assert_eq!(
+        server.commands(),
+        vec![
+            vec!["GET".to_owned(), key.clone()],
+            vec!["MULTI".to_owned()],
+            vec!["SET".to_owned(), key.clone(), "foo".to_owned()],
+            vec!["EXEC".to_owned()],
+            vec!["GET".to_owned(), key.clone()],
+            vec!["GET".to_owned(), key.clone()],
+            vec!["MULTI".to_owned()],
+            vec!["DEL".to_owned(), key.clone()],
+            vec!["EXEC".to_owned()],
+            vec!["GET".to_owned(), key.clone()],
+        ]
+    );
+}
+
+#[test]
+fn test_redis_reuses_connections() {
+    let server = FakeRedis::start();
+    let store = RedisStore::new(server.client());
+    let session_id = Uuid::new_v4();
+
+    for _ in 0..3 {
+        block_on(store.save(&session_id, value_of("alice"), None)).unwrap();
+        block_on(store.load(&session_id)).unwrap();
+    }
+    block_on(store.list_user_sessions("alice")).unwrap();
+    block_on(store.count_sessions()).unwrap();
+    assert_eq!(server.connection_count(), 1);
+
+    // The connection is not reused after an error.
+    server.fail_command("GET");
+    assert!(block_on(store.load(&session_id)).is_err());
+    block_on(store.save(&session_id, value_of("alice"), None)).unwrap();
+    assert_eq!(server.connection_count(), 2);
+}
+
+#[test]
+fn test_redis_reconnects_stale_connection() {
+    let server = FakeRedis::start();
+    let store = RedisStore::new(server.client());
+    let session_id = Uuid::new_v4();
+
+    block_on(store.save(&session_id, "foo".into(), None)).unwrap();
+    assert_eq!(server.connection_count(), 1);
+
+    // The pooled connection has been closed by the server, so the store
+    // establishes a new one instead of failing the request.
+    server.disconnect_clients();
+    assert_eq!(
+        block_on(store.load(&session_id)).unwrap(),
+        Some("foo".into())
+    );
+    assert_eq!(server.connection_count(), 2);
+}
+
+#[test]
+fn test_redis_session_expires() {
+    let server = FakeRedis::start();
+    let store = RedisStore::new(server.client());
+    let session_id = Uuid::new_v4();
+
+    block_on(store.save(&session_id, "foo".into(), Some(Duration::from_secs(60)))).unwrap();
+    assert_eq!(server.command_names(), vec!["MULTI", "SETEX", "EXEC"]);
+    assert_eq!(server.commands()[1][2], "60");
+
+    server.advance(Duration::from_secs(59));
+    assert_eq!(
+        block_on(store.load(&session_id)).unwrap(),
+        Some("foo".into())
+    );
+
+    server.advance(Duration::from_secs(2));
+    assert_eq!(block_on(store.load(&session_id)).unwrap(), None);
+}
+
+#[test]
+fn test_redis_write_future_runs_commands_in_order() {
+    let server = FakeRedis::start();
+    let store = RedisStore::new(server.client()).invalidation_channel("sessions");
+    let session_id = Uuid::new_v4();
+
+    block_on(store.save(
+        &session_id,
+        value_of("alice"),
+        Some(Duration::from_secs(60)),
+    )).unwrap();
+    assert_eq!(
+        server.command_names(),
+        vec!["MULTI", "SETEX", "ZADD", "EXPIRE", "PUBLISH", "EXEC"]
+    );
+    assert!(server.contains_key("finchers-sesssion:user:alice"));
+
+    assert_eq!(
+        block_on(store.list_user_sessions("alice")).unwrap(),
+        vec![session_id]
+    );
+    assert_eq!(
+        block_on(store.revoke_user_sessions("alice")).unwrap(),
+        vec![session_id]
+    );
+    assert_eq!(block_on(store.load(&session_id)).unwrap(), None);
+    assert!(!server.contains_key("finchers-sesssion:user:alice"));
+}
+
+#[test]
+fn test_redis_revoke_retries_on_concurrent_login() {
+    let server = FakeRedis::start();
+    let store = RedisStore::new(server.client());
+    let session_id = Uuid::new_v4();
+    let other_id = Uuid::new_v4();
+    let other_key = format!("finchers-sesssion:{}", other_id);
+    block_on(store.save(&session_id, value_of("alice"), None)).unwrap();
+
+    // Another session of the user is added while the index is being read.
+    server.run_before("MGET", &["SET", &other_key, &value_of("alice")]);
+    server.run_before(
+        "MGET",
+        &[
+            "ZADD",
+            "finchers-sesssion:user:alice",
+            "NX",
+            "1",
+            &other_id.to_string(),
+        ],
+    );
+
+    let mut revoked = block_on(store.revoke_user_sessions("alice")).unwrap();
+    revoked.sort();
+    let mut expected = vec![session_id, other_id];
+    expected.sort();
+    assert_eq!(revoked, expected);
+    assert!(!server.contains_key(&other_key));
+    assert!(!server.contains_key("finchers-sesssion:user:alice"));
+    assert_eq!(
+        server.command_names()[4..],
+        [
+            "WATCH", "ZRANGE", "MGET", "MULTI", "DEL", "EXEC", "WATCH", "ZRANGE", "MGET", "MULTI",
+            "DEL", "EXEC",
+        ]
+    );
+}
+
+#[test]
+fn test_redis_user_index_skips_expired_sessions() {
+    let server = FakeRedis::start();
+    let store = RedisStore::new(server.client());
+    let short = Uuid::new_v4();
+    let long = Uuid::new_v4();
+
+    block_on(store.save(&short, value_of("alice"), Some(Duration::from_secs(10)))).unwrap();
+    block_on(store.save(&long, value_of("alice"), Some(Duration::from_secs(60)))).unwrap();
+
+    server.advance(Duration::from_secs(30));
+    assert_eq!(
+        block_on(store.list_user_sessions("alice")).unwrap(),
+        vec![long]
+    );
+    assert!(server.command_names().contains(&"ZREM".to_owned()));
+}
+
+#[test]
+fn test_redis_write_future_discards_transaction_on_error() {
+    let server = FakeRedis::start();
+    let store = RedisStore::new(server.client()).invalidation_channel("sessions");
+    server.fail_command("ZADD");
+
+    let session_id = Uuid::new_v4();
+    let result = block_on(store.save(
+        &session_id,
+        value_of("alice"),
+        Some(Duration::from_secs(60)),
+    ));
+    assert!(result.is_err());
+    assert_eq!(
+        server.command_names(),
+        vec!["MULTI", "SETEX", "ZADD", "EXPIRE", "PUBLISH", "EXEC"]
+    );
+    // None of the commands in the transaction has been executed.
+    assert!(!server.contains_key(&format!("finchers-sesssion:{}", session_id)));
+    assert!(!server.contains_key("finchers-sesssion:user:alice"));
+}
+
+#[test]
+fn test_redis_delete_removes_from_user_index() {
+    let server = FakeRedis::start();
+    let store = RedisStore::new(server.client()).invalidation_channel("sessions");
+    let session_id = Uuid::new_v4();
+    let other_id = Uuid::new_v4();
+
+    block_on(store.save(&session_id, value_of("alice"), None)).unwrap();
+    block_on(store.save(&other_id, value_of("alice"), None)).unwrap();
+    block_on(store.delete(&session_id)).unwrap();
+    assert_eq!(
+        &server.commands()[10..],
+        &[
+            vec![
+                "GET".to_owned(),
+                format!("finchers-sesssion:{}", session_id),
+            ],
+            vec!["MULTI".to_owned()],
+            vec![
+                "DEL".to_owned(),
+                format!("finchers-sesssion:{}", session_id),
+            ],
+            vec![
+                "PUBLISH".to_owned(),
+                "sessions".to_owned(),
+                session_id.to_string(),
+            ],
+            vec![
+                "ZREM".to_owned(),
+                "finchers-sesssion:user:alice".to_owned(),
+                session_id.to_string(),
+            ],
+            vec!["EXEC".to_owned()],
+        ][..]
+    );
+
+    block_on(store.delete(&other_id)).unwrap();
+    assert!(!server.contains_key("finchers-sesssion:user:alice"));
+
+    // The deletion of a missing session does not touch any index.
+    block_on(store.delete(&Uuid::new_v4())).unwrap();
+    assert!(!server.command_names()[22..].contains(&"ZREM".to_owned()));
+}
+
+#[test]
+fn test_redis_read_future_fails_on_error_reply() {
+    let server = FakeRedis::start();
+    let store = RedisStore::new(server.client());
+    server.fail_command("GET");
+
+    assert!(block_on(store.load(&Uuid::new_v4())).is_err());
+}
+
+#[test]
+fn test_redis_connection_refused() {
+    let client = Client::open(&format!("redis://{}/", fake_redis::unused_addr())[..]).unwrap();
+    let store = RedisStore::new(client);
+
+    assert!(block_on(store.load(&Uuid::new_v4())).is_err());
+    assert!(block_on(store.save(&Uuid::new_v4(), "foo".into(), None)).is_err());
+}
+
+#[test]
+fn test_redis_admin_scan() {
+    let server = FakeRedis::start();
+    let store = RedisStore::new(server.client());
+    let mut session_ids = vec![];
+    for _ in 0..3 {
+        let session_id = Uuid::new_v4();
+        block_on(store.save(&session_id, value_of("alice"), None)).unwrap();
+        session_ids.push(session_id);
+    }
+    session_ids.sort();
+
+    // The key of user index is not listed.
+    let page = block_on(store.list_sessions(None, 10)).unwrap();
+    let mut listed = page.session_ids;
+    listed.sort();
+    assert_eq!(listed, session_ids);
+    assert_eq!(page.next_cursor, None);
+
+    assert_eq!(block_on(store.count_sessions()).unwrap(), 3);
+}
+
+fn is_timeout(err: &Error) -> bool {
+    match err.downcast_ref::<SessionError>() {
+        Some(&SessionError::Timeout) => true,
+        _ => false,
+    }
+}
+
+#[test]
+fn test_redis_command_timeout() {
+    let server = FakeRedis::start();
+    let store = RedisStore::new(server.client())
+        .invalidation_channel("sessions")
+        .command_timeout(Duration::from_millis(100));
+    server.hang_command("GET");
+    server.hang_command("PUBLISH");
+
+    let started = Instant::now();
+    let err = block_on(store.load(&Uuid::new_v4())).unwrap_err();
+    assert!(is_timeout(&err));
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // The timeout applies to the whole transaction.
+    let err = block_on(store.save(&Uuid::new_v4(), "foo".into(), None)).unwrap_err();
+    assert!(is_timeout(&err));
+    assert_eq!(
+        server.command_names(),
+        vec!["GET", "MULTI", "SET", "PUBLISH", "EXEC"]
+    );
+
+    // The subsequent operations are not affected by the hung connections.
+    let session_id = Uuid::new_v4();
+    let store = RedisStore::new(server.client()).command_timeout(Duration::from_millis(100));
+    block_on(store.save(&session_id, "foo".into(), None)).unwrap();
+    block_on(store.delete(&session_id)).unwrap();
+}
+
+#[test]
+fn test_redis_total_timeout() {
+    let server = FakeRedis::start();
+    let store = RedisStore::new(server.client()).total_timeout(Duration::from_millis(100));
+    server.hang_command("ZRANGE");
+
+    let err = block_on(store.list_user_sessions("alice")).unwrap_err();
+    assert!(is_timeout(&err));
+
+    // The operations which complete in time are not affected.
+    let session_id = Uuid::new_v4();
+    block_on(store.save(&session_id, "foo".into(), None)).unwrap();
+    assert_eq!(
+        block_on(store.load(&session_id)).unwrap(),
+        Some("foo".into())
+    );
+}
+
+#[test]
+fn test_cached_store_hit_miss_write_through() {
+    let server = FakeRedis::start();
+    let store = CachedStore::new(RedisStore::new(server.client()));
+    let uncached = RedisStore::new(server.client());
+
+    // The saved value is written to Redis and cached locally.
+    let session_id = Uuid::new_v4();
+    block_on(store.save(&session_id, "foo".into(), None)).unwrap();
+    assert_eq!(
+        block_on(uncached.load(&session_id)).unwrap(),
+        Some("foo".into())
+    );
+    assert_eq!(
+        block_on(store.load(&session_id)).unwrap(),
+        Some("foo".into())
+    );
+    assert_eq!(server.command_names(), vec!["MULTI", "SET", "EXEC", "GET"]);
+
+    // A miss fetches the value from Redis, and the subsequent loads hit the cache.
+    let other_id = Uuid::new_v4();
+    block_on(uncached.save(&other_id, "bar".into(), None)).unwrap();
+    for _ in 0..2 {
+        assert_eq!(
+            block_on(store.load(&other_id)).unwrap(),
+            Some("bar".into())
+        );
+    }
+    assert_eq!(server.command_names()[4..], ["MULTI", "SET", "EXEC", "GET"]);
+
+    // The deletion removes the cached value as well.
+    block_on(store.delete(&session_id)).unwrap();
+    assert_eq!(block_on(store.load(&session_id)).unwrap(), None);
+    assert_eq!(
+        server.command_names()[8..],
+        ["GET", "MULTI", "DEL", "EXEC", "GET"]
+    );
+}
+
+#[test]
+fn test_cached_store_evicts_on_failed_write() {
+    let server = FakeRedis::start();
+    let store = CachedStore::new(RedisStore::new(server.client()));
+    let session_id = Uuid::new_v4();
+    block_on(store.save(&session_id, "foo".into(), None)).unwrap();
+
+    // The failed save does not leave the previous value in the cache.
+    server.fail_command("SET");
+    assert!(block_on(store.save(&session_id, "bar".into(), None)).is_err());
+    assert_eq!(
+        block_on(store.load(&session_id)).unwrap(),
+        Some("foo".into())
+    );
+    assert_eq!(server.command_names().last().unwrap(), "GET");
+
+    // Neither does the failed deletion.
+    server.fail_command("DEL");
+    assert!(block_on(store.delete(&session_id)).is_err());
+    let commands = server.command_names().len();
+    assert_eq!(
+        block_on(store.load(&session_id)).unwrap(),
+        Some("foo".into())
+    );
+    assert_eq!(server.command_names()[commands..], ["GET"]);
+}
+
+#[test]
+fn test_cached_store_invalidation() {
+    let server = FakeRedis::start();
+    let store = CachedStore::new(RedisStore::new(server.client()))
+        .local_ttl(Duration::from_secs(60));
+    let other = RedisStore::new(server.client()).invalidation_channel("sessions");
+    listen_invalidation(&server.client(), "sessions", store.invalidator()).unwrap();
+    assert_eq!(server.subscriber_count("sessions"), 1);
+
+    let session_id = Uuid::new_v4();
+    block_on(store.save(&session_id, "v1".into(), None)).unwrap();
+
+    // The value modified by another instance is published to the channel.
+    block_on(other.save(&session_id, "v2".into(), None)).unwrap();
+    eventually(|| block_on(store.load(&session_id)).unwrap() == Some("v2".into()));
+
+    // The listener subscribes the channel again after the connection is lost.
+    server.disconnect_subscribers();
+    eventually(|| server.subscriber_count("sessions") == 1);
+    block_on(other.save(&session_id, "v3".into(), None)).unwrap();
+    eventually(|| block_on(store.load(&session_id)).unwrap() == Some("v3".into()));
+}
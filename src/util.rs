@@ -9,3 +9,46 @@ pub(crate) trait BuilderExt: Sized {
 }
 
 impl<T> BuilderExt for T {}
+
+/// Compares two byte sequences in constant time (with respect to their contents).
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Parses the data encoded as `application/x-www-form-urlencoded`.
+pub(crate) fn parse_urlencoded(data: &[u8]) -> Vec<(String, String)> {
+    data.split(|&b| b == b'&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, |&b| b == b'=');
+            let name = percent_decode(parts.next().unwrap_or(b""));
+            let value = percent_decode(parts.next().unwrap_or(b""));
+            (name, value)
+        }).collect()
+}
+
+fn percent_decode(data: &[u8]) -> String {
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let mut decoded = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        match data[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < data.len() => {
+                match (hex(data[i + 1]), hex(data[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        decoded.push(high << 4 | low);
+                        i += 2;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,284 @@
+//! Automatic commit of the session around an endpoint.
+//!
+//! The session yielded by the backends must be converted into a future (typically
+//! by `Session::with`) in order to write the modifications back, and forgetting it
+//! silently discards them. `Wrap` loads the session, runs the inner endpoint, and
+//! commits the session after the inner future resolves.
+//!
+//! While the inner future is being polled, the session is available through
+//! `with_session`. Whether the session is committed when the inner endpoint fails
+//! is controlled by `CommitPolicy`.
+//!
+//! The session is owned by `WrapFuture` and is registered to `with_session` only
+//! for the duration of each poll, so the future can be moved across the threads
+//! (e.g. by the work-stealing executor of Tokio) as long as the session is `Send`.
+//!
+//! # Example
+//!
+//! ```
+//! #[macro_use]
+//! extern crate finchers;
+//! extern crate finchers_session;
+//!
+//! use finchers::prelude::*;
+//! use finchers_session::Session;
+//! use finchers_session::in_memory::{InMemoryBackend, InMemorySession};
+//! use finchers_session::wrap::with_session;
+//!
+//! # fn main() {
+//! let backend = InMemoryBackend::default();
+//!
+//! let endpoint = backend.wrap(path!(@get /).map(|| {
+//!     with_session(|session: &mut Session<InMemorySession>| {
+//!         session.set("visited");
+//!     }).expect("the session is not available");
+//!     "done"
+//! }));
+//! # drop(move || finchers::server::start(endpoint).serve("127.0.0.1:4000"));
+//! # }
+//! ```
+
+use finchers::endpoint::{ApplyContext, ApplyResult, Endpoint};
+use finchers::error::Error;
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+
+use futures::{Async, Future, IntoFuture, Poll};
+
+use session::{RawSession, Session, WriteSessionFuture};
+
+// The session of a wrapped endpoint, shared with `with_session` while
+// the inner future is being polled.
+type SharedSession<S> = Arc<Mutex<Option<Session<S>>>>;
+
+thread_local! {
+    // The sessions of the wrapped endpoints being polled on the current thread.
+    // The entries are pushed and popped within a single call of `poll`.
+    static SESSIONS: RefCell<Vec<Box<dyn Any>>> = RefCell::new(vec![]);
+}
+
+/// Calls the specified function with the session of the innermost `Wrap`
+/// whose session type is `Session<S>`.
+///
+/// This function returns `None` if it is not called while the inner endpoint
+/// of such `Wrap` is being polled, or if it is called recursively.
+pub fn with_session<S, R>(f: impl FnOnce(&mut Session<S>) -> R) -> Option<R>
+where
+    S: RawSession + 'static,
+{
+    let shared = SESSIONS.with(|sessions| {
+        sessions
+            .borrow()
+            .iter()
+            .rev()
+            .filter_map(|session| session.downcast_ref::<SharedSession<S>>())
+            .next()
+            .cloned()
+    })?;
+    // `try_lock` fails if this function is called recursively.
+    let mut session = shared.try_lock().ok()?;
+    let result = session.as_mut().map(f);
+    result
+}
+
+// Makes the session available through `with_session` until dropped.
+//
+// The session is removed from the thread-local stack in `Drop`, so that it
+// does not outlive the poll even if the inner endpoint panics.
+struct Scope {
+    depth: usize,
+}
+
+impl Scope {
+    fn enter<S>(session: &SharedSession<S>) -> Scope
+    where
+        S: RawSession + 'static,
+    {
+        SESSIONS.with(|sessions| {
+            let mut sessions = sessions.borrow_mut();
+            let depth = sessions.len();
+            sessions.push(Box::new(session.clone()));
+            Scope { depth }
+        })
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        // `try_with` fails only while the thread is being destroyed.
+        let _ = SESSIONS.try_with(|sessions| sessions.borrow_mut().truncate(self.depth));
+    }
+}
+
+/// The policy whether the session is committed when the inner endpoint fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitPolicy {
+    /// Commits the session regardless of the result of the inner endpoint.
+    Always,
+    /// Commits the session only if the inner endpoint succeeds, and discards
+    /// the modifications otherwise.
+    OnSuccess,
+}
+
+impl Default for CommitPolicy {
+    fn default() -> CommitPolicy {
+        CommitPolicy::Always
+    }
+}
+
+/// An endpoint which commits the session automatically after the inner endpoint.
+#[derive(Debug, Clone)]
+pub struct Wrap<B, E> {
+    backend: B,
+    endpoint: E,
+    policy: CommitPolicy,
+}
+
+impl<B, E> Wrap<B, E> {
+    /// Create a new `Wrap` from the endpoint which yields the session and
+    /// the inner endpoint.
+    pub fn new(backend: B, endpoint: E) -> Wrap<B, E> {
+        Wrap {
+            backend,
+            endpoint,
+            policy: CommitPolicy::default(),
+        }
+    }
+
+    /// Set the policy whether the session is committed when the inner endpoint fails.
+    ///
+    /// The default value is `CommitPolicy::Always`.
+    pub fn commit_policy(mut self, policy: CommitPolicy) -> Wrap<B, E> {
+        self.policy = policy;
+        self
+    }
+}
+
+impl<'a, B, E, S> Endpoint<'a> for Wrap<B, E>
+where
+    B: Endpoint<'a, Output = (Session<S>,)>,
+    E: Endpoint<'a>,
+    S: RawSession + 'static,
+{
+    type Output = E::Output;
+    type Future = WrapFuture<B::Future, E::Future, S>;
+
+    fn apply(&'a self, cx: &mut ApplyContext<'_>) -> ApplyResult<Self::Future> {
+        // The session is not loaded unless the inner endpoint matches.
+        let inner = self.endpoint.apply(cx)?;
+        let future = self.backend.apply(cx)?;
+        Ok(WrapFuture {
+            state: WrapFutureState::Loading {
+                future,
+                inner: Some(inner),
+            },
+            policy: self.policy,
+        })
+    }
+}
+
+#[doc(hidden)]
+#[allow(missing_debug_implementations)]
+pub struct WrapFuture<F, T: Future, S: RawSession> {
+    state: WrapFutureState<F, T, S>,
+    policy: CommitPolicy,
+}
+
+#[allow(missing_debug_implementations)]
+enum WrapFutureState<F, T: Future, S: RawSession> {
+    Loading {
+        future: F,
+        inner: Option<T>,
+    },
+    Running {
+        future: T,
+        session: SharedSession<S>,
+    },
+    Committing {
+        future: WriteSessionFuture<S::WriteFuture>,
+        result: Option<Result<T::Item, Error>>,
+    },
+}
+
+enum WrapStep<T: Future, S: RawSession> {
+    Run(T, Session<S>),
+    Commit(Session<S>, Result<T::Item, Error>),
+}
+
+impl<F, T, S> Future for WrapFuture<F, T, S>
+where
+    F: Future<Item = (Session<S>,), Error = Error>,
+    T: Future<Error = Error>,
+    S: RawSession + 'static,
+{
+    type Item = T::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        use self::WrapFutureState::*;
+        loop {
+            let step = match self.state {
+                Loading {
+                    ref mut future,
+                    ref mut inner,
+                } => {
+                    let (session,) = try_ready!(future.poll());
+                    let inner = inner.take().expect("This future has already polled.");
+                    WrapStep::Run(inner, session)
+                }
+                Running {
+                    ref mut future,
+                    ref mut session,
+                } => {
+                    let polled = {
+                        let _scope = Scope::enter(session);
+                        future.poll()
+                    };
+                    let result = match polled {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(item)) => Ok(item),
+                        Err(err) => match self.policy {
+                            CommitPolicy::Always => Err(err),
+                            CommitPolicy::OnSuccess => return Err(err),
+                        },
+                    };
+                    let session = session
+                        .lock()
+                        .ok()
+                        .and_then(|mut session| session.take())
+                        .expect("This future has already polled.");
+                    WrapStep::Commit(session, result)
+                }
+                Committing {
+                    ref mut future,
+                    ref mut result,
+                } => {
+                    let committed = future.poll();
+                    if let Ok(Async::NotReady) = committed {
+                        return Ok(Async::NotReady);
+                    }
+                    let result = result.take().expect("This future has already polled.");
+                    // The error from the inner endpoint takes precedence over
+                    // the one from the commit.
+                    return match (result, committed) {
+                        (Err(err), _) | (Ok(..), Err(err)) => Err(err),
+                        (Ok(item), _) => Ok(Async::Ready(item)),
+                    };
+                }
+            };
+
+            self.state = match step {
+                WrapStep::Run(future, session) => Running {
+                    future,
+                    session: Arc::new(Mutex::new(Some(session))),
+                },
+                WrapStep::Commit(session, result) => Committing {
+                    future: session.into_future(),
+                    result: Some(result),
+                },
+            };
+        }
+    }
+}